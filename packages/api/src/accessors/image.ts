@@ -6,12 +6,23 @@ import { HostLibrary } from "../api/HostLibrary.js";
 
 export function createImageAccessor() {
   return new Accessor([new HostLibrary()], {
-    createRequest(query: { file: string; edits: string }) {
+    createRequest(query: {
+      file: string;
+      edits: string;
+      quality?: proto.RenderQuality;
+      region?: proto.ImageRegion;
+      colorSpace?: proto.ColorSpace;
+      softProof?: proto.SoftProof;
+    }) {
       return [
         proto.ClientMessage.create({
           image: proto.RequestImage.create({
             file: query.file,
             edits: query.edits,
+            quality: query.quality,
+            region: query.region,
+            colorSpace: query.colorSpace,
+            softProof: query.softProof,
           }),
         }),
       ];
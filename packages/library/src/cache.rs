@@ -0,0 +1,83 @@
+use std::env;
+use std::sync::Arc;
+
+/**
+ * Default size of the render cache in megabytes, about 15 frames at 2048 px
+ */
+const DEFAULT_SIZE_MB: usize = 512;
+
+/**
+ * Default size of the source cache in megabytes, a full resolution 45 MP source takes about 520
+ */
+const DEFAULT_SOURCE_MB: usize = 1536;
+
+/**
+ * Least recently used cache bounded by the bytes of its entries
+ */
+pub struct Lru<V> {
+  capacity: usize,
+  bytes: usize,
+  // least recently used first
+  entries: Vec<(String, Arc<V>, usize)>,
+}
+
+impl<V> Lru<V> {
+  pub fn new(capacity: usize) -> Lru<V> {
+    Lru {
+      capacity,
+      bytes: 0,
+      entries: Vec::new(),
+    }
+  }
+
+  pub fn get(&mut self, key: &str) -> Option<Arc<V>> {
+    let index = self.entries.iter().position(|(k, _, _)| k == key)?;
+    let entry = self.entries.remove(index);
+    let value = entry.1.clone();
+    self.entries.push(entry);
+    Some(value)
+  }
+
+  /**
+   * Evicts the least recently used entries until the new one fits,
+   * entries larger than the whole cache are not kept
+   */
+  pub fn insert(&mut self, key: String, value: Arc<V>, bytes: usize) {
+    if let Some(index) = self.entries.iter().position(|(k, _, _)| *k == key) {
+      let (_, _, size) = self.entries.remove(index);
+      self.bytes -= size;
+    }
+    if bytes > self.capacity {
+      return;
+    }
+    while self.bytes + bytes > self.capacity && !self.entries.is_empty() {
+      let (_, _, size) = self.entries.remove(0);
+      self.bytes -= size;
+    }
+    self.bytes += bytes;
+    self.entries.push((key, value, bytes));
+  }
+}
+
+/**
+ * Size of the render cache in bytes, set in megabytes with the RENDER_CACHE_MB environment variable
+ */
+pub fn capacity() -> usize {
+  megabytes("RENDER_CACHE_MB", DEFAULT_SIZE_MB)
+}
+
+/**
+ * Size of the cache of decoded sources in bytes, set in megabytes with the SOURCE_CACHE_MB
+ * environment variable
+ */
+pub fn source_capacity() -> usize {
+  megabytes("SOURCE_CACHE_MB", DEFAULT_SOURCE_MB)
+}
+
+fn megabytes(variable: &str, default: usize) -> usize {
+  let megabytes = env::var(variable)
+    .ok()
+    .and_then(|size| size.parse().ok())
+    .unwrap_or(default);
+  megabytes * 1024 * 1024
+}
@@ -93,35 +93,32 @@ pub fn metadat(path: &String) -> Result<Metadata> {
   })
 }
 
+/**
+ * Identifies an image by its capture date and name, None for files that aren't raws
+ * or have no capture date
+ */
 pub fn file_hash(path: &String) -> Option<String> {
   let p = Path::new(&path);
-  let raw_file = File::open(&path);
-
-  if raw_file.is_err() {
-    return None;
-  }
-
-  let reader = BufReader::new(raw_file.unwrap());
+  let reader = BufReader::new(File::open(&path).ok()?);
   let mut rawfile = RawFile::new(&p, reader);
 
   let meta: Option<RawMetadata> = match get_decoder(&mut rawfile) {
-    Ok(decoder) => Some(
-      decoder
-        .raw_metadata(&mut rawfile, RawDecodeParams { image_index: 0 })
-        .unwrap(),
-    ),
+    Ok(decoder) => match decoder.raw_metadata(&mut rawfile, RawDecodeParams { image_index: 0 }) {
+      Ok(metadata) => Some(metadata),
+      Err(error) => {
+        error!("Error reading metadata {}", error.to_string());
+        None
+      }
+    },
     Err(error) => {
       error!("Error reading metadata {}", error.to_string());
       None
     }
   };
 
-  match meta {
-    Some(metadata) => Some(sha256::digest(
-      metadata.exif.create_date.clone().unwrap() + p.file_name().unwrap().to_str().unwrap(),
-    )),
-    None => None,
-  }
+  let create_date = meta?.exif.create_date?;
+  let name = p.file_name()?.to_str()?;
+  Some(sha256::digest(create_date + name))
 }
 
 pub fn thumbnail(path: String) -> Vec<u8> {
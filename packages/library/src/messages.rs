@@ -1,16 +1,25 @@
+use crate::cache::{self, Lru};
+use crate::image::file_hash;
 use crate::IndexEntry;
 use crate::Library;
 use anyhow::anyhow;
 use anyhow::Result;
-use image::imageops::FilterType;
-use image::DynamicImage;
+use image::imageops::{self, FilterType};
 use image::EncodableLayout;
+use image::{DynamicImage, ImageBuffer, Rgb};
 use log::error;
 use log::info;
 use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
 use tokio::time::Instant;
 use tokyo_proto::schema::MetadataEntryMessage;
 use tokyo_proto::schema::{self, ClientMessage, IndexEntryMessage};
+use tokyo_proto::schema::{ColorSpace, RenderQuality, RenderingIntent};
+
+/**
+ * Long side of the images rendered without a region
+ */
+const PREVIEW_SIZE: u32 = 2048;
 
 async fn metadata(lib: &Library, file: &Vec<String>) -> schema::Message {
   let mut msg = schema::Message::new();
@@ -87,22 +96,155 @@ async fn get_index_msg(lib: &Library, ids: Vec<String>) -> schema::LibraryIndexM
   return index_msg;
 }
 
-pub async fn edited_image(path: &String, edits_json: Option<String>) -> Result<DynamicImage> {
-  let start = Instant::now();
+/**
+ * What `process` starts from, everything before it only depends on the file, the render size and
+ * `Edits::frame_key`, so it is cached between renders while sliders move
+ */
+struct Frame {
+  image: ImageBuffer<Rgb<f32>, Vec<f32>>,
+  as_shot: tokyo_shadow::AsShot,
+  /**
+   * Thumbnail of the source, automatic adjustments are found in it
+   */
+  thumbnail: Arc<ImageBuffer<Rgb<f32>, Vec<f32>>>,
+  view: tokyo_shadow::View,
+  /**
+   * Part of the processed image that is returned, cuts the padding off regions
+   */
+  crop: Option<[u32; 4]>,
+  /**
+   * Grain seed of the image, so previews and exports get the same pattern
+   */
+  seed: u64,
+}
+
+/**
+ * Identifies the contents of a file by its hash, so a moved file is still cached,
+ * files without one by their path, size and modification time
+ */
+fn file_key(path: &String) -> String {
+  if let Some(hash) = file_hash(path) {
+    return hash;
+  }
+  let metadata = std::fs::metadata(path);
+  match metadata.and_then(|metadata| Ok((metadata.len(), metadata.modified()?))) {
+    Ok((size, modified)) => format!("{} {} {:?}", path, size, modified),
+    Err(_) => path.to_string(),
+  }
+}
+
+/**
+ * Seed from the image's hash, which stays the same when the file is moved,
+ * or from its path for files without one
+ */
+fn grain_seed(path: &String) -> u64 {
+  let hash = file_hash(path).unwrap_or_else(|| sha256::digest(path.as_str()));
+  u64::from_str_radix(&hash[..16], 16).unwrap_or(0)
+}
+
+fn frames() -> &'static Mutex<Lru<Frame>> {
+  static FRAMES: OnceLock<Mutex<Lru<Frame>>> = OnceLock::new();
+  FRAMES.get_or_init(|| Mutex::new(Lru::new(cache::capacity())))
+}
+
+fn sources() -> &'static Mutex<Lru<tokyo_shadow::Source>> {
+  static SOURCES: OnceLock<Mutex<Lru<tokyo_shadow::Source>>> = OnceLock::new();
+  SOURCES.get_or_init(|| Mutex::new(Lru::new(cache::source_capacity())))
+}
+
+/**
+ * The decoded full resolution file, shared by every render with the same decode options,
+ * so panning and zooming doesn't decode the file again
+ */
+async fn source(
+  path: &String,
+  options: &tokyo_shadow::DecodeOptions,
+) -> Result<Arc<tokyo_shadow::Source>> {
+  let key = format!("{}|{:?}", file_key(path), options);
+  if let Some(source) = sources().lock().unwrap().get(&key) {
+    info!("Source from cache");
+    return Ok(source);
+  }
 
   info!("Load image");
-  let image = tokyo_shadow::get_image(&Path::new(path)).await?;
+  let source = Arc::new(tokyo_shadow::get_source(&Path::new(path), options).await?);
+  let pixels = source.image.as_raw().len() + source.thumbnail.as_raw().len();
+  let bytes = pixels * std::mem::size_of::<f32>();
+  sources().lock().unwrap().insert(key, source.clone(), bytes);
+  Ok(source)
+}
 
-  info!("Resize image");
-  let image = image.resize(2048, 2048, FilterType::Lanczos3);
+pub async fn edited_image(
+  path: &String,
+  edits_json: Option<String>,
+  quality: RenderQuality,
+  region: Option<&schema::ImageRegion>,
+  output: tokyo_shadow::OutputSpace,
+  proof: Option<&tokyo_shadow::SoftProof>,
+) -> Result<DynamicImage> {
+  let start = Instant::now();
 
-  let edits: tokyo_shadow::Edits = match edits_json {
-    Some(json) => tokyo_shadow::Edits::from_json(json),
-    _ => tokyo_shadow::Edits::new(),
+  let edits = parse_edits(edits_json);
+
+  // region coordinates are in pixels of the full resolution image, so they are always cut from
+  // the full size decode
+  let quality = match region {
+    Some(_) => RenderQuality::FULL,
+    None => quality,
+  };
+
+  // regions are padded for the nodes that read around a pixel, the frame size is the same for
+  // every render of the key so the padding at size 0 tells whether those nodes changed
+  let resolution = match region {
+    Some(region) => format!(
+      "{},{} {}x{} @{} +{}",
+      region.x,
+      region.y,
+      region.width,
+      region.height,
+      region.scale,
+      edits.padding(&region_view(region.scale, 0, 0))
+    ),
+    None => PREVIEW_SIZE.to_string(),
+  };
+  let key = format!(
+    "{}|{}|{:?}|{}",
+    file_key(path),
+    resolution,
+    quality,
+    edits.frame_key()
+  );
+
+  let cached = frames().lock().unwrap().get(&key);
+  let frame = match cached {
+    Some(frame) => {
+      info!("Frame from cache");
+      frame
+    }
+    None => {
+      let frame = Arc::new(edited_frame(path, &edits, quality, region).await?);
+      let bytes = frame.image.as_raw().len() * std::mem::size_of::<f32>();
+      frames().lock().unwrap().insert(key, frame.clone(), bytes);
+      frame
+    }
   };
 
   info!("Process image");
-  let img = tokyo_shadow::process(image.to_rgb32f(), &edits);
+  let edits = edits
+    .with_seed(frame.seed)
+    .with_auto_mix(&frame.thumbnail, &frame.as_shot);
+  let img = tokyo_shadow::process_view(
+    frame.image.clone(),
+    &edits,
+    &frame.as_shot,
+    &frame.view,
+    output,
+    proof,
+  );
+  let img = match frame.crop {
+    Some([x, y, width, height]) => imageops::crop_imm(&img, x, y, width, height).to_image(),
+    None => img,
+  };
   let image = DynamicImage::ImageRgb32F(img);
 
   info!("done in {}ms", start.elapsed().as_millis());
@@ -110,6 +252,178 @@ pub async fn edited_image(path: &String, edits_json: Option<String>) -> Result<D
   Ok(image)
 }
 
+/**
+ * Runs lens, geometry and details on the decoded file, scaled to the preview size
+ * or cut to a region
+ */
+async fn edited_frame(
+  path: &String,
+  edits: &tokyo_shadow::Edits,
+  quality: RenderQuality,
+  region: Option<&schema::ImageRegion>,
+) -> Result<Frame> {
+  let demosaic = match quality {
+    RenderQuality::PREVIEW => tokyo_shadow::Demosaic::HalfSize,
+    RenderQuality::FULL => tokyo_shadow::Demosaic::HighQuality,
+  };
+  let options = tokyo_shadow::DecodeOptions {
+    demosaic,
+    ..edits.decode_options()
+  };
+  let source = source(path, &options).await?;
+  let seed = grain_seed(path);
+  let warp = tokyo_shadow::warp(&source, edits);
+
+  if let Some(region) = region {
+    info!("Render region");
+    return Ok(region_frame(&source, &warp, edits, seed, region));
+  }
+
+  info!("Correct lens and geometry");
+  let image = warp.render_frame(&source.image);
+
+  info!("Process details");
+  let image = DynamicImage::ImageRgb32F(tokyo_shadow::process_detail(image, edits, source.scale));
+
+  info!("Resize image");
+  let image = image
+    .resize(PREVIEW_SIZE, PREVIEW_SIZE, FilterType::Lanczos3)
+    .to_rgb32f();
+
+  Ok(Frame {
+    view: tokyo_shadow::View::full(image.width(), image.height()),
+    image,
+    as_shot: source.as_shot,
+    thumbnail: source.thumbnail.clone(),
+    crop: None,
+    seed,
+  })
+}
+
+fn output_space(color_space: ColorSpace) -> tokyo_shadow::OutputSpace {
+  match color_space {
+    ColorSpace::SRGB => tokyo_shadow::OutputSpace::Srgb,
+    ColorSpace::DISPLAY_P3 => tokyo_shadow::OutputSpace::DisplayP3,
+    ColorSpace::ADOBE_RGB => tokyo_shadow::OutputSpace::AdobeRgb,
+    ColorSpace::REC2020 => tokyo_shadow::OutputSpace::Rec2020,
+    ColorSpace::PROPHOTO => tokyo_shadow::OutputSpace::ProPhoto,
+  }
+}
+
+fn soft_proof(request: &schema::SoftProof) -> Result<tokyo_shadow::SoftProof> {
+  let profile = match &request.icc_profile {
+    Some(data) => tokyo_shadow::ProofProfile::from_icc(data)?,
+    None => output_space(request.color_space.enum_value_or_default()).into(),
+  };
+  let intent = match request.intent.enum_value_or_default() {
+    RenderingIntent::RELATIVE_COLORIMETRIC => tokyo_shadow::RenderingIntent::RelativeColorimetric,
+    RenderingIntent::PERCEPTUAL => tokyo_shadow::RenderingIntent::Perceptual,
+    RenderingIntent::ABSOLUTE_COLORIMETRIC => tokyo_shadow::RenderingIntent::AbsoluteColorimetric,
+  };
+  Ok(tokyo_shadow::SoftProof {
+    profile,
+    intent,
+    paper_white: request.paper_white,
+    gamut_warning: request.gamut_warning,
+  })
+}
+
+fn region_view(scale: f32, frame_width: u32, frame_height: u32) -> tokyo_shadow::View {
+  tokyo_shadow::View {
+    x: 0.0,
+    y: 0.0,
+    scale: if scale > 0.0 { scale } else { 1.0 },
+    frame_width: frame_width as f32,
+    frame_height: frame_height as f32,
+  }
+}
+
+/**
+ * A region of the edited frame for 1:1 inspection. Lens, geometry, details and nodes only run on
+ * the region, padded so the filters see its surroundings.
+ */
+fn region_frame(
+  source: &tokyo_shadow::Source,
+  warp: &tokyo_shadow::Warp,
+  edits: &tokyo_shadow::Edits,
+  seed: u64,
+  region: &schema::ImageRegion,
+) -> Frame {
+  let (frame_width, frame_height) = warp.size();
+  let view = region_view(region.scale, frame_width, frame_height);
+  let scale = view.scale;
+  let x = region.x.min(frame_width - 1);
+  let y = region.y.min(frame_height - 1);
+  let width = region.width.clamp(1, frame_width - x);
+  let height = region.height.clamp(1, frame_height - y);
+
+  let padding = edits.padding(&view);
+  let (left, top) = (x.saturating_sub(padding), y.saturating_sub(padding));
+  let right = (x + width + padding).min(frame_width);
+  let bottom = (y + height + padding).min(frame_height);
+
+  let padded = warp.render(&source.image, left, top, right - left, bottom - top);
+  let padded = tokyo_shadow::process_detail(padded, edits, source.scale);
+  let padded = if scale == 1.0 {
+    padded
+  } else {
+    let scaled = |size: u32| ((size as f32 * scale).round() as u32).max(1);
+    imageops::resize(
+      &padded,
+      scaled(right - left),
+      scaled(bottom - top),
+      FilterType::Lanczos3,
+    )
+  };
+
+  // the padding is cut back off after processing
+  let crop_x = (((x - left) as f32 * scale).round() as u32).min(padded.width() - 1);
+  let crop_y = (((y - top) as f32 * scale).round() as u32).min(padded.height() - 1);
+  let crop_width = ((width as f32 * scale).round() as u32).clamp(1, padded.width() - crop_x);
+  let crop_height = ((height as f32 * scale).round() as u32).clamp(1, padded.height() - crop_y);
+
+  Frame {
+    image: padded,
+    as_shot: source.as_shot,
+    thumbnail: source.thumbnail.clone(),
+    view: tokyo_shadow::View {
+      x: left as f32,
+      y: top as f32,
+      ..view
+    },
+    crop: Some([crop_x, crop_y, crop_width, crop_height]),
+    seed,
+  }
+}
+
+/**
+ * White balance that neutralizes a region of the edited image, the region in 0..1
+ */
+pub async fn sampled_white_balance(
+  path: &String,
+  edits_json: Option<String>,
+  region: tokyo_shadow::Crop,
+) -> Result<tokyo_shadow::WhiteBalance> {
+  let edits = parse_edits(edits_json);
+  let source = source(path, &edits.decode_options()).await?;
+
+  // the region is picked on the edited frame, so it needs the same lens and geometry
+  let image = tokyo_shadow::warp(&source, &edits).render_frame(&source.image);
+
+  Ok(tokyo_shadow::sample_white_balance(
+    &image,
+    &region,
+    &source.as_shot.white_balance,
+  ))
+}
+
+fn parse_edits(edits_json: Option<String>) -> tokyo_shadow::Edits {
+  match edits_json {
+    Some(json) => tokyo_shadow::Edits::from_json(json),
+    _ => tokyo_shadow::Edits::new(),
+  }
+}
+
 pub async fn handle_client_request(req: ClientMessage) -> Result<schema::Message> {
   let lib = &Library::new().await;
 
@@ -155,17 +469,49 @@ pub async fn handle_client_request(req: ClientMessage) -> Result<schema::Message
   if req.has_image() {
     let file = &req.image().file; // should be the hash,
     let mut img_msg = schema::ImageMessage::new();
-    let image = edited_image(file, req.image().edits.to_owned()).await?;
+    let request = req.image();
+    let output = output_space(request.color_space());
+    let proof = request.soft_proof.as_ref().map(soft_proof).transpose()?;
+    let image = edited_image(
+      file,
+      request.edits.to_owned(),
+      request.quality(),
+      request.region.as_ref(),
+      output,
+      proof.as_ref(),
+    )
+    .await?;
     let v = image.to_rgb8().as_bytes().to_vec();
     img_msg.image = v;
     img_msg.width = image.width() as i32;
     img_msg.height = image.height() as i32;
+    img_msg.icc_profile = tokyo_shadow::icc_profile(output);
     let mut msg = schema::Message::new();
     msg.nonce = req.nonce;
     msg.set_image(img_msg);
     return Ok(msg);
   }
 
+  if req.has_white_balance() {
+    let request = req.white_balance();
+    let region = tokyo_shadow::Crop {
+      x: request.x,
+      y: request.y,
+      width: request.width,
+      height: request.height,
+    };
+    let white_balance =
+      sampled_white_balance(&request.file, request.edits.to_owned(), region).await?;
+
+    let mut wb_msg = schema::WhiteBalanceMessage::new();
+    wb_msg.temperature = white_balance.temperature;
+    wb_msg.tint = white_balance.tint;
+    let mut msg = schema::Message::new();
+    msg.nonce = req.nonce;
+    msg.set_white_balance(wb_msg);
+    return Ok(msg);
+  }
+
   if req.has_postmeta() {
     let file = &req.postmeta().file;
     let rating = req.postmeta().rating.unwrap();
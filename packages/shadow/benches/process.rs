@@ -0,0 +1,92 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use image::{ImageBuffer, Rgb};
+use tokyo_shadow::{process, AsShot, Edits, WhiteBalance};
+
+/**
+ * 8256 x 5504, the size of a 45 MP full frame sensor
+ */
+const WIDTH: u32 = 8256;
+const HEIGHT: u32 = 5504;
+
+fn source() -> ImageBuffer<Rgb<f32>, Vec<f32>> {
+  ImageBuffer::from_fn(WIDTH, HEIGHT, |x, y| {
+    let u = x as f32 / WIDTH as f32;
+    let v = y as f32 / HEIGHT as f32;
+    Rgb([u * 2.0, v, (1.0 - u) * v * 4.0])
+  })
+}
+
+/**
+ * The output conversion as it was before the color matrices were precomputed,
+ * kolor for every pixel on one thread. The empty render is measured against it.
+ */
+fn kolor_per_pixel(source: ImageBuffer<Rgb<f32>, Vec<f32>>) -> ImageBuffer<Rgb<f32>, Vec<f32>> {
+  let mut image = source;
+  let conversion =
+    kolor::ColorConversion::new(kolor::spaces::ACES2065_1, kolor::spaces::LINEAR_SRGB);
+  for pixel in image.pixels_mut() {
+    let linear = conversion.convert(pixel.0.into());
+    pixel.0 = srgb::gamma::normalised_from_linear(linear);
+  }
+  image
+}
+
+fn edits(json: &str) -> Edits {
+  Edits::from_json(json.to_string())
+}
+
+fn bench_process(c: &mut Criterion) {
+  let source = source();
+  let as_shot = AsShot {
+    white_balance: WhiteBalance::default(),
+    camera_from_working: None,
+  };
+  let renders = [
+    ("empty", edits(r#"{ "nodes": [] }"#)),
+    (
+      "tone",
+      edits(
+        r#"{ "nodes": [
+          { "type": "exposure", "amount": 0.5 },
+          { "type": "contrast", "amount": 0.2 },
+          { "type": "highlights", "amount": -0.5 },
+          { "type": "shadows", "amount": 0.4 },
+          { "type": "saturation", "amount": 0.1 }
+        ], "white_balance": { "temperature": 5000, "tint": 10 } }"#,
+      ),
+    ),
+    (
+      "color",
+      edits(
+        r#"{ "nodes": [
+          { "type": "curves", "tone": [[0, 0], [0.25, 0.2], [0.75, 0.8], [1, 1]] },
+          { "type": "color_curves", "hue_saturation": [[0, 0.5], [0.5, 0.3], [1, 0.5]] },
+          { "type": "vibrancy", "amount": 0.3, "mask": { "hue": { "min": 20, "max": 90, "feather": 10 } } }
+        ] }"#,
+      ),
+    ),
+  ];
+
+  let mut group = c.benchmark_group("process_45mp");
+  group.sample_size(10);
+  group.bench_function(BenchmarkId::new("kolor_per_pixel", "empty"), |b| {
+    b.iter(|| kolor_per_pixel(source.clone()))
+  });
+  for (name, edits) in &renders {
+    // a pool of one thread is the single threaded baseline
+    let serial = rayon::ThreadPoolBuilder::new()
+      .num_threads(1)
+      .build()
+      .unwrap();
+    group.bench_with_input(BenchmarkId::new("serial", name), edits, |b, edits| {
+      b.iter(|| serial.install(|| process(source.clone(), edits, &as_shot)))
+    });
+    group.bench_with_input(BenchmarkId::new("parallel", name), edits, |b, edits| {
+      b.iter(|| process(source.clone(), edits, &as_shot))
+    });
+  }
+  group.finish();
+}
+
+criterion_group!(benches, bench_process);
+criterion_main!(benches);
@@ -0,0 +1,94 @@
+use crate::color::{
+  linear_srgb_from_oklab, oklab_from_linear_srgb, oklab_from_oklch, oklch_from_oklab,
+};
+use image::{ImageBuffer, Rgb};
+use serde::{Deserialize, Serialize};
+
+/**
+ * Largest hue shift of a primary in degrees, at -1 or 1
+ */
+const MAX_HUE_SHIFT: f32 = 30.0;
+
+/**
+ * Primary calibration, moves the red, green and blue primaries the image is rendered with.
+ * Hue shifts are in -1..1, saturation scales the chroma of the primary by 1 + saturation.
+ * White stays white, so it changes colors without touching the white balance.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Calibration {
+  #[serde(default)]
+  pub red_hue: f32,
+  #[serde(default)]
+  pub red_saturation: f32,
+  #[serde(default)]
+  pub green_hue: f32,
+  #[serde(default)]
+  pub green_saturation: f32,
+  #[serde(default)]
+  pub blue_hue: f32,
+  #[serde(default)]
+  pub blue_saturation: f32,
+}
+
+impl Calibration {
+  pub fn is_identity(&self) -> bool {
+    [
+      self.red_hue,
+      self.red_saturation,
+      self.green_hue,
+      self.green_saturation,
+      self.blue_hue,
+      self.blue_saturation,
+    ]
+    .iter()
+    .all(|v| *v == 0.0)
+  }
+
+  /**
+   * The moved primaries as a matrix on linear Rec.2020, whose primaries are all real colors
+   */
+  fn matrix(&self) -> [[f32; 3]; 3] {
+    let to_srgb = kolor::ColorConversion::new(kolor::spaces::BT_2020, kolor::spaces::LINEAR_SRGB);
+    let from_srgb = kolor::ColorConversion::new(kolor::spaces::LINEAR_SRGB, kolor::spaces::BT_2020);
+
+    let shifts = [
+      (self.red_hue, self.red_saturation),
+      (self.green_hue, self.green_saturation),
+      (self.blue_hue, self.blue_saturation),
+    ];
+
+    let mut primaries = [[0.0; 3]; 3];
+    for (i, (hue, saturation)) in shifts.iter().enumerate() {
+      let mut primary = [0.0; 3];
+      primary[i] = 1.0;
+
+      let srgb: [f32; 3] = to_srgb.convert(primary.into()).into();
+      let [l, c, h] = oklch_from_oklab(oklab_from_linear_srgb(srgb));
+      let lch = [
+        l,
+        c * (1.0 + saturation).max(0.0),
+        h + hue.clamp(-1.0, 1.0) * MAX_HUE_SHIFT,
+      ];
+      let moved = linear_srgb_from_oklab(oklab_from_oklch(lch));
+      primaries[i] = from_srgb.convert(moved.into()).into();
+    }
+
+    // the primaries are the columns, each scaled so that together they still add up to white
+    let columns = [0, 1, 2].map(|row| [0, 1, 2].map(|col| primaries[col][row]));
+    let Some(inverse) = crate::mat_inverse(&columns) else {
+      return [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+    };
+    let scale = crate::mat_mul(&inverse, &[1.0; 3]);
+    columns.map(|row| [0, 1, 2].map(|col| row[col] * scale[col]))
+  }
+}
+
+pub fn apply(image: &mut ImageBuffer<Rgb<f32>, Vec<f32>>, calibration: &Calibration) {
+  let to_rec2020 = crate::conversion(crate::WORKING_COLORSPACE, kolor::spaces::BT_2020);
+  let from_rec2020 = crate::conversion(kolor::spaces::BT_2020, crate::WORKING_COLORSPACE);
+  let matrix = crate::mat_product(
+    &from_rec2020,
+    &crate::mat_product(&calibration.matrix(), &to_rec2020),
+  );
+  crate::tiles::apply_matrix(image, &matrix);
+}
@@ -0,0 +1,69 @@
+/**
+ * OKLab, a perceptual color space where hue and lightness stay put when changing the chroma.
+ * https://bottosson.github.io/posts/oklab/
+ */
+pub fn oklab_from_linear_srgb(rgb: [f32; 3]) -> [f32; 3] {
+  let [r, g, b] = rgb;
+
+  let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b).cbrt();
+  let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b).cbrt();
+  let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b).cbrt();
+
+  [
+    0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+    1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+    0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s,
+  ]
+}
+
+pub fn linear_srgb_from_oklab(lab: [f32; 3]) -> [f32; 3] {
+  let [l, a, b] = lab;
+
+  let l_ = l + 0.3963377774 * a + 0.2158037573 * b;
+  let m_ = l - 0.1055613458 * a - 0.0638541728 * b;
+  let s_ = l - 0.0894841775 * a - 1.2914855480 * b;
+
+  let l = l_ * l_ * l_;
+  let m = m_ * m_ * m_;
+  let s = s_ * s_ * s_;
+
+  [
+    4.0767416621 * l - 3.3077115913 * m + 0.2309699292 * s,
+    -1.2684380046 * l + 2.6097574011 * m - 0.3413193965 * s,
+    -0.0041960863 * l - 0.7034186147 * m + 1.7076147010 * s,
+  ]
+}
+
+/**
+ * Lightness, chroma and hue in degrees
+ */
+pub fn oklch_from_oklab(lab: [f32; 3]) -> [f32; 3] {
+  let [l, a, b] = lab;
+  let hue = b.atan2(a).to_degrees().rem_euclid(360.0);
+  [l, a.hypot(b), hue]
+}
+
+pub fn oklab_from_oklch(lch: [f32; 3]) -> [f32; 3] {
+  let [l, c, h] = lch;
+  let (sin, cos) = h.to_radians().sin_cos();
+  [l, c * cos, c * sin]
+}
+
+/**
+ * ACEScct, the log encoding with a linear toe that grading tools use for ACES
+ */
+pub fn acescct_from_linear(linear: f32) -> f32 {
+  if linear <= 0.0078125 {
+    10.5402377416545 * linear + 0.0729055341958355
+  } else {
+    (linear.log2() + 9.72) / 17.52
+  }
+}
+
+pub fn linear_from_acescct(cct: f32) -> f32 {
+  if cct <= 0.155251141552511 {
+    (cct - 0.0729055341958355) / 10.5402377416545
+  } else {
+    (cct * 17.52 - 9.72).exp2().min(65504.0)
+  }
+}
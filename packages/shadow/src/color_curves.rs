@@ -0,0 +1,85 @@
+use crate::curve::Curve;
+use crate::smoothstep;
+
+/**
+ * Secondary curves, each changes one property of a color depending on another.
+ * All of them work in OKLCh, the y of a point is the change, 0 leaves the color as it is.
+ */
+pub struct ColorCurves {
+  hue_saturation: Option<Curve>,
+  hue_luminance: Option<Curve>,
+  luminance_saturation: Option<Curve>,
+  luminance_hue: Option<Curve>,
+}
+
+impl ColorCurves {
+  pub fn new(
+    hue_saturation: &[(f32, f32)],
+    hue_luminance: &[(f32, f32)],
+    luminance_saturation: &[(f32, f32)],
+    luminance_hue: &[(f32, f32)],
+  ) -> ColorCurves {
+    ColorCurves {
+      hue_saturation: Curve::periodic(hue_saturation),
+      hue_luminance: Curve::periodic(hue_luminance),
+      luminance_saturation: Curve::new(luminance_saturation),
+      luminance_hue: Curve::new(luminance_hue),
+    }
+  }
+
+  /**
+   * Hue curves take the hue as a fraction of the circle and luminance curves the lightness in 0..1.
+   * Saturation changes scale the chroma by 1 + y, luminance changes are in stops
+   * and hue changes rotate by up to 180 degrees.
+   */
+  pub fn apply(&self, lch: [f32; 3]) -> [f32; 3] {
+    let [lightness, chroma, hue] = lch;
+    let turn = hue / 360.0;
+    // hue is meaningless for greys and noisy for near greys, fade the hue curves out with the chroma
+    let colorfulness = smoothstep(0.0, 0.02, chroma);
+
+    let mut saturation = 1.0;
+    let mut stops = 0.0;
+    let mut rotation = 0.0;
+
+    if let Some(curve) = &self.hue_saturation {
+      saturation *= 1.0 + curve.apply(turn) * colorfulness;
+    }
+    if let Some(curve) = &self.hue_luminance {
+      stops += curve.apply(turn) * colorfulness;
+    }
+    if let Some(curve) = &self.luminance_saturation {
+      saturation *= 1.0 + curve.apply(lightness);
+    }
+    if let Some(curve) = &self.luminance_hue {
+      rotation += curve.apply(lightness) * 180.0;
+    }
+
+    // the lightness is the cube root of the luminance, so a stop is a third in its exponent
+    let lightness = lightness * (stops / 3.0).exp2();
+
+    [
+      lightness,
+      (chroma * saturation).max(0.0),
+      (hue + rotation).rem_euclid(360.0),
+    ]
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn hue_curves_leave_greys_alone() {
+    let curves = ColorCurves::new(&[(0.08, 1.0)], &[(0.08, 1.0)], &[], &[]);
+    let grey = [0.5, 0.0, 0.0];
+    assert_eq!(curves.apply(grey), grey);
+    // near greys only get a fraction of the change
+    let near_grey = curves.apply([0.5, 0.005, 30.0]);
+    assert!(near_grey[0] < 0.5 * 1.1);
+    let red = curves.apply([0.5, 0.15, 30.0]);
+    assert!((red[0] - 0.5 * (1.0f32 / 3.0).exp2()).abs() < 1e-3);
+    assert!((red[1] - 0.3).abs() < 1e-3);
+  }
+}
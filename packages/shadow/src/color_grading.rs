@@ -0,0 +1,193 @@
+use crate::color::{acescct_from_linear, linear_srgb_from_oklab, oklab_from_oklch};
+use crate::{conversion, luminance, mat_mul, smoothstep, WORKING_COLORSPACE};
+use serde::{Deserialize, Serialize};
+
+/**
+ * Middle grey in ACEScct, the tonal ranges are split around it
+ */
+const MIDDLE_GREY_CCT: f32 = 0.4135;
+
+/**
+ * Distance in ACEScct from middle grey to the split between midtones and shadows or highlights,
+ * about two stops
+ */
+const RANGE: f32 = 0.11;
+
+/**
+ * One wheel of the grading, hue in degrees, saturation in 0..1 and luminance in stops
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Wheel {
+  #[serde(default)]
+  pub hue: f32,
+  #[serde(default)]
+  pub saturation: f32,
+  #[serde(default)]
+  pub luminance: f32,
+}
+
+impl Wheel {
+  /**
+   * Per channel gain the wheel tints with, at the luminance of white so the tint doesn't brighten
+   */
+  fn gain(&self) -> [f32; 3] {
+    if self.saturation == 0.0 {
+      return [1.0; 3];
+    }
+    let tint = linear_srgb_from_oklab(oklab_from_oklch([0.75, 0.12, self.hue]));
+    let tint = mat_mul(
+      &conversion(kolor::spaces::LINEAR_SRGB, WORKING_COLORSPACE),
+      &tint,
+    );
+    let scale = luminance(tint).max(1e-6);
+    tint.map(|c| 1.0 + ((c / scale).max(0.0) - 1.0) * self.saturation.clamp(0.0, 1.0))
+  }
+}
+
+/**
+ * Three way color grading with a global wheel on top, in the working space.
+ * Each pixel is tinted and brightened by the wheels of the tonal ranges it falls in.
+ */
+pub struct ColorGrading {
+  gains: [[f32; 3]; 4],
+  stops: [f32; 4],
+  low: f32,
+  high: f32,
+  width: f32,
+}
+
+impl ColorGrading {
+  /**
+   * Blending in 0..1 widens the overlap of the ranges, balance in -1..1 moves them,
+   * a positive balance gives more of the image to the highlights
+   */
+  pub fn new(
+    shadows: &Wheel,
+    midtones: &Wheel,
+    highlights: &Wheel,
+    global: &Wheel,
+    blending: f32,
+    balance: f32,
+  ) -> ColorGrading {
+    let center = MIDDLE_GREY_CCT - balance.clamp(-1.0, 1.0) * RANGE;
+    let wheels = [shadows, midtones, highlights, global];
+    ColorGrading {
+      gains: wheels.map(|wheel| wheel.gain()),
+      stops: wheels.map(|wheel| wheel.luminance),
+      low: center - RANGE,
+      high: center + RANGE,
+      // never wider than the midtones, so they don't get a negative weight
+      width: RANGE * (0.2 + blending.clamp(0.0, 1.0) * 0.8),
+    }
+  }
+
+  /**
+   * How much of a pixel falls in the shadows, midtones and highlights, they sum to 1
+   */
+  fn weights(&self, rgb: [f32; 3]) -> [f32; 3] {
+    let cct = acescct_from_linear(luminance(rgb));
+    let shadows = 1.0 - smoothstep(self.low - self.width, self.low + self.width, cct);
+    let highlights = smoothstep(self.high - self.width, self.high + self.width, cct);
+    [shadows, 1.0 - shadows - highlights, highlights]
+  }
+
+  pub fn apply(&self, rgb: [f32; 3]) -> [f32; 3] {
+    let [shadows, midtones, highlights] = self.weights(rgb);
+    let weights = [shadows, midtones, highlights, 1.0];
+
+    let mut gain = [1.0; 3];
+    let mut stops = 0.0;
+    for ((weight, wheel_gain), wheel_stops) in weights.iter().zip(&self.gains).zip(&self.stops) {
+      for c in 0..3 {
+        gain[c] *= 1.0 + (wheel_gain[c] - 1.0) * weight;
+      }
+      stops += wheel_stops * weight;
+    }
+
+    let exposure = stops.exp2();
+    [0, 1, 2].map(|c| rgb[c] * gain[c] * exposure)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn grading(shadows: &Wheel, blending: f32, balance: f32) -> ColorGrading {
+    let none = Wheel::default();
+    ColorGrading::new(shadows, &none, &none, &none, blending, balance)
+  }
+
+  #[test]
+  fn zero_wheels_are_the_identity() {
+    let grading = grading(&Wheel::default(), 0.5, 0.3);
+    for rgb in [
+      [0.0; 3],
+      [0.002, 0.01, 0.004],
+      [0.18, 0.2, 0.1],
+      [6.0, 2.0, 9.0],
+    ] {
+      let graded = grading.apply(rgb);
+      assert!(
+        graded.iter().zip(rgb).all(|(a, b)| (a - b).abs() < 1e-6),
+        "{graded:?}"
+      );
+    }
+  }
+
+  #[test]
+  fn shadows_wheel_only_tints_the_shadows() {
+    let shadows = Wheel {
+      hue: 220.0,
+      saturation: 0.6,
+      luminance: 0.0,
+    };
+    let grading = grading(&shadows, 0.5, 0.0);
+
+    let bright = [4.0; 3];
+    assert_eq!(grading.apply(bright), bright);
+
+    let [r, g, b] = grading.apply([0.005; 3]);
+    assert!((r - g).abs() > 1e-4 || (g - b).abs() > 1e-4, "{r} {g} {b}");
+  }
+
+  #[test]
+  fn weights_split_every_pixel() {
+    for blending in [0.0, 0.5, 1.0] {
+      for balance in [-1.0, -0.4, 0.0, 0.4, 1.0] {
+        let grading = grading(&Wheel::default(), blending, balance);
+        for i in 0..=100 {
+          let value = (i as f32 * 0.2 - 14.0).exp2();
+          let weights = grading.weights([value; 3]);
+          assert!(weights.iter().all(|w| *w >= 0.0), "{weights:?}");
+          assert!(
+            (weights.iter().sum::<f32>() - 1.0).abs() < 1e-6,
+            "{weights:?}"
+          );
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn balance_moves_the_split_towards_the_highlights() {
+    let [darker, neutral, brighter] =
+      [-1.0, 0.0, 1.0].map(|balance| grading(&Wheel::default(), 0.5, balance));
+    for i in 0..=100 {
+      let rgb = [(i as f32 * 0.2 - 14.0).exp2(); 3];
+      let (before, after) = (neutral.weights(rgb), brighter.weights(rgb));
+      assert!(
+        after[2] >= before[2] && after[0] <= before[0],
+        "{before:?} {after:?}"
+      );
+    }
+
+    // a light grey the neutral split shares between midtones and highlights
+    let light = [0.8; 3];
+    let highlights = [&darker, &neutral, &brighter].map(|grading| grading.weights(light)[2]);
+    assert!(
+      highlights[0] < highlights[1] && highlights[1] < highlights[2],
+      "{highlights:?}"
+    );
+  }
+}
@@ -0,0 +1,168 @@
+use crate::tiles;
+use crate::white_balance::{adaptation, xyz_from_xy, D65, SRGB_TO_XYZ};
+use crate::{conversion, mat_inverse, mat_mul, mat_product};
+use image::{ImageBuffer, Rgb};
+use serde::{Deserialize, Serialize};
+
+pub const D50: [f32; 2] = [0.34567, 0.35850];
+
+const IDENTITY: [[f32; 3]; 3] = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+/**
+ * Color space renders and exports are encoded in
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputSpace {
+  #[default]
+  Srgb,
+  DisplayP3,
+  AdobeRgb,
+  Rec2020,
+  ProPhoto,
+}
+
+/**
+ * Transfer functions as the parameters of ICC parametric curve type 3, from encoded to linear:
+ * `(a * x + b) ^ gamma` above `d` and `c * x` below
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct Transfer {
+  pub gamma: f32,
+  pub a: f32,
+  pub b: f32,
+  pub c: f32,
+  pub d: f32,
+}
+
+impl Transfer {
+  const fn gamma(gamma: f32) -> Transfer {
+    Transfer {
+      gamma,
+      a: 1.0,
+      b: 0.0,
+      c: 0.0,
+      d: 0.0,
+    }
+  }
+
+  pub fn encode(&self, linear: f32) -> f32 {
+    // the linear segment ends at c * d
+    if self.c > 0.0 && linear < self.c * self.d {
+      linear / self.c
+    } else {
+      (linear.max(0.0).powf(1.0 / self.gamma) - self.b) / self.a
+    }
+  }
+}
+
+const SRGB_TRANSFER: Transfer = Transfer {
+  gamma: 2.4,
+  a: 1.0 / 1.055,
+  b: 0.055 / 1.055,
+  c: 1.0 / 12.92,
+  d: 0.04045,
+};
+
+impl OutputSpace {
+  pub fn name(&self) -> &'static str {
+    match self {
+      OutputSpace::Srgb => "sRGB",
+      OutputSpace::DisplayP3 => "Display P3",
+      OutputSpace::AdobeRgb => "Adobe RGB (1998)",
+      OutputSpace::Rec2020 => "Rec. ITU-R BT.2020",
+      OutputSpace::ProPhoto => "ProPhoto RGB",
+    }
+  }
+
+  /**
+   * xy chromaticities of the red, green and blue primaries
+   */
+  fn primaries(&self) -> [[f32; 2]; 3] {
+    match self {
+      OutputSpace::Srgb => [[0.64, 0.33], [0.30, 0.60], [0.15, 0.06]],
+      OutputSpace::DisplayP3 => [[0.680, 0.320], [0.265, 0.690], [0.150, 0.060]],
+      OutputSpace::AdobeRgb => [[0.64, 0.33], [0.21, 0.71], [0.15, 0.06]],
+      OutputSpace::Rec2020 => [[0.708, 0.292], [0.170, 0.797], [0.131, 0.046]],
+      OutputSpace::ProPhoto => [[0.7347, 0.2653], [0.1596, 0.8404], [0.0366, 0.0001]],
+    }
+  }
+
+  pub fn white(&self) -> [f32; 2] {
+    match self {
+      OutputSpace::ProPhoto => D50,
+      _ => D65,
+    }
+  }
+
+  pub fn transfer(&self) -> Transfer {
+    match self {
+      OutputSpace::Srgb | OutputSpace::DisplayP3 => SRGB_TRANSFER,
+      OutputSpace::AdobeRgb => Transfer::gamma(563.0 / 256.0),
+      // the inverse of the BT.709 camera curve BT.2020 shares
+      OutputSpace::Rec2020 => Transfer {
+        gamma: 1.0 / 0.45,
+        a: 1.0 / 1.099,
+        b: 0.099 / 1.099,
+        c: 1.0 / 4.5,
+        d: 0.081,
+      },
+      OutputSpace::ProPhoto => Transfer {
+        gamma: 1.8,
+        a: 1.0,
+        b: 0.0,
+        c: 1.0 / 16.0,
+        d: 16.0 / 512.0,
+      },
+    }
+  }
+
+  /**
+   * Linear RGB to XYZ under the space's own white
+   */
+  pub fn to_xyz(&self) -> [[f32; 3]; 3] {
+    let primaries = self.primaries().map(xyz_from_xy);
+    let columns = [0, 1, 2].map(|row| [0, 1, 2].map(|col| primaries[col][row]));
+    // each primary scaled so together they add up to the white
+    let scale = mat_mul(&mat_inverse(&columns).unwrap(), &xyz_from_xy(self.white()));
+    columns.map(|row| [0, 1, 2].map(|col| row[col] * scale[col]))
+  }
+
+  /**
+   * Linear sRGB to linear RGB of this space, D65 adapted to the space's white
+   */
+  fn matrix_from_linear_srgb(&self) -> [[f32; 3]; 3] {
+    if *self == OutputSpace::Srgb {
+      return IDENTITY;
+    }
+    let xyz = mat_product(&adaptation(D65, self.white()), &SRGB_TO_XYZ);
+    mat_product(&mat_inverse(&self.to_xyz()).unwrap(), &xyz)
+  }
+
+  /**
+   * Working space to linear RGB of this space
+   */
+  pub fn from_working(&self) -> [[f32; 3]; 3] {
+    let to_srgb = conversion(crate::WORKING_COLORSPACE, kolor::spaces::LINEAR_SRGB);
+    mat_product(&self.matrix_from_linear_srgb(), &to_srgb)
+  }
+}
+
+/**
+ * Converts from the working space to display encoded RGB of the output space
+ */
+pub fn encode(image: &mut ImageBuffer<Rgb<f32>, Vec<f32>>, space: OutputSpace) {
+  let matrix = space.from_working();
+  match space {
+    // the exact curve the rest of the crate encodes sRGB with
+    OutputSpace::Srgb | OutputSpace::DisplayP3 => tiles::map_pixels(image, |rgb| {
+      srgb::gamma::normalised_from_linear(mat_mul(&matrix, &rgb))
+    }),
+    _ => {
+      let transfer = space.transfer();
+      tiles::map_pixels(image, |rgb| {
+        mat_mul(&matrix, &rgb).map(|c| transfer.encode(c))
+      });
+    }
+  }
+}
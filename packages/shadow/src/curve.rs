@@ -0,0 +1,264 @@
+const LUT_SIZE: usize = 4096;
+
+/**
+ * Tone curve through a set of control points, sampled into a lookup table.
+ * Interpolates with a monotone cubic spline (Fritsch-Carlson), so the curve never overshoots
+ * between two points and a rising set of points always gives a rising curve.
+ */
+pub struct Curve {
+  lut: Vec<f32>,
+}
+
+impl Curve {
+  /**
+   * Returns None for curves with less than two points, those leave the image untouched.
+   */
+  pub fn new(points: &[(f32, f32)]) -> Option<Curve> {
+    Curve::from_points(points.to_vec())
+  }
+
+  /**
+   * Curve over a circular axis like hue, where 1 wraps around to 0.
+   * A single point is enough here, it offsets the whole circle.
+   */
+  pub fn periodic(points: &[(f32, f32)]) -> Option<Curve> {
+    if points.is_empty() {
+      return None;
+    }
+
+    let wrapped = points
+      .iter()
+      .flat_map(|&(x, y)| {
+        let x = x.rem_euclid(1.0);
+        [(x - 1.0, y), (x, y), (x + 1.0, y)]
+      })
+      .collect();
+
+    Curve::from_points(wrapped)
+  }
+
+  fn from_points(mut points: Vec<(f32, f32)>) -> Option<Curve> {
+    points.sort_by(|a, b| a.0.total_cmp(&b.0));
+    points.dedup_by(|a, b| a.0 == b.0);
+
+    if points.len() < 2 {
+      return None;
+    }
+
+    let tangents = tangents(&points);
+
+    let lut = (0..LUT_SIZE)
+      .map(|i| {
+        let x = i as f32 / (LUT_SIZE - 1) as f32;
+        interpolate(&points, &tangents, x)
+      })
+      .collect();
+
+    Some(Curve { lut })
+  }
+
+  /**
+   * Maps a value in 0..1, anything outside is clamped to the ends of the curve
+   */
+  pub fn apply(&self, value: f32) -> f32 {
+    let position = value.clamp(0.0, 1.0) * (LUT_SIZE - 1) as f32;
+    let index = (position as usize).min(LUT_SIZE - 2);
+    let t = position - index as f32;
+    self.lut[index] * (1.0 - t) + self.lut[index + 1] * t
+  }
+
+  /**
+   * Like `apply`, but continues the curve in a straight line with its slope at 0 and 1,
+   * so values above white or below black keep their headroom instead of clipping
+   */
+  pub fn apply_extended(&self, value: f32) -> f32 {
+    let last = LUT_SIZE - 1;
+    let step = last as f32;
+    if value > 1.0 {
+      let slope = (self.lut[last] - self.lut[last - 1]) * step;
+      self.lut[last] + (value - 1.0) * slope
+    } else if value < 0.0 {
+      let slope = (self.lut[1] - self.lut[0]) * step;
+      self.lut[0] + value * slope
+    } else {
+      self.apply(value)
+    }
+  }
+}
+
+fn tangents(points: &[(f32, f32)]) -> Vec<f32> {
+  let n = points.len();
+
+  let secants: Vec<f32> = points
+    .windows(2)
+    .map(|p| (p[1].1 - p[0].1) / (p[1].0 - p[0].0))
+    .collect();
+
+  let mut tangents = vec![0.0; n];
+  tangents[0] = secants[0];
+  tangents[n - 1] = secants[n - 2];
+  for i in 1..n - 1 {
+    if secants[i - 1] * secants[i] > 0.0 {
+      tangents[i] = (secants[i - 1] + secants[i]) / 2.0;
+    }
+  }
+
+  // limit the tangents so each segment stays monotonic
+  for i in 0..n - 1 {
+    if secants[i] == 0.0 {
+      tangents[i] = 0.0;
+      tangents[i + 1] = 0.0;
+      continue;
+    }
+
+    let a = tangents[i] / secants[i];
+    let b = tangents[i + 1] / secants[i];
+    let length = (a * a + b * b).sqrt();
+    if length > 3.0 {
+      tangents[i] = 3.0 / length * a * secants[i];
+      tangents[i + 1] = 3.0 / length * b * secants[i];
+    }
+  }
+
+  tangents
+}
+
+fn interpolate(points: &[(f32, f32)], tangents: &[f32], x: f32) -> f32 {
+  let last = points.len() - 1;
+
+  if x <= points[0].0 {
+    return points[0].1;
+  }
+  if x >= points[last].0 {
+    return points[last].1;
+  }
+
+  let i = points
+    .iter()
+    .rposition(|p| p.0 <= x)
+    .unwrap_or(0)
+    .min(last - 1);
+  let (x0, y0) = points[i];
+  let (x1, y1) = points[i + 1];
+  let h = x1 - x0;
+  let t = (x - x0) / h;
+
+  // cubic hermite basis
+  let t2 = t * t;
+  let t3 = t2 * t;
+  let h00 = 2.0 * t3 - 3.0 * t2 + 1.0;
+  let h10 = t3 - 2.0 * t2 + t;
+  let h01 = -2.0 * t3 + 3.0 * t2;
+  let h11 = t3 - t2;
+
+  h00 * y0 + h10 * h * tangents[i] + h01 * y1 + h11 * h * tangents[i + 1]
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn samples(curve: &Curve) -> Vec<f32> {
+    (0..=1000).map(|i| curve.apply(i as f32 / 1000.0)).collect()
+  }
+
+  #[test]
+  fn needs_two_points() {
+    assert!(Curve::new(&[]).is_none());
+    assert!(Curve::new(&[(0.5, 0.5)]).is_none());
+    // points on the same x count once
+    assert!(Curve::new(&[(0.5, 0.2), (0.5, 0.8)]).is_none());
+    assert!(Curve::new(&[(0.0, 0.0), (1.0, 1.0)]).is_some());
+  }
+
+  #[test]
+  fn identity() {
+    let curve = Curve::new(&[(0.0, 0.0), (1.0, 1.0)]).unwrap();
+    for i in 0..=100 {
+      let x = i as f32 / 100.0;
+      assert!((curve.apply(x) - x).abs() < 1e-4, "{x}");
+    }
+  }
+
+  #[test]
+  fn passes_through_points() {
+    let points = [(0.0, 0.1), (0.3, 0.2), (0.6, 0.9), (1.0, 0.95)];
+    let curve = Curve::new(&points).unwrap();
+    for (x, y) in points {
+      assert!((curve.apply(x) - y).abs() < 1e-3, "{x}");
+    }
+  }
+
+  #[test]
+  fn points_are_sorted() {
+    let sorted = Curve::new(&[(0.0, 0.0), (0.4, 0.6), (1.0, 1.0)]).unwrap();
+    let shuffled = Curve::new(&[(1.0, 1.0), (0.0, 0.0), (0.4, 0.6)]).unwrap();
+    assert_eq!(samples(&sorted), samples(&shuffled));
+  }
+
+  #[test]
+  fn rising_points_give_a_rising_curve() {
+    // a steep step between flat parts makes a plain cubic spline overshoot
+    let curve = Curve::new(&[(0.0, 0.0), (0.4, 0.05), (0.5, 0.95), (1.0, 1.0)]).unwrap();
+    let values = samples(&curve);
+    for pair in values.windows(2) {
+      assert!(pair[1] >= pair[0] - 1e-6);
+    }
+  }
+
+  #[test]
+  fn does_not_overshoot_flat_segments() {
+    let curve = Curve::new(&[(0.0, 0.2), (0.3, 0.2), (0.6, 0.8), (1.0, 0.8)]).unwrap();
+    for value in samples(&curve) {
+      assert!((0.2 - 1e-6..=0.8 + 1e-6).contains(&value), "{value}");
+    }
+    // flat segments stay flat
+    assert!((curve.apply(0.15) - 0.2).abs() < 1e-6);
+    assert!((curve.apply(0.8) - 0.8).abs() < 1e-6);
+  }
+
+  #[test]
+  fn holds_the_end_points() {
+    let curve = Curve::new(&[(0.2, 0.3), (0.8, 0.6)]).unwrap();
+    assert_eq!(curve.apply(0.0), 0.3);
+    assert_eq!(curve.apply(0.1), 0.3);
+    assert_eq!(curve.apply(0.9), 0.6);
+    assert_eq!(curve.apply(1.0), 0.6);
+    // inputs outside of 0..1 clamp
+    assert_eq!(curve.apply(-1.0), 0.3);
+    assert_eq!(curve.apply(2.0), 0.6);
+  }
+
+  #[test]
+  fn extends_past_the_ends() {
+    let identity = Curve::new(&[(0.0, 0.0), (1.0, 1.0)]).unwrap();
+    assert!((identity.apply_extended(4.0) - 4.0).abs() < 1e-3);
+    assert!((identity.apply_extended(-0.5) + 0.5).abs() < 1e-3);
+
+    // the slope at white carries on, a flat end stays flat
+    let lifted = Curve::new(&[(0.0, 0.1), (1.0, 0.9)]).unwrap();
+    assert!((lifted.apply_extended(2.0) - 1.7).abs() < 1e-3);
+    let flat = Curve::new(&[(0.0, 0.0), (0.8, 1.0)]).unwrap();
+    assert_eq!(flat.apply_extended(3.0), 1.0);
+  }
+
+  #[test]
+  fn interpolates_between_lut_entries() {
+    let curve = Curve::new(&[(0.0, 0.0), (1.0, 1.0)]).unwrap();
+    let step = 1.0 / (LUT_SIZE - 1) as f32;
+    let x = step * 10.5;
+    assert!((curve.apply(x) - x).abs() < 1e-6);
+  }
+
+  #[test]
+  fn periodic_wraps_around() {
+    let curve = Curve::periodic(&[(0.0, 0.5), (0.5, -0.5)]).unwrap();
+    assert!((curve.apply(0.0) - curve.apply(1.0)).abs() < 1e-4);
+    assert!((curve.apply(0.5) + 0.5).abs() < 1e-3);
+    // a single point offsets the whole circle
+    let constant = Curve::periodic(&[(0.3, 0.25)]).unwrap();
+    for value in samples(&constant) {
+      assert!((value - 0.25).abs() < 1e-6);
+    }
+  }
+}
@@ -0,0 +1,800 @@
+use crate::curve::Curve;
+use crate::white_balance::{adaptation, xyz_from_xy, WhiteBalance};
+use crate::{mat_inverse, mat_mul, mat_product};
+use anyhow::anyhow;
+use image::{ImageBuffer, Rgb};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::SystemTime;
+
+const BASELINE_EXPOSURE: u16 = 50730;
+const COLOR_MATRIX_1: u16 = 50721;
+const COLOR_MATRIX_2: u16 = 50722;
+const CALIBRATION_ILLUMINANT_1: u16 = 50778;
+const CALIBRATION_ILLUMINANT_2: u16 = 50779;
+const FORWARD_MATRIX_1: u16 = 50964;
+const FORWARD_MATRIX_2: u16 = 50965;
+const HUE_SAT_MAP_DIMS: u16 = 50937;
+const HUE_SAT_MAP_DATA_1: u16 = 50938;
+const HUE_SAT_MAP_DATA_2: u16 = 50939;
+const TONE_CURVE: u16 = 50940;
+const LOOK_TABLE_DIMS: u16 = 50981;
+const LOOK_TABLE_DATA: u16 = 50982;
+const HUE_SAT_MAP_ENCODING: u16 = 51107;
+const LOOK_TABLE_ENCODING: u16 = 51108;
+const BASELINE_EXPOSURE_OFFSET: u16 = 51109;
+
+const D50: [f32; 2] = [0.34567, 0.35850];
+
+/**
+ * XYZ to linear ProPhoto RGB, both with a D50 white
+ */
+const XYZ_TO_PROPHOTO: [[f32; 3]; 3] = [
+  [1.3459433, -0.2556075, -0.0511118],
+  [-0.5445989, 1.5081673, 0.0205351],
+  [0.0, 0.0, 1.2118128],
+];
+
+/**
+ * ACES2065-1 to linear ProPhoto RGB, with a Bradford adaptation from the ACES white to D50
+ */
+const AP0_TO_PROPHOTO: [[f32; 3]; 3] = [
+  [1.24137, -0.1685873, -0.0726754],
+  [0.0061201, 1.0831042, -0.0892676],
+  [-0.0032849, 0.0099784, 0.9931788],
+];
+const PROPHOTO_TO_AP0: [[f32; 3]; 3] = [
+  [0.8051325, 0.1246745, 0.0701210],
+  [-0.0043263, 0.9218384, 0.0825389],
+  [0.0027064, -0.0088493, 1.0062707],
+];
+
+/**
+ * A DNG camera profile (.dcp). Its color and forward matrices take the camera's raw RGB to XYZ,
+ * then the hue / saturation map and look table are applied in linear ProPhoto RGB like Adobe's
+ * converters do. Profiles calibrated for two illuminants are interpolated between them
+ * by the color temperature of the white balance. The tone curve belongs to the output,
+ * it is applied to the rendered image separately.
+ */
+pub struct CameraProfile {
+  /**
+   * Color temperatures of the two calibration illuminants, when they are known
+   */
+  temperatures: [Option<f32>; 2],
+  color_matrices: [Option<[[f32; 3]; 3]>; 2],
+  forward_matrices: [Option<[[f32; 3]; 3]>; 2],
+  /**
+   * Exposure in stops the profile expects on top of the raw data
+   */
+  baseline_exposure: f32,
+  hue_sat_maps: [Option<HueSatMap>; 2],
+  look_table: Option<HueSatMap>,
+  tone_curve: Option<Curve>,
+}
+
+/**
+ * Hue shift in degrees, saturation and value scale, by hue, saturation and value
+ */
+#[derive(Clone)]
+struct HueSatMap {
+  hues: usize,
+  saturations: usize,
+  values: usize,
+  srgb_encoded: bool,
+  data: Vec<[f32; 3]>,
+}
+
+/**
+ * Parsed profiles with the modification time of their file
+ */
+type Profiles = HashMap<PathBuf, (SystemTime, Arc<CameraProfile>)>;
+
+fn profiles() -> &'static Mutex<Profiles> {
+  static PROFILES: OnceLock<Mutex<Profiles>> = OnceLock::new();
+  PROFILES.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+impl CameraProfile {
+  /**
+   * Like `open`, but keeps the parsed profile until the file changes
+   */
+  pub fn cached(path: &Path) -> anyhow::Result<Arc<CameraProfile>> {
+    let modified = std::fs::metadata(path)?.modified()?;
+    if let Some((time, profile)) = profiles().lock().unwrap().get(path) {
+      if *time == modified {
+        return Ok(profile.clone());
+      }
+    }
+
+    let profile = Arc::new(CameraProfile::open(path)?);
+    profiles()
+      .lock()
+      .unwrap()
+      .insert(path.to_path_buf(), (modified, profile.clone()));
+    Ok(profile)
+  }
+
+  pub fn open(path: &Path) -> anyhow::Result<CameraProfile> {
+    let data = std::fs::read(path)?;
+    CameraProfile::parse(&data)
+  }
+
+  pub fn parse(data: &[u8]) -> anyhow::Result<CameraProfile> {
+    let tiff = Tiff::parse(data)?;
+
+    let matrix = |tag: u16| -> anyhow::Result<Option<[[f32; 3]; 3]>> {
+      match tiff.values(tag) {
+        Some(m) if m.len() == 9 => Ok(Some([
+          [m[0], m[1], m[2]],
+          [m[3], m[4], m[5]],
+          [m[6], m[7], m[8]],
+        ])),
+        Some(_) => Err(anyhow!("Only three color matrices are supported")),
+        None => Ok(None),
+      }
+    };
+    let temperature = |tag: u16| {
+      let illuminant = tiff.values(tag)?.first().copied()?;
+      illuminant_temperature(illuminant as u16)
+    };
+
+    let map = |dims: u16, values: u16, encoding: u16| -> anyhow::Result<Option<HueSatMap>> {
+      let (Some(dims), Some(data)) = (tiff.values(dims), tiff.values(values)) else {
+        return Ok(None);
+      };
+      if dims.len() != 3 {
+        return Err(anyhow!("Expected three hue / saturation map dimensions"));
+      }
+
+      let [hues, saturations, values] = [0, 1, 2].map(|i| (dims[i] as usize).max(1));
+      let size = [saturations, values, 3]
+        .iter()
+        .try_fold(hues, |size, dim| size.checked_mul(*dim));
+      if size != Some(data.len()) {
+        return Err(anyhow!(
+          "Hue / saturation map does not match its dimensions"
+        ));
+      }
+
+      Ok(Some(HueSatMap {
+        hues,
+        saturations,
+        values,
+        srgb_encoded: tiff
+          .values(encoding)
+          .is_some_and(|e| e.first() == Some(&1.0)),
+        data: data.chunks(3).map(|c| [c[0], c[1], c[2]]).collect(),
+      }))
+    };
+
+    let color_matrices = [matrix(COLOR_MATRIX_1)?, matrix(COLOR_MATRIX_2)?];
+    if color_matrices[0].is_none() {
+      return Err(anyhow!("Camera profile has no color matrix"));
+    }
+
+    let exposure = |tag: u16| {
+      tiff
+        .values(tag)
+        .and_then(|values| values.first().copied())
+        .unwrap_or(0.0)
+    };
+
+    let tone_curve = tiff.values(TONE_CURVE).and_then(|values| {
+      let points: Vec<(f32, f32)> = values.chunks(2).map(|p| (p[0], p[1])).collect();
+      Curve::new(&points)
+    });
+
+    Ok(CameraProfile {
+      temperatures: [
+        temperature(CALIBRATION_ILLUMINANT_1),
+        temperature(CALIBRATION_ILLUMINANT_2),
+      ],
+      color_matrices,
+      forward_matrices: [matrix(FORWARD_MATRIX_1)?, matrix(FORWARD_MATRIX_2)?],
+      baseline_exposure: exposure(BASELINE_EXPOSURE) + exposure(BASELINE_EXPOSURE_OFFSET),
+      hue_sat_maps: [
+        map(HUE_SAT_MAP_DIMS, HUE_SAT_MAP_DATA_1, HUE_SAT_MAP_ENCODING)?,
+        map(HUE_SAT_MAP_DIMS, HUE_SAT_MAP_DATA_2, HUE_SAT_MAP_ENCODING)?,
+      ],
+      look_table: map(LOOK_TABLE_DIMS, LOOK_TABLE_DATA, LOOK_TABLE_ENCODING)?,
+      tone_curve,
+    })
+  }
+
+  /**
+   * How much of the first illuminant's calibration is used under a light of that temperature,
+   * linear in inverse temperature between the two illuminants like the DNG specification.
+   * Without two known illuminants the second one, usually daylight, is used.
+   */
+  fn weight(&self, temperature: f32) -> f32 {
+    match self.temperatures {
+      [Some(first), Some(second)] if first != second => {
+        let weight = (1.0 / temperature - 1.0 / second) / (1.0 / first - 1.0 / second);
+        weight.clamp(0.0, 1.0)
+      }
+      _ => 0.0,
+    }
+  }
+
+  /**
+   * Renders an image in the working space under the given light. With `camera_from_working`,
+   * the matrix back to the camera's raw RGB, the profile's matrices develop the image
+   * and that also sets its white balance. Without it the image is expected to be white balanced
+   * already and only the hue / saturation map and look table are applied.
+   */
+  pub fn apply(
+    &self,
+    image: &mut ImageBuffer<Rgb<f32>, Vec<f32>>,
+    white_balance: &WhiteBalance,
+    camera_from_working: Option<&[[f32; 3]; 3]>,
+  ) -> anyhow::Result<()> {
+    let weight = self.weight(white_balance.temperature);
+    let gain = 2.0_f32.powf(self.baseline_exposure);
+
+    let prophoto_from_working = match camera_from_working {
+      Some(camera) => {
+        let camera_to_xyz = self.camera_to_xyz(white_balance, weight)?;
+        mat_product(&XYZ_TO_PROPHOTO, &mat_product(&camera_to_xyz, camera))
+      }
+      None => AP0_TO_PROPHOTO,
+    }
+    .map(|row| row.map(|v| v * gain));
+
+    let hue_sat_map = match &self.hue_sat_maps {
+      [Some(first), Some(second)] => Some(first.blend(second, weight)),
+      [first, second] => first.as_ref().or(second.as_ref()).cloned(),
+    };
+
+    crate::tiles::map_pixels(image, |rgb| {
+      let mut rgb = mat_mul(&prophoto_from_working, &rgb);
+
+      if let Some(map) = &hue_sat_map {
+        rgb = map.apply(rgb);
+      }
+      if let Some(map) = &self.look_table {
+        rgb = map.apply(rgb);
+      }
+
+      mat_mul(&PROPHOTO_TO_AP0, &rgb)
+    });
+    Ok(())
+  }
+
+  /**
+   * Applies the tone curve to an image in the working space, at the end of the rendering
+   */
+  pub fn apply_tone_curve(&self, image: &mut ImageBuffer<Rgb<f32>, Vec<f32>>) {
+    let Some(curve) = &self.tone_curve else {
+      return;
+    };
+    crate::tiles::map_pixels(image, |rgb| {
+      let rgb = rgb_tone(mat_mul(&AP0_TO_PROPHOTO, &rgb), curve);
+      mat_mul(&PROPHOTO_TO_AP0, &rgb)
+    });
+  }
+
+  /**
+   * Raw camera RGB to XYZ with a D50 white, for an image under the given light.
+   * A neutral surface keeps the value of its green channel as its luminance.
+   */
+  fn camera_to_xyz(
+    &self,
+    white_balance: &WhiteBalance,
+    weight: f32,
+  ) -> anyhow::Result<[[f32; 3]; 3]> {
+    let xyz_to_camera = blend_matrices(&self.color_matrices, weight)
+      .ok_or(anyhow!("Camera profile has no color matrix"))?;
+
+    // the camera RGB of a white surface under the light
+    let neutral = mat_mul(&xyz_to_camera, &xyz_from_xy(white_balance.xy()));
+    if neutral.iter().any(|n| !n.is_finite() || *n <= 0.0) {
+      return Err(anyhow!(
+        "Camera profile has no neutral for {:?}",
+        white_balance
+      ));
+    }
+
+    match blend_matrices(&self.forward_matrices, weight) {
+      // the forward matrix takes white balanced camera RGB to XYZ, scaled so white lands on D50
+      Some(forward) => {
+        let white = xyz_from_xy(D50);
+        let mapped = mat_mul(&forward, &[1.0; 3]);
+        let forward: [[f32; 3]; 3] =
+          [0, 1, 2].map(|i| forward[i].map(|v| v * white[i] / mapped[i]));
+        let balance = diagonal(neutral.map(|n| neutral[1] / n));
+        Ok(mat_product(&forward, &balance))
+      }
+      // the inverse color matrix gives XYZ under the light, adapted to D50 afterwards
+      None => {
+        let camera_to_xyz =
+          mat_inverse(&xyz_to_camera).ok_or(anyhow!("Color matrix can not be inverted"))?;
+        let scale = neutral[1] / mat_mul(&camera_to_xyz, &neutral)[1];
+        let adapted = mat_product(&adaptation(white_balance.xy(), D50), &camera_to_xyz);
+        Ok(adapted.map(|row| row.map(|v| v * scale)))
+      }
+    }
+  }
+}
+
+/**
+ * Interpolates the matrices of the two illuminants, `weight` of the first one
+ */
+fn blend_matrices(matrices: &[Option<[[f32; 3]; 3]>; 2], weight: f32) -> Option<[[f32; 3]; 3]> {
+  match matrices {
+    [Some(first), Some(second)] => Some(
+      [0, 1, 2].map(|i| [0, 1, 2].map(|j| first[i][j] * weight + second[i][j] * (1.0 - weight))),
+    ),
+    [first, second] => first.or(*second),
+  }
+}
+
+fn diagonal(values: [f32; 3]) -> [[f32; 3]; 3] {
+  [
+    [values[0], 0.0, 0.0],
+    [0.0, values[1], 0.0],
+    [0.0, 0.0, values[2]],
+  ]
+}
+
+/**
+ * Color temperature of an EXIF light source, the values Adobe's DNG SDK uses
+ */
+fn illuminant_temperature(illuminant: u16) -> Option<f32> {
+  match illuminant {
+    // standard light A and tungsten
+    3 | 17 => Some(2850.0),
+    24 => Some(3200.0),
+    23 => Some(5000.0),
+    // daylight, flash, fine weather, standard light B and D55
+    1 | 4 | 9 | 18 | 20 => Some(5500.0),
+    // cloudy, standard light C and D65
+    10 | 19 | 21 => Some(6500.0),
+    // shade and D75
+    11 | 22 => Some(7500.0),
+    12 => Some(6400.0),
+    13 => Some(5050.0),
+    2 | 14 => Some(4150.0),
+    15 => Some(3525.0),
+    16 => Some(2925.0),
+    _ => None,
+  }
+}
+
+impl HueSatMap {
+  /**
+   * Interpolates two maps entry by entry, `weight` of this one.
+   * Maps of different sizes can't be, the one with more weight is used.
+   */
+  fn blend(&self, other: &HueSatMap, weight: f32) -> HueSatMap {
+    let same_size =
+      (self.hues, self.saturations, self.values) == (other.hues, other.saturations, other.values);
+    if !same_size {
+      return if weight >= 0.5 { self } else { other }.clone();
+    }
+
+    HueSatMap {
+      data: self
+        .data
+        .iter()
+        .zip(&other.data)
+        .map(|(a, b)| [0, 1, 2].map(|c| a[c] * weight + b[c] * (1.0 - weight)))
+        .collect(),
+      ..self.clone()
+    }
+  }
+
+  fn at(&self, hue: usize, saturation: usize, value: usize) -> [f32; 3] {
+    let hue = hue % self.hues;
+    let saturation = saturation.min(self.saturations - 1);
+    let value = value.min(self.values - 1);
+    self.data[(value * self.hues + hue) * self.saturations + saturation]
+  }
+
+  fn apply(&self, rgb: [f32; 3]) -> [f32; 3] {
+    let (mut hue, mut saturation, mut value) = hsv_from_rgb(rgb);
+    if value <= 0.0 {
+      return rgb;
+    }
+
+    let lookup = if self.srgb_encoded {
+      srgb::gamma::normalised_from_linear([value.min(1.0); 3])[0]
+    } else {
+      value.min(1.0)
+    };
+
+    // hue wraps around, saturation and value go from the first to the last division
+    let h = hue * self.hues as f32 / 6.0;
+    let s = saturation.clamp(0.0, 1.0) * (self.saturations - 1) as f32;
+    let v = lookup * (self.values - 1) as f32;
+    let (h0, s0, v0) = (h.floor(), s.floor(), v.floor());
+    let (th, ts, tv) = (h - h0, s - s0, v - v0);
+    let (h0, s0, v0) = (h0 as usize, s0 as usize, v0 as usize);
+
+    let mut entry = [0.0; 3];
+    for (dh, wh) in [(0, 1.0 - th), (1, th)] {
+      for (ds, ws) in [(0, 1.0 - ts), (1, ts)] {
+        for (dv, wv) in [(0, 1.0 - tv), (1, tv)] {
+          let weight = wh * ws * wv;
+          if weight == 0.0 {
+            continue;
+          }
+          let corner = self.at(h0 + dh, s0 + ds, v0 + dv);
+          for (entry, corner) in entry.iter_mut().zip(corner) {
+            *entry += corner * weight;
+          }
+        }
+      }
+    }
+
+    hue = (hue + entry[0] * 6.0 / 360.0).rem_euclid(6.0);
+    saturation = (saturation * entry[1]).min(1.0);
+    value *= entry[2];
+
+    rgb_from_hsv(hue, saturation, value)
+  }
+}
+
+/**
+ * Applies the curve to the largest and smallest channel and places the middle one between them
+ * at the same ratio as before, which keeps the hue like Adobe's RGB tone curve
+ */
+fn rgb_tone(rgb: [f32; 3], curve: &Curve) -> [f32; 3] {
+  // the curve is defined on 0..1, brighter values continue with a slope of one
+  let tone = |x: f32| {
+    if x > 1.0 {
+      curve.apply(1.0) + x - 1.0
+    } else {
+      curve.apply(x.max(0.0))
+    }
+  };
+
+  let mut order = [0, 1, 2];
+  order.sort_by(|&a, &b| rgb[b].total_cmp(&rgb[a]));
+  let [max, mid, min] = order;
+
+  let mut result = [0.0; 3];
+  result[max] = tone(rgb[max]);
+  result[min] = tone(rgb[min]);
+  result[mid] = if rgb[max] > rgb[min] {
+    result[min] + (result[max] - result[min]) * (rgb[mid] - rgb[min]) / (rgb[max] - rgb[min])
+  } else {
+    result[max]
+  };
+  result
+}
+
+/**
+ * Hue in 0..6, saturation and value
+ */
+fn hsv_from_rgb(rgb: [f32; 3]) -> (f32, f32, f32) {
+  let [r, g, b] = rgb.map(|c| c.max(0.0));
+  let max = r.max(g).max(b);
+  let min = r.min(g).min(b);
+  let delta = max - min;
+  if max <= 0.0 || delta <= 0.0 {
+    return (0.0, 0.0, max);
+  }
+
+  let hue = if max == r {
+    ((g - b) / delta).rem_euclid(6.0)
+  } else if max == g {
+    (b - r) / delta + 2.0
+  } else {
+    (r - g) / delta + 4.0
+  };
+  (hue, delta / max, max)
+}
+
+fn rgb_from_hsv(hue: f32, saturation: f32, value: f32) -> [f32; 3] {
+  let sector = hue.floor();
+  let f = hue - sector;
+  let p = value * (1.0 - saturation);
+  let q = value * (1.0 - saturation * f);
+  let t = value * (1.0 - saturation * (1.0 - f));
+
+  match sector as i32 % 6 {
+    0 => [value, t, p],
+    1 => [q, value, p],
+    2 => [p, value, t],
+    3 => [p, q, value],
+    4 => [t, p, value],
+    _ => [value, p, q],
+  }
+}
+
+/**
+ * The first IFD of a TIFF structured file, DCP files use it with the magic number 0x4352
+ */
+struct Tiff<'a> {
+  data: &'a [u8],
+  little_endian: bool,
+  entries: Vec<(u16, u16, u32, usize)>,
+}
+
+impl<'a> Tiff<'a> {
+  fn parse(data: &'a [u8]) -> anyhow::Result<Tiff<'a>> {
+    let little_endian = match data.get(0..2) {
+      Some(b"II") => true,
+      Some(b"MM") => false,
+      _ => return Err(anyhow!("Not a TIFF structured file")),
+    };
+
+    let mut tiff = Tiff {
+      data,
+      little_endian,
+      entries: Vec::new(),
+    };
+    if !matches!(tiff.u16(2), Some(0x4352) | Some(42)) {
+      return Err(anyhow!("Not a camera profile"));
+    }
+
+    let ifd = tiff.u32(4).ok_or(anyhow!("Missing IFD"))? as usize;
+    let count = tiff.u16(ifd).ok_or(anyhow!("Missing IFD"))? as usize;
+    for i in 0..count {
+      let entry = ifd + 2 + i * 12;
+      let (Some(tag), Some(kind), Some(count)) =
+        (tiff.u16(entry), tiff.u16(entry + 2), tiff.u32(entry + 4))
+      else {
+        return Err(anyhow!("Truncated IFD"));
+      };
+
+      // values of up to four bytes are stored in the entry itself
+      let size = type_size(kind) * count as usize;
+      let offset = if size <= 4 {
+        entry + 8
+      } else {
+        tiff.u32(entry + 8).unwrap_or(0) as usize
+      };
+      if offset + size > data.len() {
+        return Err(anyhow!("Tag {} points outside of the file", tag));
+      }
+      tiff.entries.push((tag, kind, count, offset));
+    }
+
+    Ok(tiff)
+  }
+
+  fn bytes<const N: usize>(&self, offset: usize) -> Option<[u8; N]> {
+    let mut bytes: [u8; N] = self.data.get(offset..offset + N)?.try_into().ok()?;
+    if !self.little_endian {
+      bytes.reverse();
+    }
+    Some(bytes)
+  }
+
+  fn u16(&self, offset: usize) -> Option<u16> {
+    self.bytes(offset).map(u16::from_le_bytes)
+  }
+
+  fn u32(&self, offset: usize) -> Option<u32> {
+    self.bytes(offset).map(u32::from_le_bytes)
+  }
+
+  fn entry(&self, tag: u16) -> Option<(u16, u32, usize)> {
+    self
+      .entries
+      .iter()
+      .find(|entry| entry.0 == tag)
+      .map(|&(_, kind, count, offset)| (kind, count, offset))
+  }
+
+  fn values(&self, tag: u16) -> Option<Vec<f32>> {
+    let (kind, count, offset) = self.entry(tag)?;
+    let size = type_size(kind);
+
+    (0..count as usize)
+      .map(|i| {
+        let at = offset + i * size;
+        match kind {
+          3 => self.u16(at).map(|v| v as f32),
+          4 => self.u32(at).map(|v| v as f32),
+          5 => Some(self.u32(at)? as f32 / self.u32(at + 4)? as f32),
+          8 => self.u16(at).map(|v| v as i16 as f32),
+          9 => self.u32(at).map(|v| v as i32 as f32),
+          10 => Some(self.u32(at)? as i32 as f32 / self.u32(at + 4)? as i32 as f32),
+          11 => self.bytes(at).map(f32::from_le_bytes),
+          12 => self.bytes(at).map(|b| f64::from_le_bytes(b) as f32),
+          _ => None,
+        }
+      })
+      .collect()
+  }
+}
+
+fn type_size(kind: u16) -> usize {
+  match kind {
+    3 | 8 => 2,
+    4 | 9 | 11 => 4,
+    5 | 10 | 12 => 8,
+    _ => 1,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const SHORT: u16 = 3;
+  const LONG: u16 = 4;
+  const SRATIONAL: u16 = 10;
+  const FLOAT: u16 = 11;
+
+  /**
+   * A little endian DCP with the given tags
+   */
+  fn dcp(tags: &[(u16, u16, Vec<f32>)]) -> Vec<u8> {
+    let mut data = b"II".to_vec();
+    data.extend(0x4352u16.to_le_bytes());
+    data.extend(8u32.to_le_bytes());
+    data.extend((tags.len() as u16).to_le_bytes());
+
+    let mut values_at = 8 + 2 + tags.len() * 12 + 4;
+    let mut values = Vec::new();
+    for (tag, kind, tag_values) in tags {
+      let mut bytes = Vec::new();
+      for &value in tag_values {
+        match *kind {
+          SHORT => bytes.extend((value as u16).to_le_bytes()),
+          LONG => bytes.extend((value as u32).to_le_bytes()),
+          SRATIONAL => {
+            bytes.extend(((value * 10000.0).round() as i32).to_le_bytes());
+            bytes.extend(10000i32.to_le_bytes());
+          }
+          _ => bytes.extend(value.to_le_bytes()),
+        }
+      }
+
+      data.extend(tag.to_le_bytes());
+      data.extend(kind.to_le_bytes());
+      data.extend((tag_values.len() as u32).to_le_bytes());
+      if bytes.len() <= 4 {
+        bytes.resize(4, 0);
+        data.extend(&bytes);
+      } else {
+        data.extend((values_at as u32).to_le_bytes());
+        values_at += bytes.len();
+        values.extend(bytes);
+      }
+    }
+    data.extend(0u32.to_le_bytes());
+    data.extend(values);
+    data
+  }
+
+  fn flatten(matrix: [[f32; 3]; 3]) -> Vec<f32> {
+    matrix.iter().flatten().copied().collect()
+  }
+
+  /**
+   * A camera that sees sRGB, a bit warmer under tungsten
+   */
+  fn color_matrices() -> [[[f32; 3]; 3]; 2] {
+    let daylight = crate::white_balance::XYZ_TO_SRGB;
+    let tungsten = [
+      [daylight[0][0] * 1.1, daylight[0][1], daylight[0][2]],
+      daylight[1],
+      daylight[2],
+    ];
+    [tungsten, daylight]
+  }
+
+  fn profile(forward: bool) -> CameraProfile {
+    let [tungsten, daylight] = color_matrices();
+    let mut tags = vec![
+      (COLOR_MATRIX_1, SRATIONAL, flatten(tungsten)),
+      (COLOR_MATRIX_2, SRATIONAL, flatten(daylight)),
+      (CALIBRATION_ILLUMINANT_1, SHORT, vec![17.0]),
+      (CALIBRATION_ILLUMINANT_2, SHORT, vec![21.0]),
+      (BASELINE_EXPOSURE_OFFSET, SRATIONAL, vec![0.5]),
+      (HUE_SAT_MAP_DIMS, LONG, vec![1.0, 2.0, 1.0]),
+      (
+        HUE_SAT_MAP_DATA_1,
+        FLOAT,
+        vec![0.0, 1.0, 1.0, 10.0, 1.0, 1.0],
+      ),
+      (
+        HUE_SAT_MAP_DATA_2,
+        FLOAT,
+        vec![0.0, 1.0, 1.0, 0.0, 1.0, 1.0],
+      ),
+      (TONE_CURVE, FLOAT, vec![0.0, 0.0, 0.5, 0.6, 1.0, 1.0]),
+    ];
+    if forward {
+      let srgb_to_xyz = crate::white_balance::SRGB_TO_XYZ;
+      tags.push((FORWARD_MATRIX_1, SRATIONAL, flatten(srgb_to_xyz)));
+      tags.push((FORWARD_MATRIX_2, SRATIONAL, flatten(srgb_to_xyz)));
+    }
+    tags.sort_by_key(|tag| tag.0);
+    CameraProfile::parse(&dcp(&tags)).unwrap()
+  }
+
+  #[test]
+  fn parses_matrices_and_maps() {
+    let profile = profile(true);
+    assert_eq!(profile.temperatures, [Some(2850.0), Some(6500.0)]);
+    let [tungsten, daylight] = color_matrices();
+    for (parsed, expected) in [
+      (profile.color_matrices[0].unwrap(), tungsten),
+      (profile.color_matrices[1].unwrap(), daylight),
+    ] {
+      for (a, b) in flatten(parsed).iter().zip(flatten(expected)) {
+        assert!((a - b).abs() < 1e-4);
+      }
+    }
+    assert!(profile.forward_matrices.iter().all(|m| m.is_some()));
+    assert_eq!(profile.baseline_exposure, 0.5);
+    assert_eq!(
+      profile.hue_sat_maps[0].as_ref().unwrap().data[1],
+      [10.0, 1.0, 1.0]
+    );
+    assert!(profile.look_table.is_none());
+    assert!(profile.tone_curve.is_some());
+  }
+
+  #[test]
+  fn rejects_other_files() {
+    assert!(CameraProfile::parse(b"not a profile").is_err());
+    // a color matrix is required
+    let tags = [(TONE_CURVE, FLOAT, vec![0.0, 0.0, 1.0, 1.0])];
+    assert!(CameraProfile::parse(&dcp(&tags)).is_err());
+  }
+
+  #[test]
+  fn interpolates_by_temperature() {
+    let profile = profile(true);
+    assert_eq!(profile.weight(2850.0), 1.0);
+    assert_eq!(profile.weight(2000.0), 1.0);
+    assert_eq!(profile.weight(6500.0), 0.0);
+    // halfway in inverse temperature
+    let middle = 2.0 / (1.0 / 2850.0 + 1.0 / 6500.0);
+    assert!((profile.weight(middle) - 0.5).abs() < 1e-4);
+
+    let map = profile.hue_sat_maps[0]
+      .as_ref()
+      .unwrap()
+      .blend(profile.hue_sat_maps[1].as_ref().unwrap(), 0.25);
+    assert_eq!(map.data[1], [2.5, 1.0, 1.0]);
+  }
+
+  #[test]
+  fn white_stays_neutral() {
+    for forward in [true, false] {
+      let profile = profile(forward);
+      for temperature in [3000.0, 4500.0, 6500.0] {
+        let white_balance = WhiteBalance {
+          temperature,
+          tint: 0.0,
+        };
+        let weight = profile.weight(temperature);
+        let xyz_to_camera = blend_matrices(&profile.color_matrices, weight).unwrap();
+        let neutral = mat_mul(&xyz_to_camera, &xyz_from_xy(white_balance.xy()));
+
+        // the image is the raw camera RGB of a grey under that light
+        let mut image = ImageBuffer::from_pixel(1, 1, Rgb(neutral.map(|c| c * 0.2)));
+        let identity = diagonal([1.0; 3]);
+        profile
+          .apply(&mut image, &white_balance, Some(&identity))
+          .unwrap();
+
+        let expected = 0.2 * neutral[1] * 2.0_f32.sqrt();
+        for c in image.get_pixel(0, 0).0 {
+          assert!(
+            (c - expected).abs() < 1e-3,
+            "{forward} {temperature}: {c} {expected}"
+          );
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn tone_curve_continues_past_white() {
+    let curve = Curve::new(&[(0.0, 0.0), (0.5, 0.6), (1.0, 1.0)]).unwrap();
+    assert_eq!(rgb_tone([2.0, 2.0, 2.0], &curve), [2.0, 2.0, 2.0]);
+    // the middle channel keeps its place between the other two
+    let toned = rgb_tone([0.5, 0.25, 0.0], &curve);
+    assert!((toned[0] - 0.6).abs() < 1e-4);
+    assert!((toned[1] - 0.3).abs() < 1e-4);
+  }
+}
@@ -0,0 +1,495 @@
+use crate::tiles;
+use image::{ImageBuffer, Rgb};
+use rawler::CFA;
+
+/**
+ * How the color filter mosaic of the sensor is interpolated to full color
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Demosaic {
+  /**
+   * One pixel per block of the pattern, half the resolution for bayer and a third for X-Trans.
+   * By far the fastest, for thumbnails and grid previews.
+   */
+  HalfSize,
+  /**
+   * Ratio corrected demosaicing (RCD) on bayer sensors and edge directed color difference
+   * interpolation on X-Trans and other patterns, for the editor and exports
+   */
+  #[default]
+  HighQuality,
+}
+
+const EPS: f32 = 1e-5;
+
+/**
+ * The repeating color filter pattern, colors 0 to 2 for red, green and blue
+ */
+pub struct Pattern {
+  width: usize,
+  height: usize,
+  colors: Vec<usize>,
+}
+
+impl Pattern {
+  /**
+   * None for patterns that are not made of red, green and blue, like CYGM or monochrome sensors
+   */
+  pub fn new(cfa: &CFA) -> Option<Pattern> {
+    let (width, height) = (cfa.width, cfa.height);
+    if width == 0 || height == 0 {
+      return None;
+    }
+    let colors: Vec<usize> = (0..height)
+      .flat_map(|row| (0..width).map(move |col| cfa.color_at(row, col)))
+      .collect();
+    if colors.iter().any(|c| *c > 2) || !(0..3).all(|c| colors.contains(&c)) {
+      return None;
+    }
+
+    let pattern = Pattern {
+      width,
+      height,
+      colors,
+    };
+    // half size needs every color in each block
+    let block = pattern.block();
+    let complete = (0..height / block).all(|by| {
+      (0..width / block).all(|bx| {
+        (0..3).all(|c| {
+          (0..block * block)
+            .any(|i| pattern.color(by * block + i / block, bx * block + i % block) == c)
+        })
+      })
+    });
+    complete.then_some(pattern)
+  }
+
+  fn color(&self, row: usize, col: usize) -> usize {
+    self.colors[(row % self.height) * self.width + col % self.width]
+  }
+
+  fn is_bayer(&self) -> bool {
+    self.width == 2 && self.height == 2
+  }
+
+  /**
+   * Side of the blocks half size merges, 2 for bayer and 3 for X-Trans
+   */
+  pub fn block(&self) -> usize {
+    if self.width.is_multiple_of(2) && self.height.is_multiple_of(2) && self.width <= 4 {
+      2
+    } else {
+      3
+    }
+  }
+}
+
+/**
+ * Interpolates a mosaic of `width` x `height` sensor values to RGB,
+ * half size returns an image a block smaller on each side
+ */
+pub fn demosaic(
+  mosaic: &[f32],
+  width: usize,
+  height: usize,
+  pattern: &Pattern,
+  method: Demosaic,
+) -> ImageBuffer<Rgb<f32>, Vec<f32>> {
+  match method {
+    Demosaic::HalfSize => half_size(mosaic, width, height, pattern),
+    Demosaic::HighQuality if pattern.is_bayer() => {
+      to_image(&rcd(mosaic, width, height, pattern), width, height)
+    }
+    Demosaic::HighQuality => to_image(
+      &color_difference(mosaic, width, height, pattern),
+      width,
+      height,
+    ),
+  }
+}
+
+fn to_image(
+  planes: &[Vec<f32>; 3],
+  width: usize,
+  height: usize,
+) -> ImageBuffer<Rgb<f32>, Vec<f32>> {
+  let mut image = ImageBuffer::new(width as u32, height as u32);
+  tiles::map_pixels_at(&mut image, |x, y, _| {
+    let i = y as usize * width + x as usize;
+    [planes[0][i], planes[1][i], planes[2][i]]
+  });
+  image
+}
+
+/**
+ * Runs `f` for the pixels of a plane at least `border` pixels from its edges across all cores,
+ * with their row, column and index. The pixels `f` returns a value for are set to it.
+ */
+fn for_each_inside(
+  plane: &mut [f32],
+  width: usize,
+  height: usize,
+  border: usize,
+  f: impl Fn(usize, usize, usize) -> Option<f32> + Sync,
+) {
+  tiles::for_each_plane_tile(plane, width, |tile, top| {
+    for (row, values) in tile.chunks_mut(width).enumerate() {
+      let y = top + row;
+      if y < border || y + border >= height {
+        continue;
+      }
+      for (x, value) in values
+        .iter_mut()
+        .enumerate()
+        .take(width - border)
+        .skip(border)
+      {
+        if let Some(new) = f(y, x, y * width + x) {
+          *value = new;
+        }
+      }
+    }
+  });
+}
+
+fn half_size(
+  mosaic: &[f32],
+  width: usize,
+  height: usize,
+  pattern: &Pattern,
+) -> ImageBuffer<Rgb<f32>, Vec<f32>> {
+  let block = pattern.block();
+  let (out_width, out_height) = ((width / block).max(1), (height / block).max(1));
+
+  let mut image = ImageBuffer::new(out_width as u32, out_height as u32);
+  tiles::map_pixels_at(&mut image, |bx, by, _| {
+    let mut sum = [0.0; 3];
+    let mut count = [0.0; 3];
+    for y in by as usize * block..((by as usize + 1) * block).min(height) {
+      for x in bx as usize * block..((bx as usize + 1) * block).min(width) {
+        let c = pattern.color(y, x);
+        sum[c] += mosaic[y * width + x];
+        count[c] += 1.0;
+      }
+    }
+    [0, 1, 2].map(|c| sum[c] / f32::max(count[c], 1.0))
+  });
+  image
+}
+
+/**
+ * Average of the pixels of each color in the 3x3 neighbourhood, 5x5 where it has none
+ */
+fn bilinear(mosaic: &[f32], width: usize, height: usize, pattern: &Pattern) -> [Vec<f32>; 3] {
+  [0, 1, 2].map(|c| {
+    let mut plane = vec![0.0; width * height];
+    for_each_inside(&mut plane, width, height, 0, |y, x, i| {
+      if pattern.color(y, x) == c {
+        return Some(mosaic[i]);
+      }
+      for radius in [1, 2] {
+        let mut sum = 0.0;
+        let mut count = 0.0;
+        for ny in y.saturating_sub(radius)..(y + radius + 1).min(height) {
+          for nx in x.saturating_sub(radius)..(x + radius + 1).min(width) {
+            if pattern.color(ny, nx) == c {
+              sum += mosaic[ny * width + nx];
+              count += 1.0;
+            }
+          }
+        }
+        if count > 0.0 {
+          return Some(sum / count);
+        }
+      }
+      None
+    });
+    plane
+  })
+}
+
+/**
+ * Ratio corrected demosaicing by Luis Sanz Rodríguez: green is interpolated along the smoother of
+ * the horizontal and vertical direction with ratios to a low pass of the mosaic, red and blue
+ * follow as color differences to green. The border falls back to bilinear.
+ */
+fn rcd(mosaic: &[f32], width: usize, height: usize, pattern: &Pattern) -> [Vec<f32>; 3] {
+  let mut planes = bilinear(mosaic, width, height, pattern);
+  const BORDER: usize = 4;
+  if width <= BORDER * 2 || height <= BORDER * 2 {
+    return planes;
+  }
+
+  let w = width;
+  let cfa = mosaic;
+
+  // how much more the mosaic varies vertically than horizontally, 0 for vertical structures
+  let mut vh_dir = vec![0.5; width * height];
+  let mut lpf = vec![0.0; width * height];
+  for_each_inside(&mut lpf, width, height, 1, |_, _, i| {
+    Some(
+      cfa[i]
+        + 0.5 * (cfa[i - w] + cfa[i + w] + cfa[i - 1] + cfa[i + 1])
+        + 0.25 * (cfa[i - w - 1] + cfa[i - w + 1] + cfa[i + w - 1] + cfa[i + w + 1]),
+    )
+  });
+  let high_pass = |i: usize, step: usize| {
+    let v = cfa[i - 2 * step] - 2.0 * cfa[i] + cfa[i + 2 * step];
+    let d = cfa[i - step] - cfa[i + step];
+    v * v + d * d
+  };
+  for_each_inside(&mut vh_dir, width, height, 3, |_, _, i| {
+    let v = EPS + high_pass(i - w, w) + high_pass(i, w) + high_pass(i + w, w);
+    let h = EPS + high_pass(i - 1, 1) + high_pass(i, 1) + high_pass(i + 1, 1);
+    Some(v / (v + h))
+  });
+  // the direction of the neighbours wins where they agree more than the pixel itself
+  let discrimination = |dir: &[f32], i: usize, step: [usize; 2]| {
+    let central = dir[i];
+    let neighbours =
+      0.25 * (dir[i - step[0]] + dir[i + step[0]] + dir[i - step[1]] + dir[i + step[1]]);
+    if (0.5 - central).abs() < (0.5 - neighbours).abs() {
+      neighbours
+    } else {
+      central
+    }
+  };
+
+  // green at red and blue
+  let mut green = planes[1].clone();
+  for_each_inside(&mut green, width, height, BORDER, |y, x, i| {
+    if pattern.color(y, x) == 1 {
+      return None;
+    }
+    let grad = |s: isize| {
+      let at = |k: isize| cfa[(i as isize + k * s) as usize];
+      EPS
+        + (at(-1) - at(1)).abs()
+        + (at(0) - at(-2)).abs()
+        + (at(-1) - at(-3)).abs()
+        + (at(-2) - at(-4)).abs()
+    };
+    let estimate = |s: isize| {
+      let at = |k: isize| (i as isize + k * s) as usize;
+      cfa[at(-1)] * 2.0 * lpf[i] / (EPS + lpf[i] + lpf[at(-2)])
+    };
+    let (n_grad, s_grad) = (grad(w as isize), grad(-(w as isize)));
+    let (w_grad, e_grad) = (grad(1), grad(-1));
+    let (n_est, s_est) = (estimate(w as isize), estimate(-(w as isize)));
+    let (w_est, e_est) = (estimate(1), estimate(-1));
+
+    let v_est = (s_grad * n_est + n_grad * s_est) / (n_grad + s_grad);
+    let h_est = (e_grad * w_est + w_grad * e_est) / (e_grad + w_grad);
+    let disc = discrimination(&vh_dir, i, [w, 1]);
+    Some((v_est + (h_est - v_est) * disc).max(0.0))
+  });
+  planes[1] = green;
+
+  // red at blue and blue at red, along the smoother diagonal
+  let mut pq_dir = vec![0.5; width * height];
+  for_each_inside(&mut pq_dir, width, height, 3, |_, _, i| {
+    let p = EPS + high_pass(i, w + 1);
+    let q = EPS + high_pass(i, w - 1);
+    Some(p / (p + q))
+  });
+  for c in [0, 2] {
+    let mut plane = planes[c].clone();
+    for_each_inside(&mut plane, width, height, BORDER, |y, x, i| {
+      let own = pattern.color(y, x);
+      if own == 1 || own == c {
+        return None;
+      }
+      let g = &planes[1];
+      let grad = |s: isize| {
+        let at = |k: isize| (i as isize + k * s) as usize;
+        EPS
+          + (cfa[at(-1)] - cfa[at(1)]).abs()
+          + (cfa[at(-1)] - cfa[at(-3)]).abs()
+          + (g[i] - g[at(-2)]).abs()
+      };
+      let estimate = |s: isize| {
+        let at = (i as isize - s) as usize;
+        cfa[at] - g[at]
+      };
+      let (nw, se, ne, sw) = (
+        (w + 1) as isize,
+        -((w + 1) as isize),
+        (w - 1) as isize,
+        -((w - 1) as isize),
+      );
+      let p_est = (grad(nw) * estimate(se) + grad(se) * estimate(nw)) / (grad(nw) + grad(se));
+      let q_est = (grad(ne) * estimate(sw) + grad(sw) * estimate(ne)) / (grad(ne) + grad(sw));
+      let disc = discrimination(&pq_dir, i, [w + 1, w - 1]);
+      Some((g[i] + p_est + (q_est - p_est) * disc).max(0.0))
+    });
+    planes[c] = plane;
+  }
+
+  // red and blue at green, now that the neighbours have both
+  for c in [0, 2] {
+    let mut plane = planes[c].clone();
+    for_each_inside(&mut plane, width, height, BORDER, |y, x, i| {
+      if pattern.color(y, x) != 1 {
+        return None;
+      }
+      let g = &planes[1];
+      let p = &planes[c];
+      let grad = |s: isize| {
+        let at = |k: isize| (i as isize + k * s) as usize;
+        EPS
+          + (g[i] - g[at(-2)]).abs()
+          + (p[at(-1)] - p[at(1)]).abs()
+          + (p[at(-1)] - p[at(-3)]).abs()
+      };
+      let estimate = |s: isize| {
+        let at = (i as isize - s) as usize;
+        p[at] - g[at]
+      };
+      let (n, s, wst, e) = (w as isize, -(w as isize), 1, -1);
+      let v_est = (grad(s) * estimate(n) + grad(n) * estimate(s)) / (grad(n) + grad(s));
+      let h_est = (grad(e) * estimate(wst) + grad(wst) * estimate(e)) / (grad(wst) + grad(e));
+      let disc = discrimination(&vh_dir, i, [w, 1]);
+      Some((g[i] + v_est + (h_est - v_est) * disc).max(0.0))
+    });
+    planes[c] = plane;
+  }
+
+  planes
+}
+
+/**
+ * For patterns without a fixed bayer layout: green from the direction with the smallest gradient
+ * among horizontal, vertical and both diagonals, red and blue as color differences to green,
+ * averaged over the 5x5 neighbourhood and weighted against crossing edges.
+ */
+fn color_difference(
+  mosaic: &[f32],
+  width: usize,
+  height: usize,
+  pattern: &Pattern,
+) -> [Vec<f32>; 3] {
+  let mut planes = bilinear(mosaic, width, height, pattern);
+  const BORDER: usize = 2;
+  if width <= BORDER * 2 || height <= BORDER * 2 {
+    return planes;
+  }
+
+  let directions: [(isize, isize); 4] = [(1, 0), (0, 1), (1, 1), (1, -1)];
+  // only reads the mosaic, so green is filled in place
+  for_each_inside(&mut planes[1], width, height, BORDER, |y, x, _| {
+    if pattern.color(y, x) == 1 {
+      return None;
+    }
+    // nearest green on either side of each line through the pixel
+    let nearest = |dx: isize, dy: isize| {
+      (1..=BORDER as isize).find_map(|k| {
+        let (nx, ny) = (
+          (x as isize + dx * k) as usize,
+          (y as isize + dy * k) as usize,
+        );
+        (pattern.color(ny, nx) == 1).then(|| (mosaic[ny * width + nx], k as f32))
+      })
+    };
+
+    let mut sum = 0.0;
+    let mut weights = 0.0;
+    for (dx, dy) in directions {
+      let (Some((a, ka)), Some((b, kb))) = (nearest(dx, dy), nearest(-dx, -dy)) else {
+        continue;
+      };
+      // distance weighted towards the nearer side
+      let estimate = (a * kb + b * ka) / (ka + kb);
+      let weight = 1.0 / (EPS + (a - b).abs()).powi(2);
+      sum += estimate * weight;
+      weights += weight;
+    }
+    (weights > 0.0).then(|| sum / weights)
+  });
+
+  for c in [0, 2] {
+    let mut plane = planes[c].clone();
+    let g = &planes[1];
+    for_each_inside(&mut plane, width, height, BORDER, |y, x, i| {
+      if pattern.color(y, x) == c {
+        return None;
+      }
+      let mut sum = 0.0;
+      let mut weights = 0.0;
+      for ny in y - BORDER..=y + BORDER {
+        for nx in x - BORDER..=x + BORDER {
+          if pattern.color(ny, nx) != c {
+            continue;
+          }
+          let j = ny * width + nx;
+          let distance = ((ny as f32 - y as f32).powi(2) + (nx as f32 - x as f32).powi(2)).sqrt();
+          let weight = 1.0 / (distance * (EPS + (g[j] - g[i]).abs()));
+          sum += (mosaic[j] - g[j]) * weight;
+          weights += weight;
+        }
+      }
+      (weights > 0.0).then(|| (g[i] + sum / weights).max(0.0))
+    });
+    planes[c] = plane;
+  }
+
+  planes
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn bayer() -> Pattern {
+    Pattern {
+      width: 2,
+      height: 2,
+      colors: vec![0, 1, 1, 2],
+    }
+  }
+
+  fn x_trans() -> Pattern {
+    Pattern {
+      width: 6,
+      height: 6,
+      colors: vec![
+        1, 1, 0, 1, 1, 2, 1, 1, 2, 1, 1, 0, 2, 0, 1, 0, 2, 1, 1, 1, 2, 1, 1, 0, 1, 1, 0, 1, 1, 2,
+        0, 2, 1, 2, 0, 1,
+      ],
+    }
+  }
+
+  /**
+   * Mosaic of a flat color, each site sees its own channel
+   */
+  fn flat(pattern: &Pattern, width: usize, height: usize, rgb: [f32; 3]) -> Vec<f32> {
+    (0..width * height)
+      .map(|i| rgb[pattern.color(i / width, i % width)])
+      .collect()
+  }
+
+  #[test]
+  fn flat_colors_stay_flat() {
+    let rgb = [0.2, 0.5, 0.3];
+    for pattern in [bayer(), x_trans()] {
+      let mosaic = flat(&pattern, 48, 36, rgb);
+      for method in [Demosaic::HalfSize, Demosaic::HighQuality] {
+        let image = demosaic(&mosaic, 48, 36, &pattern, method);
+        for pixel in image.pixels() {
+          for (value, expected) in pixel.0.iter().zip(rgb) {
+            assert!((value - expected).abs() < 1e-4, "{method:?} {:?}", pixel.0);
+          }
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn half_size_is_a_block_smaller() {
+    let mosaic = flat(&x_trans(), 48, 36, [0.5; 3]);
+    let image = demosaic(&mosaic, 48, 36, &x_trans(), Demosaic::HalfSize);
+    assert_eq!(image.dimensions(), (16, 12));
+    let image = demosaic(&mosaic, 48, 36, &bayer(), Demosaic::HalfSize);
+    assert_eq!(image.dimensions(), (24, 18));
+  }
+}
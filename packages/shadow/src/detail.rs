@@ -0,0 +1,130 @@
+use crate::filter::{bilateral, gaussian_blur};
+use crate::tiles;
+use crate::{luminance, smoothstep};
+use image::{ImageBuffer, Rgb};
+use rayon::prelude::*;
+
+/**
+ * Deconvolution iterations past this barely change the result, more are capped to it
+ */
+pub const MAX_ITERATIONS: u32 = 10;
+
+/**
+ * Sharpens the luminance, either as an unsharp mask or, with iterations set,
+ * by Richardson-Lucy deconvolution of a gaussian blur of `radius`.
+ * Differences smaller than `threshold` stops are left alone, so flat areas and noise stay smooth.
+ */
+pub fn sharpen(
+  image: &mut ImageBuffer<Rgb<f32>, Vec<f32>>,
+  amount: f32,
+  radius: f32,
+  threshold: f32,
+  iterations: u32,
+) {
+  if amount == 0.0 || radius <= 0.0 {
+    return;
+  }
+
+  let width = image.width() as usize;
+  let height = image.height() as usize;
+
+  let lum = tiles::plane(image, |rgb| luminance(rgb).max(1e-6));
+
+  let sharp = if iterations > 0 {
+    deconvolve(&lum, width, height, radius, iterations.min(MAX_ITERATIONS))
+  } else {
+    let blurred = gaussian_blur(&lum, width, height, radius);
+    lum
+      .iter()
+      .zip(&blurred)
+      .map(|(l, b)| (l * (l / b.max(1e-6))).max(1e-6))
+      .collect()
+  };
+
+  tiles::map_pixels_at(image, |x, y, rgb| {
+    let i = y as usize * width + x as usize;
+    // compare in stops, so shadows get sharpened as much as highlights
+    let detail = (sharp[i] / lum[i]).log2();
+    let weight = if threshold > 0.0 {
+      smoothstep(0.0, threshold, detail.abs())
+    } else {
+      1.0
+    };
+    let factor = (detail * amount * weight).exp2();
+    rgb.map(|c| c * factor)
+  });
+}
+
+fn deconvolve(
+  observed: &[f32],
+  width: usize,
+  height: usize,
+  radius: f32,
+  iterations: u32,
+) -> Vec<f32> {
+  let mut estimate = observed.to_vec();
+
+  for _ in 0..iterations {
+    let blurred = gaussian_blur(&estimate, width, height, radius);
+    let ratio: Vec<f32> = observed
+      .par_iter()
+      .zip(&blurred)
+      .map(|(o, b)| o / b.max(1e-6))
+      .collect();
+    // the gaussian is symmetric, so it is its own adjoint
+    let correction = gaussian_blur(&ratio, width, height, radius);
+    estimate
+      .par_iter_mut()
+      .zip(&correction)
+      .for_each(|(e, c)| *e = (*e * c).max(1e-6));
+  }
+
+  estimate
+}
+
+/**
+ * Luminance noise is smoothed with an edge preserving filter on the log luminance,
+ * color noise by taking the chromaticity of the surrounding pixels while keeping each pixel's luminance.
+ * Both strengths are in 0..1, `scale` is the image's pixels per pixel of the full resolution.
+ */
+pub fn denoise(
+  image: &mut ImageBuffer<Rgb<f32>, Vec<f32>>,
+  luminance_amount: f32,
+  chroma: f32,
+  scale: f32,
+) {
+  let width = image.width() as usize;
+  let height = image.height() as usize;
+
+  if luminance_amount > 0.0 {
+    let log_lum = tiles::plane(image, |rgb| luminance(rgb).max(1e-6).log2());
+
+    let range = 0.05 + luminance_amount * 0.3;
+    let smooth = bilateral(&log_lum, width, height, 1.5 * scale, range);
+
+    tiles::map_pixels_at(image, |x, y, rgb| {
+      let i = y as usize * width + x as usize;
+      let target = log_lum[i] + (smooth[i] - log_lum[i]) * luminance_amount.min(1.0);
+      let factor = (target - log_lum[i]).exp2();
+      rgb.map(|c| c * factor)
+    });
+  }
+
+  if chroma > 0.0 {
+    let sigma = (1.0 + chroma * 4.0) * scale;
+    let lum = tiles::plane(image, luminance);
+    let blurred_lum = gaussian_blur(&lum, width, height, sigma);
+    let channels: Vec<Vec<f32>> = (0..3)
+      .map(|c| gaussian_blur(&tiles::plane(image, |rgb| rgb[c]), width, height, sigma))
+      .collect();
+
+    tiles::map_pixels_at(image, |x, y, rgb| {
+      let i = y as usize * width + x as usize;
+      if blurred_lum[i] <= 0.0 {
+        return rgb;
+      }
+      let scale = lum[i] / blurred_lum[i];
+      [0, 1, 2].map(|c| rgb[c] + (channels[c][i] * scale - rgb[c]) * chroma.min(1.0))
+    });
+  }
+}
@@ -0,0 +1,129 @@
+use crate::geometry::View;
+use crate::tiles;
+use crate::{gain, smoothstep, stops};
+use image::{ImageBuffer, Rgb};
+
+/**
+ * Darkens (negative amount) or brightens the edges of the frame by up to two stops.
+ * Positions are relative to the frame, so any resolution or region of the same image gets the same
+ * vignette.
+ * The midpoint is where the falloff starts, between the center (0) and the corners (1).
+ * Roundness goes from following the frame (-1) over an ellipse (0) to a circle (1).
+ */
+pub fn vignette(
+  image: &mut ImageBuffer<Rgb<f32>, Vec<f32>>,
+  view: &View,
+  amount: f32,
+  midpoint: f32,
+  roundness: f32,
+  feather: f32,
+  highlight_protection: f32,
+) {
+  if amount == 0.0 {
+    return;
+  }
+
+  let width = view.frame_width;
+  let height = view.frame_height;
+  let short = width.min(height);
+
+  // towards a circle the axes are scaled by the short side instead of their own length
+  let circle = roundness.max(0.0);
+  let scale_x = (width / 2.0) * (1.0 - circle) + (short / 2.0) * circle;
+  let scale_y = (height / 2.0) * (1.0 - circle) + (short / 2.0) * circle;
+  // towards the frame the shape becomes a superellipse
+  let exponent = 2.0 + (-roundness).max(0.0) * 6.0;
+
+  let distance =
+    |dx: f32, dy: f32| (dx.abs().powf(exponent) + dy.abs().powf(exponent)).powf(1.0 / exponent);
+  let corner = distance(width / 2.0 / scale_x, height / 2.0 / scale_y);
+
+  let start = midpoint.clamp(0.0, 1.0);
+  let end = start + feather.clamp(0.01, 1.0) * (1.0 - start).max(0.01);
+
+  tiles::map_pixels_at(image, |x, y, rgb| {
+    let (fx, fy) = view.frame_position(x, y);
+    let dx = (fx - width / 2.0) / scale_x;
+    let dy = (fy - height / 2.0) / scale_y;
+    let mut weight = smoothstep(start, end, distance(dx, dy) / corner);
+
+    if amount < 0.0 {
+      weight *= 1.0 - highlight_protection.clamp(0.0, 1.0) * smoothstep(0.0, 2.5, stops(rgb));
+    }
+
+    gain(rgb, amount * 2.0 * weight)
+  });
+}
+
+/**
+ * Film grain, in stops on the luminance.
+ * The grain is noise on a grid relative to the frame, its size 1 being a thousandth of the long side.
+ * Where a grain is smaller than a pixel it averages out like it would when scaling the image down,
+ * so previews show the same grain as the full resolution. Roughness adds a finer second layer.
+ */
+pub fn grain(
+  image: &mut ImageBuffer<Rgb<f32>, Vec<f32>>,
+  view: &View,
+  amount: f32,
+  size: f32,
+  roughness: f32,
+  seed: u64,
+) {
+  if amount == 0.0 {
+    return;
+  }
+
+  let long = view.frame_long_side();
+  let grain = size.max(0.1) / 1000.0;
+  let pixel_size = 1.0 / (long * view.scale);
+
+  let layers = [
+    (grain, 1.0 - roughness.clamp(0.0, 1.0) * 0.5),
+    (grain / 2.0, roughness.clamp(0.0, 1.0)),
+  ];
+
+  tiles::map_pixels_at(image, |x, y, rgb| {
+    let (fx, fy) = view.frame_position(x, y);
+    let (u, v) = (fx / long, fy / long);
+
+    let mut noise = 0.0;
+    for (layer, (cell, weight)) in layers.iter().enumerate() {
+      // averaging (pixel / cell)^2 grains lowers the deviation by cell / pixel
+      let visible = (cell / pixel_size).min(1.0);
+      noise += value_noise(u / cell, v / cell, seed.wrapping_add(layer as u64)) * weight * visible;
+    }
+
+    gain(rgb, noise * amount * 0.5)
+  });
+}
+
+fn value_noise(x: f32, y: f32, seed: u64) -> f32 {
+  let x0 = x.floor();
+  let y0 = y.floor();
+  let tx = smoothstep(0.0, 1.0, x - x0);
+  let ty = smoothstep(0.0, 1.0, y - y0);
+  let (ix, iy) = (x0 as i64, y0 as i64);
+
+  let top = lerp(hash(ix, iy, seed), hash(ix + 1, iy, seed), tx);
+  let bottom = lerp(hash(ix, iy + 1, seed), hash(ix + 1, iy + 1, seed), tx);
+  lerp(top, bottom, ty)
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+  a + (b - a) * t
+}
+
+/**
+ * Random value in -1..1 for a grid cell, the same for the same cell and seed
+ */
+fn hash(x: i64, y: i64, seed: u64) -> f32 {
+  let mut h = seed
+    ^ (x as u64).wrapping_mul(0x9E3779B97F4A7C15)
+    ^ (y as u64).wrapping_mul(0xC2B2AE3D27D4EB4F);
+  h ^= h >> 33;
+  h = h.wrapping_mul(0xFF51AFD7ED558CCD);
+  h ^= h >> 33;
+  h = h.wrapping_mul(0xC4CEB9FE1A85EC53);
+  h ^= h >> 33;
+  (h >> 40) as f32 / (1u64 << 23) as f32 - 1.0
+}
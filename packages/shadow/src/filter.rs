@@ -0,0 +1,129 @@
+use crate::tiles::for_each_plane_tile;
+
+/**
+ * Separable gaussian blur of a single channel plane, edges are extended
+ */
+pub fn gaussian_blur(plane: &[f32], width: usize, height: usize, sigma: f32) -> Vec<f32> {
+  let kernel = gaussian_kernel(sigma);
+  let radius = (kernel.len() / 2) as isize;
+
+  let mut horizontal = vec![0.0; plane.len()];
+  for_each_plane_tile(&mut horizontal, width, |tile, top| {
+    for (i, out) in tile.chunks_mut(width).enumerate() {
+      let y = top + i;
+      let row = &plane[y * width..(y + 1) * width];
+      for (x, value) in out.iter_mut().enumerate() {
+        let mut sum = 0.0;
+        for (k, weight) in kernel.iter().enumerate() {
+          let sx = (x as isize + k as isize - radius).clamp(0, width as isize - 1) as usize;
+          sum += row[sx] * weight;
+        }
+        *value = sum;
+      }
+    }
+  });
+
+  let mut blurred = vec![0.0; plane.len()];
+  for_each_plane_tile(&mut blurred, width, |tile, top| {
+    for (i, out) in tile.chunks_mut(width).enumerate() {
+      let y = top + i;
+      for (x, value) in out.iter_mut().enumerate() {
+        let mut sum = 0.0;
+        for (k, weight) in kernel.iter().enumerate() {
+          let sy = (y as isize + k as isize - radius).clamp(0, height as isize - 1) as usize;
+          sum += horizontal[sy * width + x] * weight;
+        }
+        *value = sum;
+      }
+    }
+  });
+
+  blurred
+}
+
+fn gaussian_kernel(sigma: f32) -> Vec<f32> {
+  let radius = (sigma * 3.0).ceil().max(1.0) as isize;
+  let mut kernel: Vec<f32> = (-radius..=radius)
+    .map(|i| (-(i * i) as f32 / (2.0 * sigma * sigma)).exp())
+    .collect();
+  let sum: f32 = kernel.iter().sum();
+  for weight in kernel.iter_mut() {
+    *weight /= sum;
+  }
+  kernel
+}
+
+/**
+ * Bilateral filter of a single channel plane, neighbours are weighted by distance and
+ * by how close their value is, so edges are kept while small variations are smoothed
+ */
+pub fn bilateral(plane: &[f32], width: usize, height: usize, sigma: f32, range: f32) -> Vec<f32> {
+  let radius = (sigma * 2.0).ceil() as isize;
+  let spatial = -1.0 / (2.0 * sigma * sigma);
+  let tonal = -1.0 / (2.0 * range * range);
+
+  let mut filtered = vec![0.0; plane.len()];
+  for_each_plane_tile(&mut filtered, width, |tile, top| {
+    for (i, out) in tile.chunks_mut(width).enumerate() {
+      let y = top + i;
+      for (x, result) in out.iter_mut().enumerate() {
+        let center = plane[y * width + x];
+        let mut sum = 0.0;
+        let mut total = 0.0;
+
+        for dy in -radius..=radius {
+          let sy = (y as isize + dy).clamp(0, height as isize - 1) as usize;
+          for dx in -radius..=radius {
+            let sx = (x as isize + dx).clamp(0, width as isize - 1) as usize;
+            let value = plane[sy * width + sx];
+            let difference = value - center;
+            let weight =
+              ((dx * dx + dy * dy) as f32 * spatial + difference * difference * tonal).exp();
+            sum += value * weight;
+            total += weight;
+          }
+        }
+
+        *result = sum / total;
+      }
+    }
+  });
+
+  filtered
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn blur_keeps_flat_planes_and_energy() {
+    let (width, height) = (70, 90);
+    let flat = vec![0.5; width * height];
+    assert!(gaussian_blur(&flat, width, height, 2.0)
+      .iter()
+      .all(|v| (v - 0.5).abs() < 1e-5));
+
+    // an impulse away from the edges spreads symmetrically and keeps its sum
+    let mut impulse = vec![0.0; width * height];
+    impulse[45 * width + 35] = 1.0;
+    let blurred = gaussian_blur(&impulse, width, height, 2.0);
+    assert!((blurred.iter().sum::<f32>() - 1.0).abs() < 1e-4);
+    let at = |x: usize, y: usize| blurred[y * width + x];
+    assert!((at(33, 45) - at(37, 45)).abs() < 1e-7);
+    assert!((at(35, 43) - at(35, 47)).abs() < 1e-7);
+    assert!((at(33, 45) - at(35, 43)).abs() < 1e-7);
+  }
+
+  #[test]
+  fn bilateral_keeps_edges() {
+    let (width, height) = (40, 40);
+    let step: Vec<f32> = (0..width * height)
+      .map(|i| if i % width < width / 2 { 0.0 } else { 1.0 })
+      .collect();
+    let filtered = bilateral(&step, width, height, 1.5, 0.05);
+    for (a, b) in step.iter().zip(&filtered) {
+      assert!((a - b).abs() < 1e-3);
+    }
+  }
+}
@@ -0,0 +1,179 @@
+use crate::perspective::Perspective;
+use image::{ImageBuffer, Rgb};
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
+
+/**
+ * Framing of the image, applied as flip, rotate, perspective, straighten and then crop
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Geometry {
+  #[serde(default)]
+  pub crop: Option<Crop>,
+  /**
+   * Locks the crop to a width / height ratio, the crop shrinks around its center to fit it
+   */
+  #[serde(default)]
+  pub aspect: Option<f32>,
+  /**
+   * Straightening angle in degrees, positive turns the image clockwise.
+   * The rotated image is scaled up so no empty corners show.
+   */
+  #[serde(default)]
+  pub angle: f32,
+  #[serde(default)]
+  pub flip_horizontal: bool,
+  #[serde(default)]
+  pub flip_vertical: bool,
+  /**
+   * Quarter turns clockwise in degrees, 90, 180 or 270
+   */
+  #[serde(default)]
+  pub rotate: u32,
+  #[serde(default)]
+  pub perspective: Perspective,
+}
+
+/**
+ * Crop rectangle relative to the straightened image, all values in 0..1
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Crop {
+  pub x: f32,
+  pub y: f32,
+  pub width: f32,
+  pub height: f32,
+}
+
+/**
+ * Where a rendered buffer sits in the edited frame, so effects placed relative to the frame
+ * land in the same spot when only a region is rendered. `x` and `y` are the top left corner in
+ * pixels of the frame, `scale` the buffer pixels per frame pixel.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct View {
+  pub x: f32,
+  pub y: f32,
+  pub scale: f32,
+  pub frame_width: f32,
+  pub frame_height: f32,
+}
+
+impl View {
+  /**
+   * A buffer showing the whole frame
+   */
+  pub fn full(width: u32, height: u32) -> View {
+    View {
+      x: 0.0,
+      y: 0.0,
+      scale: 1.0,
+      frame_width: width as f32,
+      frame_height: height as f32,
+    }
+  }
+
+  /**
+   * Frame position of the center of a buffer pixel
+   */
+  pub fn frame_position(&self, x: u32, y: u32) -> (f32, f32) {
+    (
+      self.x + (x as f32 + 0.5) / self.scale,
+      self.y + (y as f32 + 0.5) / self.scale,
+    )
+  }
+
+  pub fn frame_long_side(&self) -> f32 {
+    self.frame_width.max(self.frame_height)
+  }
+}
+
+impl Geometry {
+  pub fn is_identity(&self) -> bool {
+    self.crop.is_none()
+      && self.aspect.is_none()
+      && self.angle == 0.0
+      && !self.flip_horizontal
+      && !self.flip_vertical
+      && self.quarter_turns() == 0
+      && self.perspective.is_identity()
+  }
+
+  /**
+   * Clockwise quarter turns of `rotate`, 0..4
+   */
+  pub fn quarter_turns(&self) -> u32 {
+    self.rotate / 90 % 4
+  }
+
+  /**
+   * Crop in pixels for an image of the given size, as x, y, width and height
+   */
+  pub fn crop_rect(&self, width: u32, height: u32) -> (u32, u32, u32, u32) {
+    let crop = self.crop.clone().unwrap_or(Crop {
+      x: 0.0,
+      y: 0.0,
+      width: 1.0,
+      height: 1.0,
+    });
+
+    let x = crop.x.clamp(0.0, 1.0) * width as f32;
+    let y = crop.y.clamp(0.0, 1.0) * height as f32;
+    let mut w = crop.width.clamp(0.0, 1.0 - crop.x.clamp(0.0, 1.0)) * width as f32;
+    let mut h = crop.height.clamp(0.0, 1.0 - crop.y.clamp(0.0, 1.0)) * height as f32;
+
+    let (mut cx, mut cy) = (x + w / 2.0, y + h / 2.0);
+    if let Some(aspect) = self.aspect.filter(|aspect| *aspect > 0.0) {
+      if w / h > aspect {
+        w = h * aspect;
+      } else {
+        h = w / aspect;
+      }
+    }
+    cx = cx.clamp(w / 2.0, width as f32 - w / 2.0);
+    cy = cy.clamp(h / 2.0, height as f32 - h / 2.0);
+
+    let w = (w.round() as u32).clamp(1, width);
+    let h = (h.round() as u32).clamp(1, height);
+    let x = ((cx - w as f32 / 2.0).round().max(0.0) as u32).min(width - w);
+    let y = ((cy - h as f32 / 2.0).round().max(0.0) as u32).min(height - h);
+
+    (x, y, w, h)
+  }
+}
+
+fn lanczos(x: f32) -> f32 {
+  if x == 0.0 {
+    return 1.0;
+  }
+  if x.abs() >= 3.0 {
+    return 0.0;
+  }
+  let px = PI * x;
+  3.0 * px.sin() * (px / 3.0).sin() / (px * px)
+}
+
+pub fn sample_lanczos(image: &ImageBuffer<Rgb<f32>, Vec<f32>>, x: f32, y: f32) -> [f32; 3] {
+  let max_x = image.width() as i64 - 1;
+  let max_y = image.height() as i64 - 1;
+  let (fx, fy) = (x.floor(), y.floor());
+
+  let mut sum = [0.0; 3];
+  let mut total = 0.0;
+
+  for j in -2..=3 {
+    let wy = lanczos(y - (fy + j as f32));
+    let sy = (fy as i64 + j).clamp(0, max_y) as u32;
+    for i in -2..=3 {
+      let weight = wy * lanczos(x - (fx + i as f32));
+      let sx = (fx as i64 + i).clamp(0, max_x) as u32;
+      let pixel = image.get_pixel(sx, sy).0;
+      sum[0] += pixel[0] * weight;
+      sum[1] += pixel[1] * weight;
+      sum[2] += pixel[2] * weight;
+      total += weight;
+    }
+  }
+
+  sum.map(|c| c / total)
+}
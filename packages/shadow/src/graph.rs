@@ -0,0 +1,469 @@
+use crate::color::{
+  linear_srgb_from_oklab, oklab_from_linear_srgb, oklab_from_oklch, oklch_from_oklab,
+};
+use crate::color_curves::ColorCurves;
+use crate::color_grading::{ColorGrading, Wheel};
+use crate::curve::Curve;
+use crate::detail;
+use crate::effects;
+use crate::geometry::View;
+use crate::lut::{Lut3d, LutInput};
+use crate::mask::Mask;
+use crate::monochrome::{self, HueMixer, Toning};
+use crate::texture;
+use crate::tiles::{self, map_pixels};
+use crate::{
+  blacks, contrast, exposure, highlights, mat_mul, saturation, shadows, vibrancy, whites,
+};
+use image::{ImageBuffer, Rgb};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/**
+ * A single adjustment in the processing graph
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Node {
+  #[serde(flatten)]
+  pub operation: Operation,
+  #[serde(default = "enabled")]
+  pub enabled: bool,
+  /**
+   * Nodes are applied from low to high order, nodes with the same order keep their list order
+   */
+  #[serde(default)]
+  pub order: i32,
+  #[serde(default)]
+  pub mask: Option<Mask>,
+}
+
+fn enabled() -> bool {
+  true
+}
+
+impl Node {
+  pub fn new(operation: Operation) -> Node {
+    Node {
+      operation,
+      enabled: true,
+      order: 0,
+      mask: None,
+    }
+  }
+
+  /**
+   * Applies the node on a buffer in the working space
+   */
+  pub fn apply(&self, image: &mut ImageBuffer<Rgb<f32>, Vec<f32>>) {
+    self.apply_view(image, &View::full(image.width(), image.height()));
+  }
+
+  /**
+   * Applies the node on a buffer showing part of the frame
+   */
+  pub fn apply_view(&self, image: &mut ImageBuffer<Rgb<f32>, Vec<f32>>, view: &View) {
+    self.masked(image, |image| self.operation.apply_view(image, view));
+  }
+
+  /**
+   * Applies a detail node on an image with `scale` pixels per pixel of the sensor
+   */
+  pub fn apply_detail(&self, image: &mut ImageBuffer<Rgb<f32>, Vec<f32>>, scale: f32) {
+    self.masked(image, |image| self.operation.apply_detail(image, scale));
+  }
+
+  fn masked(
+    &self,
+    image: &mut ImageBuffer<Rgb<f32>, Vec<f32>>,
+    apply: impl FnOnce(&mut ImageBuffer<Rgb<f32>, Vec<f32>>),
+  ) {
+    let Some(mask) = &self.mask else {
+      apply(image);
+      return;
+    };
+
+    let original = image.clone();
+    apply(image);
+
+    let to_display = crate::conversion(crate::WORKING_COLORSPACE, kolor::spaces::LINEAR_SRGB);
+    tiles::map_pixels_at(image, |x, y, rgb| {
+      let before = original.get_pixel(x, y).0;
+      let linear = crate::mat_mul(&to_display, &before);
+      let weight = mask.weight(oklch_from_oklab(oklab_from_linear_srgb(linear)));
+      [0, 1, 2].map(|c| before[c] + (rgb[c] - before[c]) * weight)
+    });
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Operation {
+  /**
+   * Relative white balance of edits made before Kelvin and tint, rendered as the absolute
+   * white balance it amounts to and ignored once `Edits::white_balance` is set
+   */
+  WhiteBalance {
+    temperature: f32,
+    tint: f32,
+  },
+  Exposure {
+    amount: f32,
+  },
+  Contrast {
+    amount: f32,
+  },
+  Highlights {
+    amount: f32,
+  },
+  Shadows {
+    amount: f32,
+  },
+  Blacks {
+    amount: f32,
+  },
+  Whites {
+    amount: f32,
+  },
+  Texture {
+    amount: f32,
+  },
+  Vibrancy {
+    amount: f32,
+  },
+  Saturation {
+    amount: f32,
+  },
+  Curves {
+    #[serde(default)]
+    tone: Vec<(f32, f32)>,
+    #[serde(default)]
+    red: Vec<(f32, f32)>,
+    #[serde(default)]
+    green: Vec<(f32, f32)>,
+    #[serde(default)]
+    blue: Vec<(f32, f32)>,
+  },
+  /**
+   * Hue vs saturation, hue vs luminance, luminance vs saturation and luminance vs hue
+   */
+  ColorCurves {
+    #[serde(default)]
+    hue_saturation: Vec<(f32, f32)>,
+    #[serde(default)]
+    hue_luminance: Vec<(f32, f32)>,
+    #[serde(default)]
+    luminance_saturation: Vec<(f32, f32)>,
+    #[serde(default)]
+    luminance_hue: Vec<(f32, f32)>,
+  },
+  /**
+   * Shadows, midtones and highlights wheels plus a global one, blending in 0..1, balance in -1..1
+   */
+  ColorGrading {
+    #[serde(default)]
+    shadows: Wheel,
+    #[serde(default)]
+    midtones: Wheel,
+    #[serde(default)]
+    highlights: Wheel,
+    #[serde(default)]
+    global: Wheel,
+    #[serde(default = "half")]
+    blending: f32,
+    #[serde(default)]
+    balance: f32,
+  },
+  /**
+   * Black and white with the luminance of each hue mixed in stops, auto mixes from the image,
+   * see `Edits::with_auto_mix`
+   */
+  BlackAndWhite {
+    #[serde(default)]
+    mixer: HueMixer,
+    #[serde(default)]
+    auto: bool,
+    #[serde(default)]
+    toning: Option<Toning>,
+  },
+  /**
+   * 3D LUT from a .cube or .3dl file
+   */
+  Lut {
+    path: String,
+    #[serde(default)]
+    input: LutInput,
+  },
+  /**
+   * Radius in pixels of the full resolution image, the threshold in stops
+   */
+  Sharpen {
+    amount: f32,
+    #[serde(default = "sharpen_radius")]
+    radius: f32,
+    #[serde(default)]
+    threshold: f32,
+    /**
+     * Deconvolution iterations up to `detail::MAX_ITERATIONS`, 0 sharpens with an unsharp mask
+     */
+    #[serde(default)]
+    iterations: u32,
+  },
+  Denoise {
+    #[serde(default)]
+    luminance: f32,
+    #[serde(default)]
+    chroma: f32,
+  },
+  /**
+   * Amount is in -1..1, all other parameters in 0..1, roundness in -1..1
+   */
+  Vignette {
+    amount: f32,
+    #[serde(default = "half")]
+    midpoint: f32,
+    #[serde(default)]
+    roundness: f32,
+    #[serde(default = "half")]
+    feather: f32,
+    #[serde(default)]
+    highlight_protection: f32,
+  },
+  Grain {
+    amount: f32,
+    #[serde(default = "grain_size")]
+    size: f32,
+    #[serde(default = "half")]
+    roughness: f32,
+    /**
+     * Set per image, so every render of an image gets the same grain
+     */
+    #[serde(default)]
+    seed: Option<u64>,
+  },
+}
+
+fn sharpen_radius() -> f32 {
+  1.0
+}
+
+fn half() -> f32 {
+  0.5
+}
+
+fn grain_size() -> f32 {
+  1.0
+}
+
+impl Operation {
+  /**
+   * Detail operations read the pixels around each pixel at the scale of the sensor's noise and
+   * detail, they are applied on the full resolution image before it is scaled down,
+   * see `process_detail`
+   */
+  pub fn is_detail(&self) -> bool {
+    matches!(self, Operation::Sharpen { .. } | Operation::Denoise { .. })
+  }
+
+  /**
+   * How far in buffer pixels the operation reads around a pixel, what a region has to be padded
+   * with to render the same as the whole frame
+   */
+  pub fn reach(&self, view: &View) -> f32 {
+    match self {
+      Operation::Texture { .. } => texture::sigma(view) * 3.0,
+      // every deconvolution iteration blurs twice
+      Operation::Sharpen {
+        radius, iterations, ..
+      } => radius * 3.0 * (1 + 2 * (*iterations).min(detail::MAX_ITERATIONS)) as f32,
+      Operation::Denoise { chroma, .. } => (1.0 + chroma * 4.0).max(1.5) * 3.0,
+      _ => 0.0,
+    }
+  }
+
+  /**
+   * Sharpening and noise reduction on an image with `scale` pixels per pixel of the sensor,
+   * their radii shrink with it so a half size decode looks like the full one scaled down
+   */
+  pub fn apply_detail(&self, image: &mut ImageBuffer<Rgb<f32>, Vec<f32>>, scale: f32) {
+    match self {
+      Operation::Sharpen {
+        amount,
+        radius,
+        threshold,
+        iterations,
+      } => detail::sharpen(image, *amount, radius * scale, *threshold, *iterations),
+      Operation::Denoise { luminance, chroma } => {
+        detail::denoise(image, *luminance, *chroma, scale)
+      }
+      _ => {}
+    }
+  }
+
+  pub fn apply(&self, image: &mut ImageBuffer<Rgb<f32>, Vec<f32>>) {
+    self.apply_view(image, &View::full(image.width(), image.height()));
+  }
+
+  pub fn apply_view(&self, image: &mut ImageBuffer<Rgb<f32>, Vec<f32>>, view: &View) {
+    let working_colorspace = crate::WORKING_COLORSPACE;
+
+    match self {
+      // folded into the absolute white balance, see `Edits::resolved_white_balance`
+      Operation::WhiteBalance { .. } => {}
+      Operation::Exposure { amount } => map_pixels(image, |rgb| exposure(rgb, *amount)),
+      Operation::Contrast { amount } => map_pixels(image, |rgb| contrast(rgb, *amount)),
+      Operation::Highlights { amount } => map_pixels(image, |rgb| highlights(rgb, *amount)),
+      Operation::Shadows { amount } => map_pixels(image, |rgb| shadows(rgb, *amount)),
+      Operation::Blacks { amount } => map_pixels(image, |rgb| blacks(rgb, *amount)),
+      Operation::Whites { amount } => map_pixels(image, |rgb| whites(rgb, *amount)),
+      Operation::Texture { amount } => texture::texture(image, view, *amount),
+      Operation::Vibrancy { amount } => map_pixels(image, |rgb| vibrancy(rgb, *amount)),
+      Operation::Saturation { amount } => map_pixels(image, |rgb| saturation(rgb, *amount)),
+      Operation::Curves {
+        tone,
+        red,
+        green,
+        blue,
+      } => {
+        let curve_tone = Curve::new(tone);
+        let curve_red = Curve::new(red);
+        let curve_green = Curve::new(green);
+        let curve_blue = Curve::new(blue);
+
+        let to_display = crate::conversion(working_colorspace, kolor::spaces::LINEAR_SRGB);
+        let from_display = crate::conversion(kolor::spaces::LINEAR_SRGB, working_colorspace);
+
+        map_pixels(image, |rgb| {
+          // curves work on the display encoded values, the same space their points are placed in,
+          // values above white go on along the curve's slope so the scene keeps its headroom
+          let linear = mat_mul(&to_display, &rgb);
+          let mut encoded = srgb::gamma::normalised_from_linear(linear);
+
+          if let Some(curve) = &curve_tone {
+            encoded = encoded.map(|c| curve.apply_extended(c));
+          }
+          if let Some(curve) = &curve_red {
+            encoded[0] = curve.apply_extended(encoded[0]);
+          }
+          if let Some(curve) = &curve_green {
+            encoded[1] = curve.apply_extended(encoded[1]);
+          }
+          if let Some(curve) = &curve_blue {
+            encoded[2] = curve.apply_extended(encoded[2]);
+          }
+
+          let linear = srgb::gamma::linear_from_normalised(encoded);
+          mat_mul(&from_display, &linear)
+        });
+      }
+      Operation::ColorCurves {
+        hue_saturation,
+        hue_luminance,
+        luminance_saturation,
+        luminance_hue,
+      } => {
+        let curves = ColorCurves::new(
+          hue_saturation,
+          hue_luminance,
+          luminance_saturation,
+          luminance_hue,
+        );
+
+        let to_display = crate::conversion(working_colorspace, kolor::spaces::LINEAR_SRGB);
+        let from_display = crate::conversion(kolor::spaces::LINEAR_SRGB, working_colorspace);
+
+        map_pixels(image, |rgb| {
+          let linear = mat_mul(&to_display, &rgb);
+          let lch = curves.apply(oklch_from_oklab(oklab_from_linear_srgb(linear)));
+          mat_mul(
+            &from_display,
+            &linear_srgb_from_oklab(oklab_from_oklch(lch)),
+          )
+        });
+      }
+      Operation::ColorGrading {
+        shadows,
+        midtones,
+        highlights,
+        global,
+        blending,
+        balance,
+      } => {
+        let grading = ColorGrading::new(shadows, midtones, highlights, global, *blending, *balance);
+        map_pixels(image, |rgb| grading.apply(rgb));
+      }
+      Operation::BlackAndWhite {
+        mixer,
+        auto,
+        toning,
+      } => monochrome::black_and_white(image, mixer, *auto, toning.as_ref()),
+      Operation::Lut { path, input } => {
+        let lut = match Lut3d::cached(Path::new(path)) {
+          Ok(lut) => lut,
+          Err(err) => {
+            error!("Failed to load LUT {}: {}", path, err);
+            return;
+          }
+        };
+
+        let to_input = crate::conversion(working_colorspace, input.colorspace.kolor());
+        let from_input = crate::conversion(input.colorspace.kolor(), working_colorspace);
+
+        map_pixels(image, |rgb| {
+          let encoded = lut.apply(input.transfer.encode(mat_mul(&to_input, &rgb)));
+          mat_mul(&from_input, &input.transfer.decode(encoded))
+        });
+      }
+      Operation::Sharpen { .. } | Operation::Denoise { .. } => self.apply_detail(image, 1.0),
+      Operation::Vignette {
+        amount,
+        midpoint,
+        roundness,
+        feather,
+        highlight_protection,
+      } => effects::vignette(
+        image,
+        view,
+        *amount,
+        *midpoint,
+        *roundness,
+        *feather,
+        *highlight_protection,
+      ),
+      Operation::Grain {
+        amount,
+        size,
+        roughness,
+        seed,
+      } => effects::grain(image, view, *amount, *size, *roughness, seed.unwrap_or(0)),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn sharpen(iterations: u32) -> Operation {
+    Operation::Sharpen {
+      amount: 1.0,
+      radius: 1.0,
+      threshold: 0.0,
+      iterations,
+    }
+  }
+
+  #[test]
+  fn sharpen_iterations_are_capped() {
+    let view = View::full(64, 64);
+    let capped = sharpen(detail::MAX_ITERATIONS).reach(&view);
+    assert_eq!(sharpen(u32::MAX).reach(&view), capped);
+    assert!(sharpen(1).reach(&view) < capped);
+
+    let edge = ImageBuffer::from_fn(16, 16, |x, _| Rgb([if x < 8 { 0.2 } else { 0.6 }; 3]));
+    let (mut many, mut capped) = (edge.clone(), edge);
+    sharpen(u32::MAX).apply(&mut many);
+    sharpen(detail::MAX_ITERATIONS).apply(&mut capped);
+    assert_eq!(many, capped);
+  }
+}
@@ -0,0 +1,432 @@
+use crate::color_space::{OutputSpace, Transfer, D50};
+use crate::mat_product;
+use crate::white_balance::{adaptation, xyz_from_xy};
+use anyhow::anyhow;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::{ImageEncoder, RgbImage};
+use std::path::Path;
+
+/**
+ * ICC v4 matrix and curves display profile of an output space
+ */
+pub fn profile(space: OutputSpace) -> Vec<u8> {
+  // the profile connection space is D50, colorants are adapted to it
+  let chad = adaptation(space.white(), D50);
+  let colorants = mat_product(&chad, &space.to_xyz());
+  let column = |c: usize| [colorants[0][c], colorants[1][c], colorants[2][c]];
+  let curve = parametric_curve(&space.transfer());
+
+  let tags: Vec<(&[u8; 4], Vec<u8>)> = vec![
+    (b"desc", text(space.name())),
+    (b"cprt", text("No copyright, use freely")),
+    (b"wtpt", xyz(xyz_from_xy(D50))),
+    (b"chad", sf32(&chad.concat())),
+    (b"rXYZ", xyz(column(0))),
+    (b"gXYZ", xyz(column(1))),
+    (b"bXYZ", xyz(column(2))),
+    (b"rTRC", curve.clone()),
+    (b"gTRC", curve.clone()),
+    (b"bTRC", curve),
+  ];
+
+  assemble([4, 0x30, 0, 0], b"mntrRGB XYZ ", &tags)
+}
+
+/**
+ * Profile of the given version out of its tags, with the device class, color space and connection
+ * space as they go in the header
+ */
+pub(crate) fn assemble(
+  version: [u8; 4],
+  class: &[u8; 12],
+  tags: &[(&[u8; 4], Vec<u8>)],
+) -> Vec<u8> {
+  let table_size = 4 + tags.len() * 12;
+  let mut data = Vec::new();
+  let mut table = (tags.len() as u32).to_be_bytes().to_vec();
+  let mut offset = 128 + table_size;
+  for (signature, tag) in tags {
+    table.extend_from_slice(*signature);
+    table.extend_from_slice(&(offset as u32).to_be_bytes());
+    table.extend_from_slice(&(tag.len() as u32).to_be_bytes());
+    data.extend_from_slice(tag);
+    // tags start on 4 byte boundaries
+    while data.len() % 4 != 0 {
+      data.push(0);
+    }
+    offset = 128 + table_size + data.len();
+  }
+
+  let size = 128 + table_size + data.len();
+  let mut header = Vec::with_capacity(size);
+  header.extend_from_slice(&(size as u32).to_be_bytes());
+  header.extend_from_slice(&[0; 4]); // preferred CMM
+  header.extend_from_slice(&version);
+  header.extend_from_slice(class);
+  header.extend_from_slice(&[0; 12]); // creation date
+  header.extend_from_slice(b"acsp");
+  header.extend_from_slice(&[0; 24]); // platform, flags, manufacturer, model, attributes
+  header.extend_from_slice(&[0; 4]); // perceptual intent
+  for v in xyz_from_xy(D50) {
+    header.extend_from_slice(&s15_fixed16(v));
+  }
+  header.extend_from_slice(&[0; 4]); // creator
+  header.extend_from_slice(&[0; 16]); // no profile id
+  header.extend_from_slice(&[0; 28]);
+
+  header.extend_from_slice(&table);
+  header.extend_from_slice(&data);
+  header
+}
+
+pub(crate) fn s15_fixed16(v: f32) -> [u8; 4] {
+  ((v as f64 * 65536.0).round() as i32).to_be_bytes()
+}
+
+pub(crate) fn xyz(v: [f32; 3]) -> Vec<u8> {
+  let mut tag = b"XYZ \0\0\0\0".to_vec();
+  for c in v {
+    tag.extend_from_slice(&s15_fixed16(c));
+  }
+  tag
+}
+
+fn sf32(values: &[f32]) -> Vec<u8> {
+  let mut tag = b"sf32\0\0\0\0".to_vec();
+  for v in values {
+    tag.extend_from_slice(&s15_fixed16(*v));
+  }
+  tag
+}
+
+fn parametric_curve(transfer: &Transfer) -> Vec<u8> {
+  let mut tag = b"para\0\0\0\0".to_vec();
+  if transfer.c == 0.0 && transfer.b == 0.0 && transfer.a == 1.0 {
+    tag.extend_from_slice(&[0, 0, 0, 0]);
+    tag.extend_from_slice(&s15_fixed16(transfer.gamma));
+  } else {
+    tag.extend_from_slice(&[0, 3, 0, 0]);
+    for v in [
+      transfer.gamma,
+      transfer.a,
+      transfer.b,
+      transfer.c,
+      transfer.d,
+    ] {
+      tag.extend_from_slice(&s15_fixed16(v));
+    }
+  }
+  tag
+}
+
+/**
+ * Multi localized unicode text with a single english record
+ */
+fn text(value: &str) -> Vec<u8> {
+  let utf16: Vec<u8> = value.encode_utf16().flat_map(|c| c.to_be_bytes()).collect();
+  let mut tag = b"mluc\0\0\0\0".to_vec();
+  tag.extend_from_slice(&1u32.to_be_bytes());
+  tag.extend_from_slice(&12u32.to_be_bytes());
+  tag.extend_from_slice(b"enUS");
+  tag.extend_from_slice(&(utf16.len() as u32).to_be_bytes());
+  tag.extend_from_slice(&28u32.to_be_bytes());
+  tag.extend_from_slice(&utf16);
+  tag
+}
+
+/**
+ * Writes a display encoded image as JPEG or PNG, by the extension of the path,
+ * with the profile of its output space embedded
+ */
+pub fn write(image: &RgbImage, path: &Path, space: OutputSpace) -> anyhow::Result<()> {
+  let extension = path
+    .extension()
+    .and_then(|e| e.to_str())
+    .map(|e| e.to_lowercase());
+
+  let mut encoded = Vec::new();
+  let (width, height) = image.dimensions();
+  let profile = profile(space);
+  let data = match extension.as_deref() {
+    Some("jpg" | "jpeg") => {
+      JpegEncoder::new_with_quality(&mut encoded, 95).write_image(
+        image.as_raw(),
+        width,
+        height,
+        image::ColorType::Rgb8,
+      )?;
+      embed_in_jpeg(&encoded, &profile)?
+    }
+    Some("png") => {
+      PngEncoder::new(&mut encoded).write_image(
+        image.as_raw(),
+        width,
+        height,
+        image::ColorType::Rgb8,
+      )?;
+      embed_in_png(&encoded, &profile)?
+    }
+    _ => return Err(anyhow!("Unsupported format {:?}", path)),
+  };
+
+  std::fs::write(path, data)?;
+  Ok(())
+}
+
+/**
+ * Inserts the profile as APP2 segments after the JFIF and Exif segments,
+ * which readers expect right after the start of image marker
+ */
+fn embed_in_jpeg(jpeg: &[u8], profile: &[u8]) -> anyhow::Result<Vec<u8>> {
+  if jpeg.len() < 2 || jpeg[0..2] != [0xFF, 0xD8] {
+    return Err(anyhow!("Not a JPEG"));
+  }
+  let mut at = 2;
+  while let [0xFF, 0xE0 | 0xE1, high, low, ..] = jpeg[at..] {
+    at += 2 + u16::from_be_bytes([high, low]) as usize;
+  }
+  if at > jpeg.len() {
+    return Err(anyhow!("Truncated JPEG"));
+  }
+  const HEADER: &[u8] = b"ICC_PROFILE\0";
+  // a segment holds at most 65535 bytes including its length, the header and the sequence
+  let chunks: Vec<&[u8]> = profile.chunks(65535 - 2 - HEADER.len() - 2).collect();
+
+  let mut out = jpeg[..at].to_vec();
+  for (i, chunk) in chunks.iter().enumerate() {
+    let length = 2 + HEADER.len() + 2 + chunk.len();
+    out.extend_from_slice(&[0xFF, 0xE2]);
+    out.extend_from_slice(&(length as u16).to_be_bytes());
+    out.extend_from_slice(HEADER);
+    out.push(i as u8 + 1);
+    out.push(chunks.len() as u8);
+    out.extend_from_slice(chunk);
+  }
+  out.extend_from_slice(&jpeg[at..]);
+  Ok(out)
+}
+
+/**
+ * Inserts an iCCP chunk after the IHDR chunk, the profile zlib wrapped in stored deflate blocks
+ */
+fn embed_in_png(png: &[u8], profile: &[u8]) -> anyhow::Result<Vec<u8>> {
+  // signature and IHDR, which always has 13 bytes of data
+  let ihdr_end = 8 + 4 + 4 + 13 + 4;
+  if png.len() < ihdr_end || &png[12..16] != b"IHDR" {
+    return Err(anyhow!("Not a PNG"));
+  }
+
+  let mut zlib = vec![0x78, 0x01];
+  let blocks: Vec<&[u8]> = profile.chunks(65535).collect();
+  for (i, block) in blocks.iter().enumerate() {
+    zlib.push((i + 1 == blocks.len()) as u8);
+    zlib.extend_from_slice(&(block.len() as u16).to_le_bytes());
+    zlib.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+    zlib.extend_from_slice(block);
+  }
+  zlib.extend_from_slice(&adler32(profile).to_be_bytes());
+
+  let mut chunk = b"iCCP".to_vec();
+  chunk.extend_from_slice(b"icc\0\0"); // name and compression method
+  chunk.extend_from_slice(&zlib);
+
+  let mut out = png[..ihdr_end].to_vec();
+  out.extend_from_slice(&((chunk.len() - 4) as u32).to_be_bytes());
+  out.extend_from_slice(&chunk);
+  out.extend_from_slice(&crc32(&chunk).to_be_bytes());
+  out.extend_from_slice(&png[ihdr_end..]);
+  Ok(out)
+}
+
+fn adler32(data: &[u8]) -> u32 {
+  let (mut a, mut b) = (1u32, 0u32);
+  for byte in data {
+    a = (a + *byte as u32) % 65521;
+    b = (b + a) % 65521;
+  }
+  (b << 16) | a
+}
+
+fn crc32(data: &[u8]) -> u32 {
+  let mut crc = 0xFFFFFFFFu32;
+  for byte in data {
+    crc ^= *byte as u32;
+    for _ in 0..8 {
+      crc = if crc & 1 == 1 {
+        (crc >> 1) ^ 0xEDB88320
+      } else {
+        crc >> 1
+      };
+    }
+  }
+  !crc
+}
+
+/**
+ * Colorants and media white of an RGB matrix and curves profile, both in the D50 connection space.
+ * Profiles of printers and other devices built from lookup tables are left to lcms.
+ */
+pub fn read_matrix(data: &[u8]) -> anyhow::Result<([[f32; 3]; 3], [f32; 3])> {
+  if data.len() < 132 || &data[36..40] != b"acsp" {
+    return Err(anyhow!("Not an ICC profile"));
+  }
+  if &data[16..20] != b"RGB " || &data[20..24] != b"XYZ " {
+    return Err(anyhow!(
+      "Only RGB profiles with an XYZ connection space are supported"
+    ));
+  }
+
+  let read_u32 =
+    |at: usize| u32::from_be_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]);
+  let count = read_u32(128) as usize;
+  let find = |signature: &[u8; 4]| -> Option<[f32; 3]> {
+    (0..count)
+      .map(|i| 132 + i * 12)
+      .take_while(|entry| entry + 12 <= data.len())
+      .find(|entry| &data[*entry..entry + 4] == signature)
+      .and_then(|entry| {
+        let offset = read_u32(entry + 4) as usize;
+        let tag = data.get(offset..offset + 20)?;
+        if &tag[0..4] != b"XYZ " {
+          return None;
+        }
+        let value = |at: usize| {
+          i32::from_be_bytes([tag[at], tag[at + 1], tag[at + 2], tag[at + 3]]) as f32 / 65536.0
+        };
+        Some([value(8), value(12), value(16)])
+      })
+  };
+
+  let (Some(red), Some(green), Some(blue)) = (find(b"rXYZ"), find(b"gXYZ"), find(b"bXYZ")) else {
+    return Err(anyhow!("Only matrix profiles are supported"));
+  };
+  let colorants = [0, 1, 2].map(|row| [red[row], green[row], blue[row]]);
+  let media_white = find(b"wtpt").unwrap_or(xyz_from_xy(D50));
+  Ok((colorants, media_white))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use image::codecs::jpeg::JpegDecoder;
+  use image::codecs::png::PngDecoder;
+  use image::ImageDecoder;
+  use std::io::Cursor;
+
+  const SPACES: [OutputSpace; 5] = [
+    OutputSpace::Srgb,
+    OutputSpace::DisplayP3,
+    OutputSpace::AdobeRgb,
+    OutputSpace::Rec2020,
+    OutputSpace::ProPhoto,
+  ];
+
+  fn read_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(data[at..at + 4].try_into().unwrap())
+  }
+
+  fn image() -> RgbImage {
+    RgbImage::from_fn(16, 8, |x, y| image::Rgb([x as u8 * 16, y as u8 * 32, 128]))
+  }
+
+  /**
+   * The image written to a temporary file and read back
+   */
+  fn written(space: OutputSpace, extension: &str) -> Vec<u8> {
+    let path = std::env::temp_dir().join(format!(
+      "icc-test-{}-{:?}.{}",
+      std::process::id(),
+      space,
+      extension
+    ));
+    write(&image(), &path, space).unwrap();
+    let data = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    data
+  }
+
+  #[test]
+  fn profiles_read_back() {
+    for space in SPACES {
+      let data = profile(space);
+      assert_eq!(read_u32(&data, 0) as usize, data.len());
+
+      let count = read_u32(&data, 128) as usize;
+      for i in 0..count {
+        let (offset, size) = (read_u32(&data, 136 + i * 12), read_u32(&data, 140 + i * 12));
+        assert_eq!(offset % 4, 0);
+        assert!((offset + size) as usize <= data.len());
+      }
+
+      let (colorants, white) = read_matrix(&data).unwrap();
+      let expected = mat_product(&adaptation(space.white(), D50), &space.to_xyz());
+      for (row, expected) in colorants.iter().zip(expected) {
+        for (c, e) in row.iter().zip(expected) {
+          assert!((c - e).abs() < 1e-4, "{:?}", space);
+        }
+      }
+      for (w, e) in white.iter().zip(xyz_from_xy(D50)) {
+        assert!((w - e).abs() < 1e-4);
+      }
+    }
+  }
+
+  #[test]
+  fn curves_invert_the_encoding() {
+    for space in SPACES {
+      let data = profile(space);
+      let count = read_u32(&data, 128) as usize;
+      let entry = (0..count)
+        .map(|i| 132 + i * 12)
+        .find(|entry| &data[*entry..entry + 4] == b"rTRC")
+        .unwrap();
+      let tag = &data[read_u32(&data, entry + 4) as usize..];
+      assert_eq!(&tag[0..4], b"para");
+
+      let value = |i: usize| read_u32(tag, 12 + i * 4) as i32 as f32 / 65536.0;
+      let decode = |x: f32| match tag[9] {
+        0 => x.powf(value(0)),
+        _ if x >= value(4) => (value(1) * x + value(2)).powf(value(0)),
+        _ => value(3) * x,
+      };
+      let transfer = space.transfer();
+      for linear in [0.001, 0.01, 0.18, 0.5, 1.0] {
+        let decoded = decode(transfer.encode(linear));
+        assert!((decoded - linear).abs() < 1e-3, "{:?} {}", space, linear);
+      }
+    }
+  }
+
+  #[test]
+  fn embeds_in_jpeg_after_jfif() {
+    let data = written(OutputSpace::DisplayP3, "jpg");
+    assert_eq!(data[0..4], [0xFF, 0xD8, 0xFF, 0xE0]);
+    let app2 = 4 + u16::from_be_bytes([data[4], data[5]]) as usize;
+    assert_eq!(data[app2..app2 + 2], [0xFF, 0xE2]);
+    assert_eq!(&data[app2 + 4..app2 + 16], b"ICC_PROFILE\0");
+
+    let mut decoder = JpegDecoder::new(Cursor::new(&data)).unwrap();
+    assert_eq!(decoder.dimensions(), (16, 8));
+    assert_eq!(decoder.icc_profile(), Some(profile(OutputSpace::DisplayP3)));
+  }
+
+  #[test]
+  fn embeds_in_png() {
+    let data = written(OutputSpace::AdobeRgb, "png");
+    let mut decoder = PngDecoder::new(Cursor::new(&data)).unwrap();
+    assert_eq!(decoder.icc_profile(), Some(profile(OutputSpace::AdobeRgb)));
+
+    let mut pixels = vec![0; decoder.total_bytes() as usize];
+    decoder.read_image(&mut pixels).unwrap();
+    assert_eq!(pixels, image().into_raw());
+  }
+
+  #[test]
+  fn rejects_other_formats() {
+    let path = std::env::temp_dir().join("icc-test.tiff");
+    assert!(write(&image(), &path, OutputSpace::Srgb).is_err());
+    assert!(embed_in_jpeg(b"not a jpeg", &[]).is_err());
+    assert!(embed_in_png(b"not a png", &[]).is_err());
+  }
+}
@@ -0,0 +1,625 @@
+use crate::geometry::sample_lanczos;
+use anyhow::anyhow;
+use image::{ImageBuffer, Rgb};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/**
+ * Camera and lens the image was taken with, as recorded in its metadata.
+ * Focal length is in mm and aperture an f-number, both 0 when unknown.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct LensInfo {
+  pub camera_make: String,
+  pub camera_model: String,
+  pub lens_make: String,
+  pub lens_model: String,
+  pub focal_length: f32,
+  pub aperture: f32,
+}
+
+/**
+ * Corrects distortion, vignetting and lateral chromatic aberration of the lens.
+ * The profile is looked up in the lensfun database, the manual values are applied on top of it.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LensCorrection {
+  #[serde(default = "enabled")]
+  pub profile: bool,
+  /**
+   * Lens model to look up instead of the one in the metadata
+   */
+  #[serde(default)]
+  pub lens: Option<String>,
+  /**
+   * In -1..1, positive straightens barrel distortion, negative pincushion distortion
+   */
+  #[serde(default)]
+  pub distortion: f32,
+  /**
+   * In -1..1, positive brightens the corners by up to two stops
+   */
+  #[serde(default)]
+  pub vignetting: f32,
+  /**
+   * Scales the red channel around the center, in thousandths of the image
+   */
+  #[serde(default)]
+  pub chromatic_aberration_red: f32,
+  /**
+   * Scales the blue channel around the center, in thousandths of the image
+   */
+  #[serde(default)]
+  pub chromatic_aberration_blue: f32,
+}
+
+fn enabled() -> bool {
+  true
+}
+
+impl Default for LensCorrection {
+  fn default() -> Self {
+    LensCorrection {
+      profile: true,
+      lens: None,
+      distortion: 0.0,
+      vignetting: 0.0,
+      chromatic_aberration_red: 0.0,
+      chromatic_aberration_blue: 0.0,
+    }
+  }
+}
+
+impl LensCorrection {
+  pub fn is_manual_identity(&self) -> bool {
+    self.distortion == 0.0
+      && self.vignetting == 0.0
+      && self.chromatic_aberration_red == 0.0
+      && self.chromatic_aberration_blue == 0.0
+  }
+}
+
+/**
+ * Radial polynomial, the distorted radius is r * (c0 + c1 r + c2 r^2 + c3 r^3 + c4 r^4).
+ * All lensfun distortion and TCA models fit into it, which lets calibrations be interpolated.
+ */
+type Radial = [f32; 5];
+
+const IDENTITY: Radial = [1.0, 0.0, 0.0, 0.0, 0.0];
+
+struct Camera {
+  make: String,
+  model: String,
+  crop_factor: f32,
+}
+
+struct Lens {
+  make: String,
+  model: String,
+  crop_factor: f32,
+  distortion: Vec<(f32, Radial)>,
+  /**
+   * Red and blue channel per focal length
+   */
+  tca: Vec<(f32, [Radial; 2])>,
+  /**
+   * Focal length, aperture, distance and the k1, k2, k3 of the pa model
+   */
+  vignetting: Vec<(f32, f32, f32, [f32; 3])>,
+}
+
+/**
+ * Corrections of a lens at one focal length and aperture
+ */
+pub struct LensProfile {
+  /**
+   * Image radius relative to the calibration's, from a different crop factor
+   */
+  scale: f32,
+  distortion: Radial,
+  tca: [Radial; 2],
+  vignetting: [f32; 3],
+}
+
+/**
+ * Camera and lens entries of lensfun's XML files
+ */
+pub struct LensDatabase {
+  cameras: Vec<Camera>,
+  lenses: Vec<Lens>,
+}
+
+impl LensDatabase {
+  /**
+   * Reads all .xml files of a directory, like lensfun's data/db.
+   * Files that can't be read are skipped, the rest of the database still works.
+   */
+  pub fn open(dir: &Path) -> anyhow::Result<LensDatabase> {
+    let mut database = LensDatabase {
+      cameras: Vec::new(),
+      lenses: Vec::new(),
+    };
+
+    for entry in std::fs::read_dir(dir)? {
+      let path = match entry {
+        Ok(entry) => entry.path(),
+        Err(err) => {
+          error!("Failed to list lens database: {}", err);
+          continue;
+        }
+      };
+      if path.extension().and_then(|ext| ext.to_str()) != Some("xml") {
+        continue;
+      }
+      let parsed = std::fs::read_to_string(&path)
+        .map_err(|err| anyhow!(err))
+        .and_then(|text| database.parse(&text));
+      if let Err(err) = parsed {
+        error!("Failed to read {:?}: {}", path, err);
+      }
+    }
+
+    Ok(database)
+  }
+
+  pub fn parse(&mut self, text: &str) -> anyhow::Result<()> {
+    let document = roxmltree::Document::parse(text)?;
+
+    for node in document.root_element().children() {
+      match node.tag_name().name() {
+        "camera" => self.cameras.push(Camera {
+          make: child_text(&node, "maker"),
+          model: child_text(&node, "model"),
+          crop_factor: child_text(&node, "cropfactor").parse().unwrap_or(1.0),
+        }),
+        "lens" => self.lenses.push(parse_lens(&node)),
+        _ => {}
+      }
+    }
+
+    Ok(())
+  }
+
+  /**
+   * Profile of the lens at the focal length and aperture of the shot,
+   * `lens_model` replaces the lens from the metadata when set
+   */
+  pub fn find(&self, info: &LensInfo, lens_model: Option<&str>) -> Option<LensProfile> {
+    let lens = match lens_model {
+      Some(model) => self.find_lens("", model)?,
+      None => self.find_lens(&info.lens_make, &info.lens_model)?,
+    };
+
+    let camera_crop = self
+      .find_camera(&info.camera_make, &info.camera_model)
+      .map(|camera| camera.crop_factor)
+      .unwrap_or(lens.crop_factor);
+
+    let focal = info.focal_length;
+    let distortion = interpolate(&lens.distortion, focal, mix).unwrap_or(IDENTITY);
+    let tca = interpolate(&lens.tca, focal, |a, b, t| {
+      [mix(&a[0], &b[0], t), mix(&a[1], &b[1], t)]
+    })
+    .unwrap_or([IDENTITY; 2]);
+
+    // vignetting per focal length at the aperture of the shot, then between the focal lengths
+    let mut focals: Vec<f32> = lens.vignetting.iter().map(|v| v.0).collect();
+    focals.sort_by(|a, b| a.total_cmp(b));
+    focals.dedup();
+    let per_focal: Vec<(f32, [f32; 3])> = focals
+      .iter()
+      .filter_map(|&f| {
+        let at_focal: Vec<_> = lens.vignetting.iter().filter(|v| v.0 == f).collect();
+        // the farthest calibrated distance, closest to how most images are focused
+        let distance = at_focal.iter().map(|v| v.2).fold(0.0, f32::max);
+        let mut apertures: Vec<(f32, [f32; 3])> = at_focal
+          .iter()
+          .filter(|v| v.2 == distance)
+          .map(|v| (v.1.max(0.1).log2(), v.3))
+          .collect();
+        apertures.sort_by(|a, b| a.0.total_cmp(&b.0));
+        interpolate(&apertures, info.aperture.max(0.1).log2(), mix).map(|k| (f, k))
+      })
+      .collect();
+    let vignetting = interpolate(&per_focal, focal, mix).unwrap_or([0.0; 3]);
+
+    Some(LensProfile {
+      scale: lens.crop_factor / camera_crop,
+      distortion,
+      tca,
+      vignetting,
+    })
+  }
+
+  fn find_lens(&self, make: &str, model: &str) -> Option<&Lens> {
+    let wanted = tokens(model);
+    if wanted.is_empty() {
+      return None;
+    }
+    let make = tokens(make);
+
+    // every word of the database entry has to be in the name, apart from the maker,
+    // the entry naming the most of them wins
+    self
+      .lenses
+      .iter()
+      .filter_map(|lens| {
+        let maker = tokens(&lens.make);
+        if !make.is_empty() && !maker.iter().any(|word| make.contains(word)) {
+          return None;
+        }
+        let words = tokens(&lens.model);
+        let matches = words
+          .iter()
+          .all(|word| wanted.contains(word) || maker.contains(word));
+        matches.then_some((words.len(), lens))
+      })
+      .max_by_key(|(count, _)| *count)
+      .map(|(_, lens)| lens)
+  }
+
+  fn find_camera(&self, make: &str, model: &str) -> Option<&Camera> {
+    let make = tokens(make);
+    let model = tokens(model);
+
+    self.cameras.iter().find(|camera| {
+      let maker = tokens(&camera.make);
+      // camera models are sometimes written with the maker in front
+      let name: Vec<String> = tokens(&camera.model)
+        .into_iter()
+        .filter(|word| !maker.contains(word))
+        .collect();
+      let own: Vec<&String> = model.iter().filter(|word| !maker.contains(word)).collect();
+      maker.iter().any(|word| make.contains(word)) && name.iter().eq(own)
+    })
+  }
+}
+
+fn parse_lens(node: &roxmltree::Node) -> Lens {
+  let mut lens = Lens {
+    make: child_text(node, "maker"),
+    model: child_text(node, "model"),
+    crop_factor: child_text(node, "cropfactor").parse().unwrap_or(1.0),
+    distortion: Vec::new(),
+    tca: Vec::new(),
+    vignetting: Vec::new(),
+  };
+
+  let calibration = node.children().find(|c| c.has_tag_name("calibration"));
+  for entry in calibration.iter().flat_map(|c| c.children()) {
+    let value = |name: &str| {
+      entry
+        .attribute(name)
+        .and_then(|v| v.parse::<f32>().ok())
+        .unwrap_or(0.0)
+    };
+    let model = entry.attribute("model").unwrap_or("");
+    let focal = value("focal");
+
+    match (entry.tag_name().name(), model) {
+      ("distortion", "poly3") => {
+        let k1 = value("k1");
+        lens.distortion.push((focal, [1.0 - k1, 0.0, k1, 0.0, 0.0]));
+      }
+      ("distortion", "poly5") => {
+        lens
+          .distortion
+          .push((focal, [1.0, 0.0, value("k1"), 0.0, value("k2")]));
+      }
+      ("distortion", "ptlens") => {
+        let (a, b, c) = (value("a"), value("b"), value("c"));
+        lens
+          .distortion
+          .push((focal, [1.0 - a - b - c, c, b, a, 0.0]));
+      }
+      ("tca", "linear") => {
+        let red = [value("kr"), 0.0, 0.0, 0.0, 0.0];
+        let blue = [value("kb"), 0.0, 0.0, 0.0, 0.0];
+        lens.tca.push((focal, [red, blue]));
+      }
+      ("tca", "poly3") => {
+        let vr = entry.attribute("vr").map_or(1.0, |_| value("vr"));
+        let vb = entry.attribute("vb").map_or(1.0, |_| value("vb"));
+        let red = [vr, value("cr"), value("br"), 0.0, 0.0];
+        let blue = [vb, value("cb"), value("bb"), 0.0, 0.0];
+        lens.tca.push((focal, [red, blue]));
+      }
+      ("vignetting", "pa") => lens.vignetting.push((
+        focal,
+        value("aperture"),
+        value("distance"),
+        [value("k1"), value("k2"), value("k3")],
+      )),
+      _ => {}
+    }
+  }
+
+  lens.distortion.sort_by(|a, b| a.0.total_cmp(&b.0));
+  lens.tca.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+  lens
+}
+
+fn child_text(node: &roxmltree::Node, name: &str) -> String {
+  // the untranslated name is the one without a lang attribute
+  node
+    .children()
+    .filter(|child| child.has_tag_name(name) && child.attribute("lang").is_none())
+    .find_map(|child| child.text())
+    .unwrap_or("")
+    .trim()
+    .to_string()
+}
+
+/**
+ * Lowercase words of a name, split between letters and numbers so "EF24-70mm" matches "EF 24-70mm"
+ */
+fn tokens(name: &str) -> Vec<String> {
+  let mut words: Vec<String> = Vec::new();
+  let mut last: Option<char> = None;
+
+  for c in name.to_lowercase().chars() {
+    if c.is_whitespace() || c == '/' || c == ',' {
+      last = None;
+      continue;
+    }
+    let boundary = match last {
+      None => true,
+      Some(l) => l.is_ascii_alphabetic() != c.is_ascii_alphabetic() && c != '.' && l != '.',
+    };
+    if boundary {
+      words.push(String::new());
+    }
+    words.last_mut().unwrap().push(c);
+    last = Some(c);
+  }
+
+  words
+}
+
+fn mix<const N: usize>(a: &[f32; N], b: &[f32; N], t: f32) -> [f32; N] {
+  let mut result = *a;
+  for i in 0..N {
+    result[i] += (b[i] - a[i]) * t;
+  }
+  result
+}
+
+/**
+ * Linear interpolation between the calibrations around `at`, sorted by their key.
+ * Outside the calibrated range the closest calibration is used.
+ */
+fn interpolate<T: Clone>(
+  entries: &[(f32, T)],
+  at: f32,
+  mix: impl Fn(&T, &T, f32) -> T,
+) -> Option<T> {
+  let first = entries.first()?;
+  let last = entries.last()?;
+  if at <= first.0 {
+    return Some(first.1.clone());
+  }
+  if at >= last.0 {
+    return Some(last.1.clone());
+  }
+
+  let upper = entries.iter().position(|entry| entry.0 >= at)?;
+  let (a, b) = (&entries[upper - 1], &entries[upper]);
+  if b.0 == a.0 {
+    return Some(a.1.clone());
+  }
+  Some(mix(&a.1, &b.1, (at - a.0) / (b.0 - a.0)))
+}
+
+fn radial(poly: &Radial, r: f32) -> f32 {
+  poly[0] + r * (poly[1] + r * (poly[2] + r * (poly[3] + r * poly[4])))
+}
+
+/**
+ * The profile (when there is one) and the manual corrections, as a mapping from the corrected
+ * image back into the source. The source is expected in the working space,
+ * vignetting is corrected on linear values.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct LensMap {
+  scale: f32,
+  distortion: Radial,
+  tca: [Radial; 2],
+  channel_scale: [f32; 3],
+  vignetting: [f32; 3],
+  manual_vignetting: f32,
+}
+
+impl LensMap {
+  pub fn new(profile: Option<&LensProfile>, manual: &LensCorrection) -> LensMap {
+    let mut distortion = profile.map_or(IDENTITY, |p| p.distortion);
+    // the manual correction as a poly3 model, small corrections add up
+    let k1 = manual.distortion * -0.1;
+    distortion[0] -= k1;
+    distortion[2] += k1;
+
+    LensMap {
+      scale: profile.map_or(1.0, |p| p.scale),
+      distortion,
+      tca: profile.map_or([IDENTITY; 2], |p| p.tca),
+      channel_scale: [
+        1.0 + manual.chromatic_aberration_red / 1000.0,
+        1.0,
+        1.0 + manual.chromatic_aberration_blue / 1000.0,
+      ],
+      vignetting: profile.map_or([0.0; 3], |p| p.vignetting),
+      manual_vignetting: manual.vignetting,
+    }
+  }
+
+  /**
+   * Corrected color at a position of the corrected image, in pixels from its top left corner
+   */
+  pub fn sample(&self, image: &ImageBuffer<Rgb<f32>, Vec<f32>>, x: f32, y: f32) -> [f32; 3] {
+    let width = image.width() as f32;
+    let height = image.height() as f32;
+    // distortion and TCA are relative to half the short side like in Panorama Tools,
+    // vignetting to half the diagonal
+    let half_short = width.min(height) / 2.0;
+    let half_diagonal = width.hypot(height) / 2.0;
+
+    let dx = x - width / 2.0;
+    let dy = y - height / 2.0;
+
+    // radius of the output pixel in calibration units
+    let r = dx.hypot(dy) / half_short * self.scale;
+    let factor = radial(&self.distortion, r);
+
+    let source = |channel_factor: f32| {
+      let f = factor * channel_factor;
+      sample_lanczos(
+        image,
+        dx * f + width / 2.0 - 0.5,
+        dy * f + height / 2.0 - 0.5,
+      )
+    };
+
+    let has_tca = self.tca != [IDENTITY; 2] || self.channel_scale != [1.0; 3];
+    let pixel = if has_tca {
+      let rd = r * factor;
+      let red = source(radial(&self.tca[0], rd) * self.channel_scale[0])[0];
+      let blue = source(radial(&self.tca[1], rd) * self.channel_scale[2])[2];
+      let green = source(1.0)[1];
+      [red, green, blue]
+    } else {
+      source(1.0)
+    };
+
+    // the pa model describes how much light reaches the sensor, so its inverse brightens it back
+    let k = self.vignetting;
+    let rv = (dx * factor).hypot(dy * factor) / half_diagonal;
+    let r2 = (rv * self.scale).powi(2);
+    let falloff = 1.0 + k[0] * r2 + k[1] * r2 * r2 + k[2] * r2 * r2 * r2;
+    let manual_gain = (self.manual_vignetting * 2.0 * rv.min(1.0).powi(2)).exp2();
+    let gain = manual_gain / falloff.max(0.1);
+    pixel.map(|c| c * gain)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const DATABASE: &str = r#"<lensdatabase version="1">
+    <camera>
+      <maker>Canon</maker>
+      <model>Canon EOS 90D</model>
+      <mount>Canon EF-S</mount>
+      <cropfactor>1.6</cropfactor>
+    </camera>
+    <lens>
+      <maker>Canon</maker>
+      <model>Canon EF 24-70mm f/2.8L II USM</model>
+      <model lang="de">Canon EF 24-70 mm</model>
+      <mount>Canon EF</mount>
+      <cropfactor>1</cropfactor>
+      <calibration>
+        <distortion model="poly3" focal="24" k1="-0.02"/>
+        <distortion model="poly3" focal="70" k1="0.01"/>
+        <tca model="poly3" focal="24" vr="1.0002" vb="0.9998"/>
+        <vignetting model="pa" focal="24" aperture="2.8" distance="10" k1="-0.5" k2="0.1" k3="0"/>
+        <vignetting model="pa" focal="24" aperture="5.6" distance="10" k1="-0.2" k2="0" k3="0"/>
+        <vignetting model="pa" focal="24" aperture="5.6" distance="1" k1="-0.9" k2="0" k3="0"/>
+      </calibration>
+    </lens>
+  </lensdatabase>"#;
+
+  fn database() -> LensDatabase {
+    let mut database = LensDatabase {
+      cameras: Vec::new(),
+      lenses: Vec::new(),
+    };
+    database.parse(DATABASE).unwrap();
+    database
+  }
+
+  fn info(focal_length: f32, aperture: f32) -> LensInfo {
+    LensInfo {
+      camera_make: "Canon".to_string(),
+      camera_model: "EOS 90D".to_string(),
+      lens_make: "Canon".to_string(),
+      lens_model: "EF24-70mm f/2.8L II USM".to_string(),
+      focal_length,
+      aperture,
+    }
+  }
+
+  #[test]
+  fn parses_cameras_and_lenses() {
+    let database = database();
+    assert_eq!(database.cameras.len(), 1);
+    assert_eq!(database.cameras[0].crop_factor, 1.6);
+    let lens = &database.lenses[0];
+    // the untranslated name
+    assert_eq!(lens.model, "Canon EF 24-70mm f/2.8L II USM");
+    assert_eq!(lens.distortion.len(), 2);
+    assert_eq!(lens.tca.len(), 1);
+    assert_eq!(lens.vignetting.len(), 3);
+  }
+
+  #[test]
+  fn interpolates_between_focal_lengths() {
+    let profile = database().find(&info(47.0, 2.8), None).unwrap();
+    // halfway between k1 = -0.02 and 0.01 as a poly3 model
+    assert!((profile.distortion[2] + 0.005).abs() < 1e-6);
+    assert!((profile.distortion[0] - 1.005).abs() < 1e-6);
+    // the lens was calibrated on full frame
+    assert!((profile.scale - 1.0 / 1.6).abs() < 1e-6);
+    assert!((profile.tca[0][0] - 1.0002).abs() < 1e-6);
+    assert!((profile.tca[1][0] - 0.9998).abs() < 1e-6);
+  }
+
+  #[test]
+  fn interpolates_vignetting_in_stops() {
+    let profile = database().find(&info(24.0, 4.0), None).unwrap();
+    let t = (4.0f32.log2() - 2.8f32.log2()) / (5.6f32.log2() - 2.8f32.log2());
+    // the farthest distance is used, the one at 1 m is ignored
+    assert!((profile.vignetting[0] - (-0.5 + 0.3 * t)).abs() < 1e-5);
+    assert!((profile.vignetting[1] - 0.1 * (1.0 - t)).abs() < 1e-5);
+  }
+
+  #[test]
+  fn finds_lenses_by_their_words() {
+    let database = database();
+    assert!(database.find(&info(24.0, 2.8), None).is_some());
+    assert!(database
+      .find(&info(24.0, 2.8), Some("Canon EF 24-70mm f/2.8L II USM"))
+      .is_some());
+    assert!(database
+      .find(&info(24.0, 2.8), Some("EF 70-200mm f/2.8L"))
+      .is_none());
+    assert!(database.find(&info(24.0, 2.8), Some("")).is_none());
+    assert_eq!(
+      tokens("EF24-70mm f/2.8L"),
+      ["ef", "24-70", "mm", "f", "2.8", "l"]
+    );
+  }
+
+  #[test]
+  fn rejects_broken_xml() {
+    let mut database = database();
+    assert!(database.parse("<lensdatabase><lens>").is_err());
+    assert_eq!(database.lenses.len(), 1);
+  }
+
+  #[test]
+  fn skips_unreadable_files() {
+    let dir = std::env::temp_dir().join(format!("lensfun-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("broken.xml"), "<lensdatabase><lens>").unwrap();
+    std::fs::write(dir.join("canon.xml"), DATABASE).unwrap();
+    std::fs::write(dir.join("notes.txt"), "not a database").unwrap();
+
+    let database = LensDatabase::open(&dir);
+    std::fs::remove_dir_all(&dir).unwrap();
+    let database = database.unwrap();
+    assert_eq!(database.lenses.len(), 1);
+    assert_eq!(database.cameras.len(), 1);
+  }
+}
@@ -1,19 +1,128 @@
+mod calibration;
+mod color;
+mod color_curves;
+mod color_grading;
+mod color_space;
+mod curve;
+mod dcp;
+mod demosaic;
+mod detail;
+mod effects;
+mod filter;
+mod geometry;
+mod graph;
+mod icc;
+mod lens;
+mod lut;
+mod mask;
+mod monochrome;
+mod perspective;
+mod proof;
+mod reconstruction;
+mod texture;
+mod tiles;
+mod view_transform;
+mod warp;
+mod white_balance;
+
+pub use calibration::Calibration;
+pub use color_grading::Wheel;
+pub use color_space::OutputSpace;
+pub use demosaic::Demosaic;
+pub use geometry::{Crop, Geometry, View};
+pub use graph::{Node, Operation};
+pub use lens::{LensCorrection, LensInfo};
+pub use lut::{LutColorspace, LutInput, Transfer};
+pub use mask::{Mask, Range};
+pub use monochrome::{HueMixer, Toning};
+pub use perspective::{Perspective, PerspectiveMode};
+pub use proof::{ProofProfile, RenderingIntent, SoftProof};
+pub use reconstruction::HighlightReconstruction;
+pub use view_transform::ViewTransform;
+pub use warp::Warp;
+pub use white_balance::WhiteBalance;
+
 use anyhow::anyhow;
-use image::{DynamicImage, ImageBuffer};
-use image::{Pixel, Rgb};
+use dcp::CameraProfile;
+use demosaic::Pattern;
+use image::Rgb;
+use image::{imageops, DynamicImage, ImageBuffer};
+use lens::{LensDatabase, LensMap};
 use log::{error, info};
 use rawler::buffer::Buffer;
-use rawler::imgop::develop::RawDevelop;
+use rawler::formats::tiff::Rational;
+use rawler::imgop::develop::{Intermediate, ProcessingStep, RawDevelop};
+use rawler::imgop::xyz::Illuminant;
 use rawler::{
   decoders::{RawDecodeParams, RawMetadata},
-  get_decoder, RawFile,
+  get_decoder, RawFile, RawImage,
 };
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
 use tokio::fs::File;
 use tokio::io::{self, AsyncReadExt};
 
+/**
+ * Decoded image with what its metadata says about how it was taken.
+ * The image is scene-linear in the working space, white balanced and through the camera matrix
+ * but not clipped, 1.0 being the white level of the sensor.
+ */
+pub struct Source {
+  pub image: ImageBuffer<Rgb<f32>, Vec<f32>>,
+  pub lens: LensInfo,
+  pub as_shot: AsShot,
+  /**
+   * The image scaled down to `THUMBNAIL_SIZE`, for analysis that doesn't need every pixel.
+   * Renders of the source share it.
+   */
+  pub thumbnail: Arc<ImageBuffer<Rgb<f32>, Vec<f32>>>,
+  /**
+   * Pixels of the image per pixel of the sensor, below 1 for half size decodes
+   */
+  pub scale: f32,
+}
+
+/**
+ * How the camera recorded the colors of a source
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AsShot {
+  /**
+   * The light the camera balanced the image for
+   */
+  pub white_balance: WhiteBalance,
+  /**
+   * Matrix from the working space back to the camera's raw RGB, what camera profiles start from.
+   * None when rawler developed the image.
+   */
+  pub camera_from_working: Option<[[f32; 3]; 3]>,
+}
+
+/**
+ * Long side of the source's thumbnail
+ */
+const THUMBNAIL_SIZE: u32 = 1024;
+
+/**
+ * How the raw data is developed into the source image
+ */
+#[derive(Debug, Clone, Default)]
+pub struct DecodeOptions {
+  pub highlights: HighlightReconstruction,
+  pub demosaic: Demosaic,
+}
+
+/**
+ * The decoded image as sRGB, without any edits
+ */
 pub async fn get_image(path: &Path) -> anyhow::Result<DynamicImage> {
+  let mut image = get_source(path, &DecodeOptions::default()).await?.image;
+  from_working(&mut image);
+  Ok(DynamicImage::ImageRgb32F(image))
+}
+
+pub async fn get_source(path: &Path, options: &DecodeOptions) -> anyhow::Result<Source> {
   let mut file = File::open(&path).await.unwrap();
   let mut buffer = Vec::new();
   file.read_to_end(&mut buffer).await?;
@@ -34,31 +143,453 @@ pub async fn get_image(path: &Path) -> anyhow::Result<DynamicImage> {
   if let Ok(decoder) = get_decoder(&mut rawfile) {
     let rawimage = decoder.raw_image(&mut rawfile, params, false)?;
 
-    let dev = RawDevelop::default();
-    let mut img = dev
-      .develop_intermediate(&rawimage)
-      .unwrap()
-      .to_dynamic_image()
-      .unwrap();
-    // spits out a srg gamma 2.4 image
-
-    img = match metadata.unwrap().exif.orientation.unwrap() {
-      5 | 6 => img.rotate90(),
-      7 | 8 => img.rotate270(),
+    let (mut img, camera_from_srgb, scale) =
+      match (xyz_to_camera(&rawimage), Pattern::new(&rawimage.camera.cfa)) {
+        (Some(matrix), Some(pattern)) => {
+          let (mut img, scale) = develop_mosaic(&rawimage, &pattern, options.demosaic)?;
+          let camera =
+            develop_camera_rgb(&mut img, rawimage.wb_coeffs, &matrix, options.highlights);
+          (img, camera, scale)
+        }
+        // rawler does it all for sensors without a color matrix or a red, green and blue pattern
+        _ => (develop_rawler(&rawimage)?, None, 1.0),
+      };
+    // linear rec. 709 primaries from here
+
+    let metadata = metadata.unwrap();
+    img = match metadata.exif.orientation.unwrap() {
+      5 | 6 => imageops::rotate90(&img),
+      7 | 8 => imageops::rotate270(&img),
       _ => img,
     };
+    to_working(&mut img);
 
-    return Ok(img);
+    return Ok(Source {
+      thumbnail: Arc::new(thumbnail(&img)),
+      image: img,
+      lens: lens_info(&metadata),
+      scale,
+      as_shot: AsShot {
+        white_balance: as_shot_white_balance(&rawimage),
+        camera_from_working: camera_from_srgb.map(|matrix| {
+          mat_product(
+            &matrix,
+            &conversion(WORKING_COLORSPACE, kolor::spaces::LINEAR_SRGB),
+          )
+        }),
+      },
+    });
   }
 
   Err(anyhow!("Failed to get image"))
 }
 
+fn thumbnail(image: &ImageBuffer<Rgb<f32>, Vec<f32>>) -> ImageBuffer<Rgb<f32>, Vec<f32>> {
+  let factor = (THUMBNAIL_SIZE as f32 / image.width().max(image.height()) as f32).min(1.0);
+  let scaled = |size: u32| ((size as f32 * factor).round() as u32).max(1);
+  imageops::resize(
+    image,
+    scaled(image.width()),
+    scaled(image.height()),
+    imageops::FilterType::Triangle,
+  )
+}
+
 /**
- * relative changes to image properties
+ * Developed image and its pixels per sensor pixel
+ */
+type Developed = (ImageBuffer<Rgb<f32>, Vec<f32>>, f32);
+
+/**
+ * Demosaics the sensor data, white level scaled to 1, and crops it to the area that has image
+ */
+fn develop_mosaic(
+  rawimage: &RawImage,
+  pattern: &Pattern,
+  method: Demosaic,
+) -> anyhow::Result<Developed> {
+  let dev = RawDevelop {
+    steps: vec![ProcessingStep::Rescale],
+  };
+  let Intermediate::Monochrome(mosaic) = dev.develop_intermediate(rawimage)? else {
+    return Err(anyhow!("Rescaled sensor data is not a mosaic"));
+  };
+
+  let img = demosaic::demosaic(&mosaic.data, mosaic.width, mosaic.height, pattern, method);
+  // half size shrinks the sensor coordinates by the block size
+  let scale = img.width() as f32 / mosaic.width as f32;
+
+  let Some(area) = rawimage.crop_area.or(rawimage.active_area) else {
+    return Ok((img, scale));
+  };
+  let x = ((area.p.x as f32 * scale) as u32).min(img.width() - 1);
+  let y = ((area.p.y as f32 * scale) as u32).min(img.height() - 1);
+  let width = ((area.d.w as f32 * scale) as u32).clamp(1, img.width() - x);
+  let height = ((area.d.h as f32 * scale) as u32).clamp(1, img.height() - y);
+  Ok((
+    imageops::crop_imm(&img, x, y, width, height).to_image(),
+    scale,
+  ))
+}
+
+/**
+ * Every step of rawler but the sRGB gamma, which would quantize and clip the highlights
+ */
+fn develop_rawler(rawimage: &RawImage) -> anyhow::Result<ImageBuffer<Rgb<f32>, Vec<f32>>> {
+  let dev = RawDevelop {
+    steps: RawDevelop::default()
+      .steps
+      .into_iter()
+      .filter(|step| !matches!(step, ProcessingStep::SRgb))
+      .collect(),
+  };
+  match dev.develop_intermediate(rawimage)? {
+    Intermediate::ThreeColor(rgb) => {
+      let data = rgb.data.into_iter().flatten().collect();
+      ImageBuffer::from_raw(rgb.width as u32, rgb.height as u32, data)
+    }
+    Intermediate::Monochrome(mono) => {
+      let data = mono.data.into_iter().flat_map(|v| [v; 3]).collect();
+      ImageBuffer::from_raw(mono.width as u32, mono.height as u32, data)
+    }
+    Intermediate::FourColor(_) => return Err(anyhow!("Four color images are not supported")),
+  }
+  .ok_or(anyhow!("Developed image does not match its size"))
+}
+
+/**
+ * The camera's matrix from XYZ to its RGB, preferably the one for daylight
+ */
+fn xyz_to_camera(rawimage: &RawImage) -> Option<[[f32; 3]; 3]> {
+  let m = rawimage
+    .color_matrix
+    .get(&Illuminant::D65)
+    .or(rawimage.color_matrix.values().next())
+    .filter(|matrix| matrix.len() >= 9)?;
+  Some([[m[0], m[1], m[2]], [m[3], m[4], m[5]], [m[6], m[7], m[8]]])
+}
+
+/**
+ * White balances camera RGB with the as shot multipliers, reconstructs the clipped highlights
+ * and converts it to linear sRGB with the camera matrix.
+ * Returns the matrix from linear sRGB back to the raw camera RGB.
+ */
+fn develop_camera_rgb(
+  image: &mut ImageBuffer<Rgb<f32>, Vec<f32>>,
+  multipliers: [f32; 4],
+  xyz_to_camera: &[[f32; 3]; 3],
+  highlights: HighlightReconstruction,
+) -> Option<[[f32; 3]; 3]> {
+  let [r, g, b, _] = multipliers;
+  let white_balance = if [r, g, b].iter().all(|m| m.is_finite() && *m > 0.0) {
+    [r / g, 1.0, b / g]
+  } else {
+    [1.0; 3]
+  };
+  for pixel in image.pixels_mut() {
+    for (value, multiplier) in pixel.0.iter_mut().zip(white_balance) {
+      *value *= multiplier;
+    }
+  }
+
+  // rawler rescales the white level of the sensor to 1, so each channel clipped at its multiplier
+  reconstruction::reconstruct(image, highlights, white_balance);
+
+  // the camera RGB of the sRGB primaries, each row normalized so white stays white
+  let mut camera_from_srgb = mat_product(xyz_to_camera, &white_balance::SRGB_TO_XYZ);
+  for row in camera_from_srgb.iter_mut() {
+    let sum: f32 = row.iter().sum();
+    if sum != 0.0 {
+      *row = row.map(|v| v / sum);
+    }
+  }
+  let Some(srgb_from_camera) = mat_inverse(&camera_from_srgb) else {
+    error!("Camera matrix can not be inverted");
+    return None;
+  };
+
+  for pixel in image.pixels_mut() {
+    pixel.0 = mat_mul(&srgb_from_camera, &pixel.0);
+  }
+
+  // and the multipliers undone
+  Some([0, 1, 2].map(|c| camera_from_srgb[c].map(|v| v / white_balance[c])))
+}
+
+/**
+ * As shot white balance from the camera's multipliers, daylight when the camera has no color matrix
+ */
+fn as_shot_white_balance(rawimage: &RawImage) -> WhiteBalance {
+  let [r, g, b, _] = rawimage.wb_coeffs;
+  xyz_to_camera(rawimage)
+    .and_then(|matrix| WhiteBalance::from_camera([r, g, b], &matrix))
+    .unwrap_or_default()
+}
+
+fn lens_info(metadata: &RawMetadata) -> LensInfo {
+  let rational = |value: Option<Rational>| match value {
+    Some(value) if value.d != 0 => value.n as f32 / value.d as f32,
+    _ => 0.0,
+  };
+
+  LensInfo {
+    camera_make: metadata.make.clone(),
+    camera_model: metadata.model.clone(),
+    lens_make: metadata.exif.lens_make.clone().unwrap_or_default(),
+    lens_model: metadata.exif.lens_model.clone().unwrap_or_default(),
+    focal_length: rational(metadata.exif.focal_length),
+    aperture: rational(metadata.exif.fnumber),
+  }
+}
+
+/**
+ * The lensfun database, read once from the directory in LENSFUN_DATABASE. Without it the first
+ * of data/lensfun next to the executable, lensfun's own install and ./data/lensfun that exists.
+ */
+fn lens_database() -> Option<&'static LensDatabase> {
+  static DATABASE: OnceLock<Option<LensDatabase>> = OnceLock::new();
+  DATABASE
+    .get_or_init(|| {
+      let dir = lens_database_dir()?;
+      info!("Lens database from {:?}", dir);
+      match LensDatabase::open(&dir) {
+        Ok(database) => Some(database),
+        Err(err) => {
+          error!("Failed to load lens database: {}", err);
+          None
+        }
+      }
+    })
+    .as_ref()
+}
+
+fn lens_database_dir() -> Option<PathBuf> {
+  if let Ok(dir) = std::env::var("LENSFUN_DATABASE") {
+    return Some(PathBuf::from(dir));
+  }
+
+  let beside_executable = std::env::current_exe()
+    .ok()
+    .and_then(|exe| Some(exe.parent()?.join("data/lensfun")));
+  let mut candidates = beside_executable.into_iter().chain(
+    [
+      "/usr/share/lensfun/version_1",
+      "/usr/local/share/lensfun/version_1",
+      "./data/lensfun",
+    ]
+    .map(PathBuf::from),
+  );
+  let dir = candidates.find(|dir| dir.is_dir());
+  if dir.is_none() {
+    error!("No lens database found, set LENSFUN_DATABASE to lensfun's data/db");
+  }
+  dir
+}
+
+/**
+ * Processing graph of an image, an ordered list of adjustments applied in the working space
  */
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Edits {
+  pub nodes: Vec<Node>,
+  #[serde(default)]
+  pub geometry: Geometry,
+  #[serde(default)]
+  pub lens: LensCorrection,
+  /**
+   * Path of a DNG camera profile (.dcp) the image is rendered with
+   */
+  #[serde(default)]
+  pub camera_profile: Option<String>,
+  #[serde(default)]
+  pub calibration: Calibration,
+  /**
+   * None keeps the white balance the camera recorded
+   */
+  #[serde(default)]
+  pub white_balance: Option<WhiteBalance>,
+  #[serde(default)]
+  pub highlight_reconstruction: HighlightReconstruction,
+  #[serde(default)]
+  pub view_transform: ViewTransform,
+}
+
+impl Default for Edits {
+  fn default() -> Edits {
+    Edits::new()
+  }
+}
+
+impl Edits {
+  pub fn new() -> Edits {
+    FlatEdits::new().into()
+  }
+
+  /**
+   * Reads edits stored as a graph or in the flat format used before nodes existed
+   */
+  pub fn from_json(str: String) -> Edits {
+    let edits: Edits = serde_json::from_str(&str)
+      .or_else(|_| serde_json::from_str::<FlatEdits>(&str).map(Edits::from))
+      .expect("Failed to parse edits");
+    edits
+  }
+
+  /**
+   * The part of the edits that decides how the raw data is developed, see `get_source`
+   */
+  pub fn decode_options(&self) -> DecodeOptions {
+    DecodeOptions {
+      highlights: self.highlight_reconstruction,
+      demosaic: Demosaic::default(),
+    }
+  }
+
+  /**
+   * The white balance to render with, the absolute one or else the as shot light
+   * with the relative white balance nodes of older edits on top
+   */
+  pub fn resolved_white_balance(&self, as_shot: &WhiteBalance) -> WhiteBalance {
+    if let Some(white_balance) = self.white_balance {
+      return white_balance;
+    }
+
+    self
+      .active_nodes()
+      .iter()
+      .fold(*as_shot, |white_balance, node| match node.operation {
+        Operation::WhiteBalance { temperature, tint } if temperature != 0.0 || tint != 0.0 => {
+          WhiteBalance::from_relative(temperature, tint, &white_balance)
+        }
+        _ => white_balance,
+      })
+  }
+
+  /**
+   * Seeds the grain nodes that have no seed yet, pass something unique to the image
+   */
+  pub fn with_seed(mut self, seed: u64) -> Edits {
+    for node in self.nodes.iter_mut() {
+      if let Operation::Grain {
+        seed: grain_seed, ..
+      } = &mut node.operation
+      {
+        grain_seed.get_or_insert(seed);
+      }
+    }
+    self
+  }
+
+  /**
+   * Resolves the automatic black and white mixes into their mixers. The mix is found in the
+   * source's thumbnail rendered up to the node, so every region and zoom gets the same one.
+   */
+  pub fn with_auto_mix(
+    mut self,
+    thumbnail: &ImageBuffer<Rgb<f32>, Vec<f32>>,
+    as_shot: &AsShot,
+  ) -> Edits {
+    let is_auto =
+      |node: &Node| matches!(node.operation, Operation::BlackAndWhite { auto: true, .. });
+    let count = self
+      .active_nodes()
+      .into_iter()
+      .filter(|n| is_auto(n))
+      .count();
+    if count == 0 {
+      return self;
+    }
+
+    let mut image = thumbnail.clone();
+    let profile = camera_profile(&self);
+    develop(&mut image, &self, as_shot, profile.as_deref());
+    let mut mixes = Vec::new();
+    for node in self.active_nodes() {
+      if is_auto(node) {
+        let index = self.nodes.iter().position(|n| std::ptr::eq(n, node));
+        mixes.extend(index.map(|index| (index, HueMixer::auto(&image))));
+        if mixes.len() == count {
+          break;
+        }
+      }
+      node.apply(&mut image);
+    }
+
+    for (index, mix) in mixes {
+      if let Operation::BlackAndWhite { mixer, auto, .. } = &mut self.nodes[index].operation {
+        *mixer = mix;
+        *auto = false;
+      }
+    }
+    self
+  }
+
+  /**
+   * Enabled nodes in the order they are applied, without the detail nodes
+   */
+  pub fn active_nodes(&self) -> Vec<&Node> {
+    self.ordered_nodes(|node| !node.operation.is_detail())
+  }
+
+  /**
+   * Identifies what `warp` and `process_detail` make of a source,
+   * renders with the same key can share that and only run `process`
+   */
+  pub fn frame_key(&self) -> String {
+    let frame = (
+      &self.highlight_reconstruction,
+      &self.lens,
+      &self.geometry,
+      self.detail_nodes(),
+    );
+    serde_json::to_string(&frame).unwrap_or_default()
+  }
+
+  /**
+   * Pixels around a region of the frame the nodes read from, in pixels of the full resolution
+   * frame. The detail nodes run at full resolution, the others at the view's scale.
+   */
+  pub fn padding(&self, view: &View) -> u32 {
+    let full = View {
+      scale: 1.0,
+      ..*view
+    };
+    let detail: f32 = self
+      .detail_nodes()
+      .iter()
+      .map(|node| node.operation.reach(&full))
+      .sum();
+    let nodes: f32 = self
+      .active_nodes()
+      .iter()
+      .map(|node| node.operation.reach(view))
+      .sum();
+    (detail + nodes / view.scale.max(1e-3)).ceil() as u32
+  }
+
+  /**
+   * Enabled sharpening and noise reduction nodes in the order they are applied
+   */
+  pub fn detail_nodes(&self) -> Vec<&Node> {
+    self.ordered_nodes(|node| node.operation.is_detail())
+  }
+
+  fn ordered_nodes(&self, filter: impl Fn(&Node) -> bool) -> Vec<&Node> {
+    let mut nodes: Vec<&Node> = self
+      .nodes
+      .iter()
+      .filter(|node| node.enabled && filter(node))
+      .collect();
+    nodes.sort_by_key(|node| node.order);
+    nodes
+  }
+}
+
+/**
+ * relative changes to image properties
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FlatEdits {
   pub exposure: f32,
   pub contrast: f32,
   pub temperature: f32,
@@ -76,12 +607,18 @@ pub struct Edits {
   pub curve_blue: Vec<(f32, f32)>,
 }
 
-impl Edits {
-  pub fn new() -> Edits {
-    Edits {
+impl Default for FlatEdits {
+  fn default() -> FlatEdits {
+    FlatEdits::new()
+  }
+}
+
+impl FlatEdits {
+  pub fn new() -> FlatEdits {
+    FlatEdits {
       exposure: 0.4,
       contrast: 0.03,
-      temperature: 0.15,
+      temperature: 0.0,
       tint: 0.0,
       highlights: -0.22,
       shadows: 0.15,
@@ -96,13 +633,83 @@ impl Edits {
       curve_blue: vec![],
     }
   }
+}
 
-  pub fn from_json(str: String) -> Edits {
-    let edits: Edits = serde_json::from_str(&str).expect("Failed to parse edits");
-    edits
+/**
+ * The flat edits as a graph, in the order the fixed pipeline used to apply them
+ */
+impl From<FlatEdits> for Edits {
+  fn from(flat: FlatEdits) -> Edits {
+    let mut nodes = vec![
+      Operation::Exposure {
+        amount: flat.exposure,
+      },
+      Operation::Contrast {
+        amount: flat.contrast,
+      },
+      Operation::Highlights {
+        amount: flat.highlights,
+      },
+      Operation::Shadows {
+        amount: flat.shadows,
+      },
+      Operation::Blacks {
+        amount: flat.blacks,
+      },
+      Operation::Whites {
+        amount: flat.whites,
+      },
+      Operation::Texture {
+        amount: flat.texture,
+      },
+      Operation::Vibrancy {
+        amount: flat.vibrancy,
+      },
+      Operation::Saturation {
+        amount: flat.saturation,
+      },
+      Operation::Curves {
+        tone: flat.curve_tone,
+        red: flat.curve_red,
+        green: flat.curve_green,
+        blue: flat.curve_blue,
+      },
+    ];
+    // kept relative, the as shot light it applies to is only known when rendering
+    if flat.temperature != 0.0 || flat.tint != 0.0 {
+      nodes.insert(
+        0,
+        Operation::WhiteBalance {
+          temperature: flat.temperature,
+          tint: flat.tint,
+        },
+      );
+    }
+
+    Edits {
+      nodes: nodes.into_iter().map(Node::new).collect(),
+      geometry: Geometry::default(),
+      lens: LensCorrection::default(),
+      camera_profile: None,
+      calibration: Calibration::default(),
+      white_balance: None,
+      highlight_reconstruction: HighlightReconstruction::default(),
+      view_transform: ViewTransform::default(),
+    }
   }
 }
 
+/**
+ * The matrix of a conversion between two linear color spaces, so it is set up once per render
+ * instead of going through kolor for every pixel
+ */
+fn conversion(from: kolor::ColorSpace, to: kolor::ColorSpace) -> [[f32; 3]; 3] {
+  let conversion = kolor::ColorConversion::new(from, to);
+  let columns: [[f32; 3]; 3] = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]
+    .map(|primary: [f32; 3]| conversion.convert(primary.into()).into());
+  [0, 1, 2].map(|row| [0, 1, 2].map(|col| columns[col][row]))
+}
+
 fn mat_mul(matrix: &[[f32; 3]; 3], vector: &[f32; 3]) -> [f32; 3] {
   let mut result = [0.0; 3];
   for i in 0..3 {
@@ -113,6 +720,44 @@ fn mat_mul(matrix: &[[f32; 3]; 3], vector: &[f32; 3]) -> [f32; 3] {
   result
 }
 
+fn mat_product(a: &[[f32; 3]; 3], b: &[[f32; 3]; 3]) -> [[f32; 3]; 3] {
+  let mut result = [[0.0; 3]; 3];
+  for i in 0..3 {
+    for j in 0..3 {
+      for k in 0..3 {
+        result[i][j] += a[i][k] * b[k][j];
+      }
+    }
+  }
+  result
+}
+
+fn mat_inverse(m: &[[f32; 3]; 3]) -> Option<[[f32; 3]; 3]> {
+  let [[a, b, c], [d, e, f], [g, h, i]] = *m;
+  let det = a * (e * i - f * h) - b * (d * i - f * g) + c * (d * h - e * g);
+  if det.abs() < 1e-9 {
+    return None;
+  }
+
+  Some([
+    [
+      (e * i - f * h) / det,
+      (c * h - b * i) / det,
+      (b * f - c * e) / det,
+    ],
+    [
+      (f * g - d * i) / det,
+      (a * i - c * g) / det,
+      (c * d - a * f) / det,
+    ],
+    [
+      (d * h - e * g) / det,
+      (b * g - a * h) / det,
+      (a * e - b * d) / det,
+    ],
+  ])
+}
+
 fn exposure(rgb: [f32; 3], exposure: f32) -> [f32; 3] {
   let mut color = rgb;
   color[0] = color[0] * (1.0 + exposure);
@@ -129,6 +774,101 @@ fn contrast(rgb: [f32; 3], contrast: f32) -> [f32; 3] {
   color
 }
 
+/**
+ * Luminance weights of the ACES2065-1 (AP0) primaries
+ */
+const AP0_LUMINANCE: [f32; 3] = [0.3439664, 0.7281661, -0.0721325];
+const MIDDLE_GREY: f32 = 0.18;
+
+fn luminance(rgb: [f32; 3]) -> f32 {
+  AP0_LUMINANCE[0] * rgb[0] + AP0_LUMINANCE[1] * rgb[1] + AP0_LUMINANCE[2] * rgb[2]
+}
+
+/**
+ * Distance of the color's luminance from middle grey in stops
+ */
+fn stops(rgb: [f32; 3]) -> f32 {
+  (luminance(rgb).max(1e-6) / MIDDLE_GREY).log2()
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+  let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+  t * t * (3.0 - 2.0 * t)
+}
+
+/**
+ * Scales the color by a number of stops, keeping the ratio between channels (and so the hue)
+ */
+fn gain(rgb: [f32; 3], amount: f32) -> [f32; 3] {
+  let factor = amount.exp2();
+  [rgb[0] * factor, rgb[1] * factor, rgb[2] * factor]
+}
+
+/**
+ * Moves the luminance of the color to `target`, keeping the ratio between channels
+ */
+fn relight(rgb: [f32; 3], target: f32) -> [f32; 3] {
+  let y = luminance(rgb);
+  if y <= 0.0 {
+    return rgb;
+  }
+  let factor = target / y;
+  [rgb[0] * factor, rgb[1] * factor, rgb[2] * factor]
+}
+
+/**
+ * Everything above middle grey, up to 1.5 stops at full strength.
+ * Recovering keeps pulling on the brightest values so clipped highlights come back into range,
+ * brightening fades out again towards the top so bright values are not pushed into clipping.
+ */
+fn highlights(rgb: [f32; 3], highlights: f32) -> [f32; 3] {
+  let ev = stops(rgb);
+  let weight = if highlights < 0.0 {
+    smoothstep(-0.5, 2.5, ev)
+  } else {
+    smoothstep(-0.5, 1.5, ev) * (1.0 - smoothstep(2.0, 5.0, ev))
+  };
+  gain(rgb, highlights * 1.5 * weight)
+}
+
+/**
+ * Everything below middle grey, up to 1.5 stops at full strength.
+ * Centered 2.5 stops under middle grey, fading out towards black so the black point stays put.
+ */
+fn shadows(rgb: [f32; 3], shadows: f32) -> [f32; 3] {
+  let distance = (stops(rgb) + 2.5) / 2.0;
+  let weight = (-distance * distance).exp();
+  gain(rgb, shadows * 1.5 * weight)
+}
+
+/**
+ * Black point, with a soft toe instead of an offset, so crushed blacks never go negative
+ */
+fn blacks(rgb: [f32; 3], blacks: f32) -> [f32; 3] {
+  let y = luminance(rgb);
+  if y <= 0.0 || blacks == 0.0 {
+    return rgb;
+  }
+
+  let toe = blacks.abs() * 0.02;
+  let target = if blacks < 0.0 {
+    y * y / (y + toe)
+  } else {
+    // the lift fades out before middle grey, its slope never exceeds 1 for toe <= 0.02
+    y + toe * (1.0 - smoothstep(0.0, MIDDLE_GREY, y))
+  };
+
+  relight(rgb, target)
+}
+
+/**
+ * White point, moves the brightest values up to one stop without touching the midtones
+ */
+fn whites(rgb: [f32; 3], whites: f32) -> [f32; 3] {
+  let weight = smoothstep(0.0, 4.0, stops(rgb));
+  gain(rgb, whites * weight)
+}
+
 fn saturation(color: [f32; 3], saturation: f32) -> [f32; 3] {
   let [r, g, b] = color;
 
@@ -153,69 +893,213 @@ fn vibrancy(color: [f32; 3], vibrancy: f32) -> [f32; 3] {
   ]
 }
 
+const WORKING_COLORSPACE: kolor::ColorSpace = kolor::spaces::ACES2065_1;
+
 /**
- * Color temperature acording to CIE 1960 color space
+ * Lens correction and geometry of the edits for the source, render the frame or a region of it
+ * from the full resolution image so crops keep all their detail
  */
-fn temprature(color: [f32; 3], temp: f32, tint: f32) -> [f32; 3] {
-  let [x, y, z] = color;
+pub fn warp(source: &Source, paramters: &Edits) -> Warp {
+  let correction = &paramters.lens;
+  let profile = if correction.profile {
+    lens_database().and_then(|db| db.find(&source.lens, correction.lens.as_deref()))
+  } else {
+    None
+  };
+  let lens = match profile.is_none() && correction.is_manual_identity() {
+    true => None,
+    false => Some(LensMap::new(profile.as_ref(), correction)),
+  };
 
-  let mut u = (2.0 / 3.0) * x;
-  let mut v = y;
-  let mut w = (1.0 / 2.0) * (-x + 3.0 * y + z);
+  Warp::new(
+    source.image.width(),
+    source.image.height(),
+    lens,
+    &paramters.geometry,
+    &source.thumbnail,
+  )
+}
 
-  u = u * (1.0 + tint);
-  w = w * (1.0 + temp);
+/**
+ * Applies the sharpening and noise reduction nodes.
+ * Their radii are in pixels of the sensor, so this runs on the image from `get_source` with its
+ * `Source::scale`, before it is scaled down and handed to `process`.
+ */
+pub fn process_detail(
+  source: ImageBuffer<Rgb<f32>, Vec<f32>>,
+  paramters: &Edits,
+  scale: f32,
+) -> ImageBuffer<Rgb<f32>, Vec<f32>> {
+  let nodes = paramters.detail_nodes();
+  if nodes.is_empty() {
+    return source;
+  }
 
-  let X = (3.0 / 2.0) * u;
-  let Y = v;
-  let Z = (3.0 / 2.0) * u - 3.0 * v + 2.0 * w;
+  let mut source = source;
+  for node in nodes {
+    node.apply_detail(&mut source, scale);
+  }
 
-  [X, Y, Z]
+  source
 }
 
+/**
+ * Renders the scene-linear image from `get_source` with the white balance, profile and nodes,
+ * the result is display encoded sRGB
+ */
 pub fn process(
   source: ImageBuffer<Rgb<f32>, Vec<f32>>,
   paramters: &Edits,
+  as_shot: &AsShot,
+) -> ImageBuffer<Rgb<f32>, Vec<f32>> {
+  let view = View::full(source.width(), source.height());
+  process_view(source, paramters, as_shot, &view, OutputSpace::Srgb, None)
+}
+
+/**
+ * Renders a region of the frame like `process`, so vignette, grain and texture line up with the
+ * whole frame. Pad the region by `Edits::padding` and cut the padding off afterwards.
+ * The result is display encoded in the output space, soft-proofed first when a proof is given.
+ */
+pub fn process_view(
+  source: ImageBuffer<Rgb<f32>, Vec<f32>>,
+  paramters: &Edits,
+  as_shot: &AsShot,
+  view: &View,
+  output: OutputSpace,
+  proof: Option<&SoftProof>,
 ) -> ImageBuffer<Rgb<f32>, Vec<f32>> {
   let mut source = source;
 
-  for pixel in source.pixels_mut() {
-    let out = pixel.channels_mut();
-    let linear_srgb = srgb::gamma::linear_from_normalised([out[0], out[1], out[2]]);
+  let profile = camera_profile(paramters);
+  develop(&mut source, paramters, as_shot, profile.as_deref());
+  for node in paramters.active_nodes() {
+    node.apply_view(&mut source, view);
+  }
+  if let Some(profile) = &profile {
+    profile.apply_tone_curve(&mut source);
+  }
+  view_transform::apply(&mut source, paramters.view_transform);
+  if let Some(proof) = proof {
+    proof::soft_proof(&mut source, proof);
+  }
+  color_space::encode(&mut source, output);
+
+  source
+}
+
+/**
+ * Eyedropper, the white balance that makes the region neutral.
+ * Pass the frame rendered by `warp`, so the region is relative to the edited frame.
+ */
+pub fn sample_white_balance(
+  source: &ImageBuffer<Rgb<f32>, Vec<f32>>,
+  region: &Crop,
+  as_shot: &WhiteBalance,
+) -> WhiteBalance {
+  white_balance::neutral(source, region, as_shot)
+}
+
+fn camera_profile(paramters: &Edits) -> Option<Arc<CameraProfile>> {
+  let path = paramters.camera_profile.as_ref()?;
+  match CameraProfile::cached(Path::new(path)) {
+    Ok(profile) => Some(profile),
+    Err(err) => {
+      error!("Failed to load camera profile {}: {}", path, err);
+      None
+    }
+  }
+}
+
+/**
+ * The camera rendering the nodes start from, the white balance and camera profile
+ * and then the primary calibration
+ */
+fn develop(
+  source: &mut ImageBuffer<Rgb<f32>, Vec<f32>>,
+  paramters: &Edits,
+  as_shot: &AsShot,
+  profile: Option<&CameraProfile>,
+) {
+  let white_balance = paramters.resolved_white_balance(&as_shot.white_balance);
 
-    // in
+  // from the camera's raw colors the profile renders the white balance itself
+  let camera = as_shot
+    .camera_from_working
+    .as_ref()
+    .filter(|_| profile.is_some());
+  if camera.is_none() && white_balance != as_shot.white_balance {
+    white_balance::apply(source, &white_balance, &as_shot.white_balance);
+  }
+  if let Some(profile) = profile {
+    if let Err(err) = profile.apply(source, &white_balance, camera) {
+      error!("Failed to apply camera profile: {}", err);
+    }
+  }
 
-    let source_colorspace = kolor::spaces::LINEAR_SRGB;
-    let working_colorspace = kolor::spaces::ACES2065_1;
-    let target_colorspace = kolor::spaces::LINEAR_SRGB;
+  if !paramters.calibration.is_identity() {
+    calibration::apply(source, &paramters.calibration);
+  }
+}
 
-    let conversion_source = kolor::ColorConversion::new(source_colorspace, kolor::spaces::CIE_XYZ);
-    let mut xyz = conversion_source.convert(linear_srgb.into());
+/**
+ * Linear sRGB, as rawler develops it, into the working space
+ */
+fn to_working(source: &mut ImageBuffer<Rgb<f32>, Vec<f32>>) {
+  let matrix = conversion(kolor::spaces::LINEAR_SRGB, WORKING_COLORSPACE);
+  tiles::apply_matrix(source, &matrix);
+}
 
-    xyz = temprature(xyz.clone().into(), paramters.temperature, paramters.tint).into();
+fn from_working(source: &mut ImageBuffer<Rgb<f32>, Vec<f32>>) {
+  color_space::encode(source, OutputSpace::Srgb);
+}
 
-    let conversion_source = kolor::ColorConversion::new(kolor::spaces::CIE_XYZ, working_colorspace);
-    let mut aces = conversion_source.convert(xyz.into());
+/**
+ * ICC profile describing images rendered for an output space
+ */
+pub fn icc_profile(space: OutputSpace) -> Vec<u8> {
+  icc::profile(space)
+}
 
-    aces = exposure(aces.clone().into(), paramters.exposure).into();
-    aces = contrast(aces.clone().into(), paramters.contrast).into();
-    // highlights
-    // shadows
-    // blacks
-    // whites
-    aces = vibrancy(aces.clone().into(), paramters.vibrancy).into();
-    aces = saturation(aces.clone().into(), paramters.saturation).into();
+/**
+ * Writes a rendered image as JPEG or PNG with the profile of its output space embedded
+ */
+pub fn write_image(image: &image::RgbImage, path: &Path, space: OutputSpace) -> anyhow::Result<()> {
+  icc::write(image, path, space)
+}
 
-    let conversion_target = kolor::ColorConversion::new(working_colorspace, target_colorspace);
-    let linear_srgb = conversion_target.convert(aces);
+#[cfg(test)]
+mod tests {
+  use super::*;
 
-    // out
+  #[test]
+  fn auto_mix_is_the_same_for_every_region() {
+    // dark blue on the left half and bright yellow on the right
+    let from_display = conversion(kolor::spaces::LINEAR_SRGB, WORKING_COLORSPACE);
+    let image = ImageBuffer::from_fn(64, 32, |x, _| match x < 32 {
+      true => Rgb(mat_mul(&from_display, &[0.02, 0.03, 0.3])),
+      false => Rgb(mat_mul(&from_display, &[0.8, 0.7, 0.05])),
+    });
+    let as_shot = AsShot {
+      white_balance: WhiteBalance::default(),
+      camera_from_working: None,
+    };
+    let edits = Edits::from_json(r#"{"nodes":[{"type":"black_and_white","auto":true}]}"#.into())
+      .with_auto_mix(&thumbnail(&image), &as_shot);
+    let Operation::BlackAndWhite { mixer, auto, .. } = edits.nodes[0].operation else {
+      panic!("{:?}", edits.nodes[0]);
+    };
+    assert!(!auto);
+    assert!(mixer.blue < 0.0, "{mixer:?}");
 
-    let srgb_out = srgb::gamma::normalised_from_linear(linear_srgb);
-    out[0] = srgb_out[0];
-    out[1] = srgb_out[1];
-    out[2] = srgb_out[2];
+    let full = process(image.clone(), &edits, &as_shot);
+    // only the blue half in view
+    let region = imageops::crop_imm(&image, 0, 0, 32, 32).to_image();
+    let view = View {
+      frame_width: 64.0,
+      ..View::full(32, 32)
+    };
+    let region = process_view(region, &edits, &as_shot, &view, OutputSpace::Srgb, None);
+    assert_eq!(region.get_pixel(5, 5), full.get_pixel(5, 5));
   }
-
-  return source;
 }
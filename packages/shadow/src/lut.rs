@@ -0,0 +1,485 @@
+use crate::color::{acescct_from_linear, linear_from_acescct};
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::SystemTime;
+
+/**
+ * Color space and transfer function the LUT expects its input in.
+ * The output of the LUT is read back in the same encoding.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LutInput {
+  #[serde(default)]
+  pub colorspace: LutColorspace,
+  #[serde(default)]
+  pub transfer: Transfer,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LutColorspace {
+  #[default]
+  Srgb,
+  Rec2020,
+  Aces,
+  AcesCg,
+}
+
+impl LutColorspace {
+  pub fn kolor(&self) -> kolor::ColorSpace {
+    match self {
+      LutColorspace::Srgb => kolor::spaces::LINEAR_SRGB,
+      LutColorspace::Rec2020 => kolor::spaces::BT_2020,
+      LutColorspace::Aces => kolor::spaces::ACES2065_1,
+      LutColorspace::AcesCg => kolor::spaces::ACES_CG,
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Transfer {
+  Linear,
+  #[default]
+  Srgb,
+  AcesCct,
+}
+
+impl Transfer {
+  pub fn encode(&self, linear: [f32; 3]) -> [f32; 3] {
+    match self {
+      Transfer::Linear => linear,
+      Transfer::Srgb => srgb::gamma::normalised_from_linear(linear),
+      Transfer::AcesCct => linear.map(acescct_from_linear),
+    }
+  }
+
+  pub fn decode(&self, encoded: [f32; 3]) -> [f32; 3] {
+    match self {
+      Transfer::Linear => encoded,
+      Transfer::Srgb => srgb::gamma::linear_from_normalised(encoded),
+      Transfer::AcesCct => encoded.map(linear_from_acescct),
+    }
+  }
+}
+
+/**
+ * Largest table size read, 256 entries per side is already a 200 MB table
+ */
+const MAX_SIZE: usize = 256;
+
+/**
+ * A 3D lookup table, stored with red changing fastest like in .cube files
+ */
+pub struct Lut3d {
+  size: usize,
+  domain_min: [f32; 3],
+  domain_max: [f32; 3],
+  table: Vec<[f32; 3]>,
+}
+
+/**
+ * Parsed LUTs by path, with the modification time of the file they were read from
+ */
+type Luts = HashMap<PathBuf, (SystemTime, Arc<Lut3d>)>;
+
+fn luts() -> &'static Mutex<Luts> {
+  static LUTS: OnceLock<Mutex<Luts>> = OnceLock::new();
+  LUTS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+impl Lut3d {
+  /**
+   * Like `open`, but keeps the parsed LUT until the file changes
+   */
+  pub fn cached(path: &Path) -> anyhow::Result<Arc<Lut3d>> {
+    let modified = std::fs::metadata(path)?.modified()?;
+    if let Some((time, lut)) = luts().lock().unwrap().get(path) {
+      if *time == modified {
+        return Ok(lut.clone());
+      }
+    }
+
+    let lut = Arc::new(Lut3d::open(path)?);
+    luts()
+      .lock()
+      .unwrap()
+      .insert(path.to_path_buf(), (modified, lut.clone()));
+    Ok(lut)
+  }
+
+  /**
+   * Reads a .cube or .3dl file, picked by the file extension
+   */
+  pub fn open(path: &Path) -> anyhow::Result<Lut3d> {
+    let text = std::fs::read_to_string(path)?;
+    let extension = path
+      .extension()
+      .and_then(|ext| ext.to_str())
+      .map(|ext| ext.to_lowercase());
+
+    match extension.as_deref() {
+      Some("cube") => Lut3d::parse_cube(&text),
+      Some("3dl") => Lut3d::parse_3dl(&text),
+      _ => Err(anyhow!("Unsupported LUT format {:?}", path)),
+    }
+  }
+
+  /**
+   * Adobe / Resolve .cube, only 3D tables
+   */
+  pub fn parse_cube(text: &str) -> anyhow::Result<Lut3d> {
+    let mut size = 0;
+    let mut domain_min = [0.0; 3];
+    let mut domain_max = [1.0; 3];
+    let mut table = Vec::new();
+
+    for line in text.lines() {
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('#') || line.starts_with("TITLE") {
+        continue;
+      }
+
+      let mut words = line.split_whitespace();
+      match words.next() {
+        Some("LUT_3D_SIZE") => {
+          size = words.next().unwrap_or("").parse()?;
+          if size > MAX_SIZE {
+            return Err(anyhow!("LUT size {} is larger than {}", size, MAX_SIZE));
+          }
+        }
+        Some("LUT_1D_SIZE") => return Err(anyhow!("1D LUTs are not supported")),
+        Some("DOMAIN_MIN") => domain_min = parse_triplet(words)?,
+        Some("DOMAIN_MAX") => domain_max = parse_triplet(words)?,
+        Some(keyword) if keyword.starts_with(|c: char| c.is_ascii_alphabetic()) => {
+          // unknown keywords like LUT_3D_INPUT_RANGE are ignored
+        }
+        _ => table.push(parse_triplet(line.split_whitespace())?),
+      }
+    }
+
+    if size < 2 || table.len() != size * size * size {
+      return Err(anyhow!(
+        "Expected {} entries in LUT, found {}",
+        size * size * size,
+        table.len()
+      ));
+    }
+
+    Ok(Lut3d {
+      size,
+      domain_min,
+      domain_max,
+      table,
+    })
+  }
+
+  /**
+   * Autodesk / Lustre .3dl, integer tables with blue changing fastest.
+   * The optional first row lists the input mesh points, the output bit depth is read from a
+   * "Mesh" header or guessed from the largest value.
+   */
+  pub fn parse_3dl(text: &str) -> anyhow::Result<Lut3d> {
+    let mut output_bits: Option<u32> = None;
+    let mut lines: Vec<(&str, Vec<f32>)> = Vec::new();
+
+    for line in text.lines() {
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('#') {
+        continue;
+      }
+
+      let words: Vec<&str> = line.split_whitespace().collect();
+      if words[0].eq_ignore_ascii_case("mesh") {
+        output_bits = words.get(2).and_then(|bits| bits.parse().ok());
+        continue;
+      }
+      if words[0].starts_with(|c: char| c.is_ascii_alphabetic()) {
+        continue;
+      }
+
+      let values = words
+        .iter()
+        .map(|word| word.parse::<f32>())
+        .collect::<Result<Vec<f32>, _>>()?;
+      lines.push((line, values));
+    }
+
+    // the mesh has an entry per table size, a mesh of 3 looks like a table row but leaves 27 rows
+    let mesh = match lines.first() {
+      Some((_, first)) if first.len() != 3 || lines.len() == 3 * 3 * 3 + 1 => {
+        Some(lines.remove(0).1)
+      }
+      _ => None,
+    };
+
+    let mut rows: Vec<[f32; 3]> = Vec::with_capacity(lines.len());
+    for (line, values) in &lines {
+      match values[..] {
+        [r, g, b] => rows.push([r, g, b]),
+        _ => return Err(anyhow!("Unexpected row in 3dl LUT: {}", line)),
+      }
+    }
+
+    let size = match mesh {
+      Some(mesh) => mesh.len(),
+      None => (rows.len() as f32).cbrt().round() as usize,
+    };
+
+    if size > MAX_SIZE {
+      return Err(anyhow!("LUT size {} is larger than {}", size, MAX_SIZE));
+    }
+    if size < 2 || rows.len() != size * size * size {
+      return Err(anyhow!(
+        "Expected {} entries in LUT, found {}",
+        size * size * size,
+        rows.len()
+      ));
+    }
+
+    let largest = rows.iter().flatten().fold(0.0_f32, |a, &b| a.max(b));
+    let output_max = match output_bits {
+      Some(bits) if !(1..=31).contains(&bits) => {
+        return Err(anyhow!("Unsupported output bit depth {} in 3dl LUT", bits))
+      }
+      Some(bits) => ((1u32 << bits) - 1) as f32,
+      None if largest <= 1.0 => 1.0,
+      None if largest <= 1023.0 => 1023.0,
+      None if largest <= 4095.0 => 4095.0,
+      None => 65535.0,
+    };
+
+    let mut table = vec![[0.0; 3]; rows.len()];
+    for (i, row) in rows.iter().enumerate() {
+      let b = i % size;
+      let g = (i / size) % size;
+      let r = i / (size * size);
+      table[r + g * size + b * size * size] = row.map(|v| v / output_max);
+    }
+
+    Ok(Lut3d {
+      size,
+      domain_min: [0.0; 3],
+      domain_max: [1.0; 3],
+      table,
+    })
+  }
+
+  fn at(&self, r: usize, g: usize, b: usize) -> [f32; 3] {
+    self.table[r + g * self.size + b * self.size * self.size]
+  }
+
+  /**
+   * Looks up a color with tetrahedral interpolation, values outside the domain are clamped
+   */
+  pub fn apply(&self, rgb: [f32; 3]) -> [f32; 3] {
+    let last = (self.size - 1) as f32;
+
+    let mut index = [0; 3];
+    let mut fraction = [0.0; 3];
+    for c in 0..3 {
+      let range = self.domain_max[c] - self.domain_min[c];
+      let position = ((rgb[c] - self.domain_min[c]) / range).clamp(0.0, 1.0) * last;
+      index[c] = (position as usize).min(self.size - 2);
+      fraction[c] = position - index[c] as f32;
+    }
+
+    let [r, g, b] = index;
+    let [dr, dg, db] = fraction;
+
+    // the tetrahedron the color falls into, as its two inner corners and the weights of all four
+    let (first, second, weights) = if dr > dg {
+      if dg > db {
+        ([1, 0, 0], [1, 1, 0], [1.0 - dr, dr - dg, dg - db, db])
+      } else if dr > db {
+        ([1, 0, 0], [1, 0, 1], [1.0 - dr, dr - db, db - dg, dg])
+      } else {
+        ([0, 0, 1], [1, 0, 1], [1.0 - db, db - dr, dr - dg, dg])
+      }
+    } else if db > dg {
+      ([0, 0, 1], [0, 1, 1], [1.0 - db, db - dg, dg - dr, dr])
+    } else if db > dr {
+      ([0, 1, 0], [0, 1, 1], [1.0 - dg, dg - db, db - dr, dr])
+    } else {
+      ([0, 1, 0], [1, 1, 0], [1.0 - dg, dg - dr, dr - db, db])
+    };
+
+    let corners = [
+      self.at(r, g, b),
+      self.at(r + first[0], g + first[1], b + first[2]),
+      self.at(r + second[0], g + second[1], b + second[2]),
+      self.at(r + 1, g + 1, b + 1),
+    ];
+
+    [0, 1, 2].map(|c| (0..4).map(|i| weights[i] * corners[i][c]).sum())
+  }
+}
+
+fn parse_triplet<'a>(mut words: impl Iterator<Item = &'a str>) -> anyhow::Result<[f32; 3]> {
+  let mut triplet = [0.0; 3];
+  for value in triplet.iter_mut() {
+    *value = words
+      .next()
+      .ok_or(anyhow!("Expected three values"))?
+      .parse()?;
+  }
+  Ok(triplet)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn identity_cube(size: usize) -> String {
+    let mut text = format!("TITLE \"identity\"\n# comment\nLUT_3D_SIZE {}\n", size);
+    let last = (size - 1) as f32;
+    for b in 0..size {
+      for g in 0..size {
+        for r in 0..size {
+          text += &format!(
+            "{} {} {}\n",
+            r as f32 / last,
+            g as f32 / last,
+            b as f32 / last
+          );
+        }
+      }
+    }
+    text
+  }
+
+  fn assert_close(a: [f32; 3], b: [f32; 3]) {
+    for c in 0..3 {
+      assert!((a[c] - b[c]).abs() < 1e-3, "{:?} != {:?}", a, b);
+    }
+  }
+
+  const COLORS: [[f32; 3]; 4] = [
+    [0.0, 0.0, 0.0],
+    [0.25, 0.5, 0.75],
+    [0.9, 0.1, 0.4],
+    [1.0, 1.0, 1.0],
+  ];
+
+  #[test]
+  fn cube_identity() {
+    let lut = Lut3d::parse_cube(&identity_cube(5)).unwrap();
+    assert_eq!(lut.size, 5);
+    assert_eq!(lut.table.len(), 125);
+    for color in COLORS {
+      assert_close(lut.apply(color), color);
+    }
+  }
+
+  #[test]
+  fn cube_red_changes_fastest() {
+    let lut = Lut3d::parse_cube(&identity_cube(3)).unwrap();
+    assert_close(lut.table[1], [0.5, 0.0, 0.0]);
+    assert_close(lut.table[3], [0.0, 0.5, 0.0]);
+    assert_close(lut.table[9], [0.0, 0.0, 0.5]);
+  }
+
+  #[test]
+  fn cube_domain() {
+    let text = identity_cube(2).replace(
+      "LUT_3D_SIZE 2",
+      "LUT_3D_SIZE 2\nDOMAIN_MIN -1 -1 -1\nDOMAIN_MAX 3 3 3",
+    );
+    let lut = Lut3d::parse_cube(&text).unwrap();
+    assert_eq!(lut.domain_min, [-1.0; 3]);
+    assert_eq!(lut.domain_max, [3.0; 3]);
+    // the domain is spread over the table
+    assert_close(lut.apply([1.0, -1.0, 3.0]), [0.5, 0.0, 1.0]);
+    // and clamped outside of it
+    assert_close(lut.apply([5.0, -2.0, 1.0]), [1.0, 0.0, 0.5]);
+  }
+
+  #[test]
+  fn cube_errors() {
+    let missing = identity_cube(3).replace("0 0 0\n", "");
+    assert!(Lut3d::parse_cube(&missing).is_err());
+    assert!(Lut3d::parse_cube("LUT_1D_SIZE 2\n0 0 0\n1 1 1\n").is_err());
+    assert!(Lut3d::parse_cube("LUT_3D_SIZE 1\n0 0 0\n").is_err());
+  }
+
+  fn identity_3dl(size: usize, bits: u32, header: bool) -> String {
+    let max = ((1u32 << bits) - 1) as f32;
+    let last = (size - 1) as f32;
+    let mut text = String::new();
+    if header {
+      text += &format!("Mesh {} {}\n", (size as f32).log2() as u32, bits);
+    }
+    let mesh: Vec<String> = (0..size)
+      .map(|i| ((i as f32 / last) * 1023.0).round().to_string())
+      .collect();
+    text += &(mesh.join(" ") + "\n");
+    // blue changes fastest
+    for r in 0..size {
+      for g in 0..size {
+        for b in 0..size {
+          let value = |v: usize| ((v as f32 / last) * max).round();
+          text += &format!("{} {} {}\n", value(r), value(g), value(b));
+        }
+      }
+    }
+    text
+  }
+
+  #[test]
+  fn threedl_identity() {
+    for (bits, header) in [(12, true), (10, false), (16, true)] {
+      let lut = Lut3d::parse_3dl(&identity_3dl(5, bits, header)).unwrap();
+      assert_eq!(lut.size, 5);
+      for color in COLORS {
+        assert_close(lut.apply(color), color);
+      }
+    }
+  }
+
+  #[test]
+  fn threedl_is_reordered() {
+    let lut = Lut3d::parse_3dl(&identity_3dl(5, 12, true)).unwrap();
+    assert_close(lut.table[1], [0.25, 0.0, 0.0]);
+    assert_close(lut.table[5], [0.0, 0.25, 0.0]);
+    assert_close(lut.table[25], [0.0, 0.0, 0.25]);
+  }
+
+  #[test]
+  fn threedl_rejects_bit_depths() {
+    let text = identity_3dl(5, 12, true);
+    for bits in ["0", "32", "40"] {
+      let broken = text.replacen(" 12\n", &format!(" {}\n", bits), 1);
+      assert!(Lut3d::parse_3dl(&broken).is_err(), "{bits}");
+    }
+  }
+
+  #[test]
+  fn threedl_mesh_of_three() {
+    let text = identity_3dl(3, 12, false);
+    assert!(text.starts_with("0 512 1023\n"));
+    let lut = Lut3d::parse_3dl(&text).unwrap();
+    assert_eq!(lut.size, 3);
+    for color in COLORS {
+      assert_close(lut.apply(color), color);
+    }
+  }
+
+  #[test]
+  fn rejects_huge_sizes() {
+    assert!(Lut3d::parse_cube("LUT_3D_SIZE 4000000\n0 0 0\n").is_err());
+    assert!(Lut3d::parse_cube(&identity_cube(2).replace("SIZE 2", "SIZE 257")).is_err());
+    let mesh: Vec<String> = (0..300).map(|i| i.to_string()).collect();
+    assert!(Lut3d::parse_3dl(&(mesh.join(" ") + "\n0 0 0\n")).is_err());
+  }
+
+  #[test]
+  fn threedl_errors() {
+    let text = identity_3dl(5, 12, true);
+    let (head, _) = text.rsplit_once("4095 4095 4095").unwrap();
+    assert!(Lut3d::parse_3dl(head).is_err());
+    assert!(Lut3d::parse_3dl("0 512 1023\n0 0\n").is_err());
+  }
+}
@@ -0,0 +1,101 @@
+use crate::smoothstep;
+use serde::{Deserialize, Serialize};
+
+/**
+ * Chroma that counts as fully saturated, about the chroma of the sRGB primaries in OKLCh
+ */
+const FULL_CHROMA: f32 = 0.32;
+
+/**
+ * Limits where a node is applied, the weight of a pixel blends between the node's input and output.
+ * Every range that is set has to match, a mask without ranges covers the whole image.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Mask {
+  #[serde(default = "full_opacity")]
+  pub opacity: f32,
+  #[serde(default)]
+  pub invert: bool,
+  /**
+   * Perceptual lightness in 0..1
+   */
+  #[serde(default)]
+  pub luminance: Option<Range>,
+  /**
+   * Hue angle in degrees, a range with min above max wraps around 0, e.g. 330..30 for reds
+   */
+  #[serde(default)]
+  pub hue: Option<Range>,
+  /**
+   * Chroma in 0..1, 1 being about as saturated as the sRGB primaries
+   */
+  #[serde(default)]
+  pub saturation: Option<Range>,
+}
+
+fn full_opacity() -> f32 {
+  1.0
+}
+
+/**
+ * Selected range of a value, fading out over `feather` on both sides
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Range {
+  pub min: f32,
+  pub max: f32,
+  #[serde(default)]
+  pub feather: f32,
+}
+
+impl Range {
+  fn weight(&self, value: f32) -> f32 {
+    let distance = (self.min - value).max(value - self.max).max(0.0);
+    falloff(distance, self.feather)
+  }
+
+  fn weight_circular(&self, degrees: f32) -> f32 {
+    let width = (self.max - self.min).rem_euclid(360.0);
+    let offset = (degrees - self.min).rem_euclid(360.0);
+    let distance = if offset <= width {
+      0.0
+    } else {
+      (offset - width).min(360.0 - offset)
+    };
+    falloff(distance, self.feather)
+  }
+}
+
+fn falloff(distance: f32, feather: f32) -> f32 {
+  if feather <= 0.0 {
+    return if distance > 0.0 { 0.0 } else { 1.0 };
+  }
+  1.0 - smoothstep(0.0, feather, distance)
+}
+
+impl Mask {
+  /**
+   * Weight of a pixel in 0..1, evaluated on the OKLCh color of the node's input
+   */
+  pub fn weight(&self, lch: [f32; 3]) -> f32 {
+    let [lightness, chroma, hue] = lch;
+
+    let mut weight = 1.0;
+    if let Some(range) = &self.luminance {
+      weight *= range.weight(lightness);
+    }
+    if let Some(range) = &self.saturation {
+      weight *= range.weight(chroma / FULL_CHROMA);
+    }
+    if let Some(range) = &self.hue {
+      // hue is meaningless for greys, fade the selection out with the chroma
+      weight *= range.weight_circular(hue) * smoothstep(0.0, 0.02, chroma);
+    }
+
+    if self.invert {
+      weight = 1.0 - weight;
+    }
+
+    weight * self.opacity.clamp(0.0, 1.0)
+  }
+}
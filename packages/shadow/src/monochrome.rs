@@ -0,0 +1,217 @@
+use crate::color::{oklab_from_linear_srgb, oklch_from_oklab};
+use crate::color_grading::{ColorGrading, Wheel};
+use crate::curve::Curve;
+use crate::tiles;
+use crate::{conversion, luminance, WORKING_COLORSPACE};
+use image::{ImageBuffer, Rgb};
+use serde::{Deserialize, Serialize};
+
+/**
+ * OKLCh hues the mixer channels are centered on, in degrees
+ */
+const HUES: [f32; 8] = [25.0, 55.0, 100.0, 140.0, 195.0, 255.0, 295.0, 330.0];
+
+/**
+ * Chroma from which a color gets the full change of its hue, grays are never changed
+ */
+const FULL_CHROMA: f32 = 0.1;
+
+/**
+ * Luminance change of the colors of each hue in stops, in -1..1
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(default)]
+pub struct HueMixer {
+  pub red: f32,
+  pub orange: f32,
+  pub yellow: f32,
+  pub green: f32,
+  pub aqua: f32,
+  pub blue: f32,
+  pub purple: f32,
+  pub magenta: f32,
+}
+
+impl HueMixer {
+  fn values(&self) -> [f32; 8] {
+    [
+      self.red,
+      self.orange,
+      self.yellow,
+      self.green,
+      self.aqua,
+      self.blue,
+      self.purple,
+      self.magenta,
+    ]
+  }
+
+  fn from_values(values: [f32; 8]) -> HueMixer {
+    let [red, orange, yellow, green, aqua, blue, purple, magenta] = values;
+    HueMixer {
+      red,
+      orange,
+      yellow,
+      green,
+      aqua,
+      blue,
+      purple,
+      magenta,
+    }
+  }
+
+  /**
+   * Mix that separates the main colors of the image, each hue is moved further from the
+   * average lightness by how much it stands out and how much of the image it covers
+   */
+  pub fn auto(image: &ImageBuffer<Rgb<f32>, Vec<f32>>) -> HueMixer {
+    let to_display = conversion(WORKING_COLORSPACE, kolor::spaces::LINEAR_SRGB);
+    let mut weights = [0.0f32; 8];
+    let mut lightness = [0.0f32; 8];
+    let mut total = 0.0f32;
+    let mut total_lightness = 0.0f32;
+
+    // every few pixels is plenty for averages
+    for pixel in image.pixels().step_by(7) {
+      let linear = crate::mat_mul(&to_display, &pixel.0).map(|c| c.max(0.0));
+      let [l, chroma, hue] = oklch_from_oklab(oklab_from_linear_srgb(linear));
+      total += 1.0;
+      total_lightness += l;
+
+      let (index, _) = nearest_hue(hue);
+      let weight = (chroma / FULL_CHROMA).min(1.0);
+      weights[index] += weight;
+      lightness[index] += l * weight;
+    }
+    if total == 0.0 || total_lightness <= 0.0 {
+      return HueMixer::default();
+    }
+    let average = total_lightness / total;
+
+    HueMixer::from_values([0, 1, 2, 3, 4, 5, 6, 7].map(|i| {
+      if weights[i] == 0.0 {
+        return 0.0;
+      }
+      let coverage = (weights[i] / total / 0.05).min(1.0);
+      // lightness is the cube root of the luminance, three stops per doubling
+      let stops = 3.0 * (lightness[i] / weights[i] / average).max(1e-6).log2();
+      (stops * 0.5 * coverage).clamp(-1.0, 1.0)
+    }))
+  }
+}
+
+/**
+ * Index of the mixer channel closest to the hue and the distance to it in degrees
+ */
+fn nearest_hue(hue: f32) -> (usize, f32) {
+  HUES
+    .iter()
+    .enumerate()
+    .map(|(i, center)| {
+      let distance = (hue - center).rem_euclid(360.0);
+      (i, distance.min(360.0 - distance))
+    })
+    .fold(
+      (0, f32::MAX),
+      |best, next| if next.1 < best.1 { next } else { best },
+    )
+}
+
+/**
+ * Split toning of a black and white image, the hue and strength of shadows and highlights
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(default)]
+pub struct Toning {
+  pub shadows: Wheel,
+  pub highlights: Wheel,
+  /**
+   * -1..1, a positive balance tones more of the image like the highlights
+   */
+  pub balance: f32,
+}
+
+/**
+ * Converts to gray from the luminance, brightened or darkened by the hue of each color.
+ * An auto mix left unresolved is found in the image itself, which changes with the region shown.
+ */
+pub fn black_and_white(
+  image: &mut ImageBuffer<Rgb<f32>, Vec<f32>>,
+  mixer: &HueMixer,
+  auto: bool,
+  toning: Option<&Toning>,
+) {
+  let mixer = match auto {
+    true => HueMixer::auto(image),
+    false => *mixer,
+  };
+  let points: Vec<(f32, f32)> = HUES
+    .iter()
+    .zip(mixer.values())
+    .map(|(hue, value)| (hue / 360.0, value))
+    .collect();
+  let curve = match mixer.values().iter().any(|v| *v != 0.0) {
+    true => Curve::periodic(&points),
+    false => None,
+  };
+  let toning = toning.map(|toning| {
+    let none = Wheel::default();
+    ColorGrading::new(
+      &toning.shadows,
+      &none,
+      &toning.highlights,
+      &none,
+      0.5,
+      toning.balance,
+    )
+  });
+
+  let to_display = conversion(WORKING_COLORSPACE, kolor::spaces::LINEAR_SRGB);
+  tiles::map_pixels(image, |rgb| {
+    let mut gray = luminance(rgb).max(0.0);
+    if let Some(curve) = &curve {
+      let linear = crate::mat_mul(&to_display, &rgb).map(|c| c.max(0.0));
+      let [_, chroma, hue] = oklch_from_oklab(oklab_from_linear_srgb(linear));
+      let stops = curve.apply(hue / 360.0) * (chroma / FULL_CHROMA).min(1.0);
+      gray *= stops.exp2();
+    }
+    match &toning {
+      Some(toning) => toning.apply([gray; 3]),
+      None => [gray; 3],
+    }
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /**
+   * Dark blue on the left half and bright yellow on the right, in the working space
+   */
+  fn blue_and_yellow() -> ImageBuffer<Rgb<f32>, Vec<f32>> {
+    let from_display = conversion(kolor::spaces::LINEAR_SRGB, WORKING_COLORSPACE);
+    ImageBuffer::from_fn(64, 32, |x, _| match x < 32 {
+      true => Rgb(crate::mat_mul(&from_display, &[0.02, 0.03, 0.3])),
+      false => Rgb(crate::mat_mul(&from_display, &[0.8, 0.7, 0.05])),
+    })
+  }
+
+  #[test]
+  fn auto_mix_separates_the_colors() {
+    let mixer = HueMixer::auto(&blue_and_yellow());
+    assert!(mixer.blue < 0.0, "{mixer:?}");
+    assert!(mixer.yellow > 0.0, "{mixer:?}");
+    assert_eq!(mixer.red, 0.0);
+  }
+
+  #[test]
+  fn grays_need_no_mix() {
+    let from_display = conversion(kolor::spaces::LINEAR_SRGB, WORKING_COLORSPACE);
+    let image = ImageBuffer::from_fn(16, 16, |x, _| {
+      Rgb(crate::mat_mul(&from_display, &[x as f32 / 16.0; 3]))
+    });
+    let mixer = HueMixer::auto(&image);
+    assert!(mixer.values().iter().all(|v| v.abs() < 1e-3), "{mixer:?}");
+  }
+}
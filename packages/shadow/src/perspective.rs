@@ -0,0 +1,416 @@
+use image::imageops::{self, FilterType};
+use image::{ImageBuffer, Rgb};
+use serde::{Deserialize, Serialize};
+
+/**
+ * Largest keystone correction, in degrees the virtual camera is tilted at -1 or 1
+ */
+const MAX_TILT: f32 = 30.0;
+
+/**
+ * Largest zoom the constrained correction scales up by
+ */
+const MAX_COVER: f32 = 16.0;
+
+/**
+ * Projective correction, done by virtually turning the camera around the image center.
+ * The image is treated as if shot with a focal length of its long side, about a 35mm lens.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Perspective {
+  #[serde(default)]
+  pub mode: PerspectiveMode,
+  /**
+   * Keystone in -1..1, positive pulls the top apart like tilting the camera up
+   */
+  #[serde(default)]
+  pub vertical: f32,
+  /**
+   * Keystone in -1..1, positive pulls the right side apart like turning the camera right
+   */
+  #[serde(default)]
+  pub horizontal: f32,
+  /**
+   * Rotation in degrees, positive turns the image clockwise
+   */
+  #[serde(default)]
+  pub rotate: f32,
+  #[serde(default = "unit_scale")]
+  pub scale: f32,
+  /**
+   * Scales the image up to the largest rectangle that has no empty corners,
+   * `scale` applies on top of it
+   */
+  #[serde(default = "enabled")]
+  pub constrain: bool,
+}
+
+fn unit_scale() -> f32 {
+  1.0
+}
+
+fn enabled() -> bool {
+  true
+}
+
+impl Default for Perspective {
+  fn default() -> Self {
+    Perspective {
+      mode: PerspectiveMode::Manual,
+      vertical: 0.0,
+      horizontal: 0.0,
+      rotate: 0.0,
+      scale: 1.0,
+      constrain: true,
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PerspectiveMode {
+  #[default]
+  Manual,
+  /**
+   * Finds the converging vertical lines of the image and levels them,
+   * the manual values are applied on top of the detected correction
+   */
+  Auto,
+}
+
+impl Perspective {
+  pub fn is_identity(&self) -> bool {
+    self.mode == PerspectiveMode::Manual
+      && self.vertical == 0.0
+      && self.horizontal == 0.0
+      && self.rotate == 0.0
+      && (self.scale == 1.0 || self.scale <= 0.0)
+  }
+}
+
+/**
+ * The correction as a mapping from the corrected image back into the uncorrected one
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct PerspectiveMap {
+  rotation: [[f32; 3]; 3],
+  scale: f32,
+}
+
+impl PerspectiveMap {
+  /**
+   * Correction of an image of the given size, `detected` is the tilt and roll found by
+   * `detect_verticals`, used in the auto mode
+   */
+  pub fn new(
+    perspective: &Perspective,
+    detected: (f32, f32),
+    width: f32,
+    height: f32,
+  ) -> PerspectiveMap {
+    let (mut tilt, mut roll) = (0.0, 0.0);
+    if perspective.mode == PerspectiveMode::Auto {
+      (tilt, roll) = detected;
+    }
+
+    let tilt = tilt + (perspective.vertical * MAX_TILT).to_radians();
+    let pan = (perspective.horizontal * MAX_TILT).to_radians();
+    let roll = roll + perspective.rotate.to_radians();
+
+    let map = PerspectiveMap {
+      rotation: rotation(tilt, pan, roll),
+      scale: if perspective.scale > 0.0 {
+        perspective.scale
+      } else {
+        1.0
+      },
+    };
+    match perspective.constrain {
+      true => PerspectiveMap {
+        scale: map.scale * map.cover(width, height),
+        ..map
+      },
+      false => map,
+    }
+  }
+
+  /**
+   * Smallest zoom, at least 1, that leaves no empty corners. The corrected image is a convex
+   * quadrilateral, so the frame fits in it once its corner pixels do, and keeps fitting
+   * when zoomed in further.
+   */
+  fn cover(&self, width: f32, height: f32) -> f32 {
+    let fits = |zoom: f32| {
+      let map = PerspectiveMap {
+        scale: self.scale * zoom,
+        ..*self
+      };
+      let (right, bottom) = (width - 0.5, height - 0.5);
+      [(0.5, 0.5), (right, 0.5), (0.5, bottom), (right, bottom)]
+        .iter()
+        .all(|&(x, y)| map.map(x, y, width, height).is_some())
+    };
+
+    if fits(1.0) {
+      return 1.0;
+    }
+    let (mut low, mut high) = (1.0, 2.0);
+    while !fits(high) {
+      // the center itself is outside, zooming doesn't help
+      if high >= MAX_COVER {
+        return 1.0;
+      }
+      (low, high) = (high, high * 2.0);
+    }
+    for _ in 0..24 {
+      let middle = (low + high) / 2.0;
+      if fits(middle) {
+        high = middle;
+      } else {
+        low = middle;
+      }
+    }
+    high
+  }
+
+  /**
+   * Position in the uncorrected image of a position in the corrected one, both in pixels from
+   * the top left corner of images of the given size. None outside of the uncorrected image.
+   */
+  pub fn map(&self, x: f32, y: f32, width: f32, height: f32) -> Option<(f32, f32)> {
+    let focal = width.max(height);
+    let out = [
+      (x - width / 2.0) / focal / self.scale,
+      (y - height / 2.0) / focal / self.scale,
+      1.0,
+    ];
+
+    // the rotation is orthonormal, its transpose maps back into the source
+    let src = transpose_mul(&self.rotation, out);
+    if src[2] <= 0.0 {
+      return None;
+    }
+
+    let sx = src[0] / src[2] * focal + width / 2.0;
+    let sy = src[1] / src[2] * focal + height / 2.0;
+    if sx < 0.0 || sy < 0.0 || sx > width || sy > height {
+      return None;
+    }
+    Some((sx, sy))
+  }
+}
+
+/**
+ * Camera rotation, tilting around the x axis first, then panning and rolling
+ */
+fn rotation(tilt: f32, pan: f32, roll: f32) -> [[f32; 3]; 3] {
+  let (st, ct) = tilt.sin_cos();
+  let (sp, cp) = pan.sin_cos();
+  let (sr, cr) = roll.sin_cos();
+
+  let rx = [[1.0, 0.0, 0.0], [0.0, ct, -st], [0.0, st, ct]];
+  let ry = [[cp, 0.0, sp], [0.0, 1.0, 0.0], [-sp, 0.0, cp]];
+  let rz = [[cr, -sr, 0.0], [sr, cr, 0.0], [0.0, 0.0, 1.0]];
+
+  mul(&rz, &mul(&ry, &rx))
+}
+
+fn mul(a: &[[f32; 3]; 3], b: &[[f32; 3]; 3]) -> [[f32; 3]; 3] {
+  let mut result = [[0.0; 3]; 3];
+  for i in 0..3 {
+    for j in 0..3 {
+      result[i][j] = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+    }
+  }
+  result
+}
+
+fn transpose_mul(matrix: &[[f32; 3]; 3], vector: [f32; 3]) -> [f32; 3] {
+  [0, 1, 2].map(|i| (0..3).map(|j| matrix[j][i] * vector[j]).sum())
+}
+
+/**
+ * Estimates the tilt and roll that make the near vertical lines of the image parallel.
+ * Every strong edge pixel with a near horizontal gradient is a short line segment,
+ * the point all those lines meet in the least squares sense is their vanishing point,
+ * which (with the image plane at a distance of one focal length) is also their 3D direction.
+ */
+pub fn detect_verticals(image: &ImageBuffer<Rgb<f32>, Vec<f32>>) -> (f32, f32) {
+  // analysed at a fixed size, so previews and full resolution renders detect the same lines
+  let long = image.width().max(image.height()) as f32;
+  let factor = 512.0 / long;
+  let small = imageops::resize(
+    image,
+    ((image.width() as f32 * factor).round() as u32).max(3),
+    ((image.height() as f32 * factor).round() as u32).max(3),
+    FilterType::Triangle,
+  );
+
+  let (width, height) = small.dimensions();
+  let focal = width.max(height) as f32;
+  let lum: Vec<f32> = small
+    .pixels()
+    .map(|p| 0.2126 * p.0[0] + 0.7152 * p.0[1] + 0.0722 * p.0[2])
+    .collect();
+  let at = |x: u32, y: u32| lum[(y * width + x) as usize];
+
+  let mut edges = Vec::new();
+  for y in 1..height - 1 {
+    for x in 1..width - 1 {
+      // sobel
+      let gx = at(x + 1, y - 1) + 2.0 * at(x + 1, y) + at(x + 1, y + 1)
+        - at(x - 1, y - 1)
+        - 2.0 * at(x - 1, y)
+        - at(x - 1, y + 1);
+      let gy = at(x - 1, y + 1) + 2.0 * at(x, y + 1) + at(x + 1, y + 1)
+        - at(x - 1, y - 1)
+        - 2.0 * at(x, y - 1)
+        - at(x + 1, y - 1);
+
+      // a near vertical line has a near horizontal gradient, allow 25 degrees of lean
+      if gy.abs() > gx.abs() * 0.47 {
+        continue;
+      }
+
+      let magnitude = gx.hypot(gy);
+      edges.push((x, y, gx / magnitude, gy / magnitude, magnitude));
+    }
+  }
+
+  if edges.len() < 50 {
+    return (0.0, 0.0);
+  }
+
+  // only the strongest tenth of the edges, texture makes for poor lines
+  let mut magnitudes: Vec<f32> = edges.iter().map(|e| e.4).collect();
+  magnitudes.sort_by(|a, b| a.total_cmp(b));
+  let threshold = magnitudes[magnitudes.len() * 9 / 10].max(1e-3);
+
+  let mut moments = [[0.0_f64; 3]; 3];
+  for (x, y, nx, ny, magnitude) in edges {
+    if magnitude < threshold {
+      continue;
+    }
+    let px = (x as f32 + 0.5 - width as f32 / 2.0) / focal;
+    let py = (y as f32 + 0.5 - height as f32 / 2.0) / focal;
+    let line = [nx as f64, ny as f64, -(nx * px + ny * py) as f64];
+    for i in 0..3 {
+      for j in 0..3 {
+        moments[i][j] += magnitude as f64 * line[i] * line[j];
+      }
+    }
+  }
+
+  let mut direction = smallest_eigenvector(moments);
+  if direction[1] < 0.0 {
+    direction = direction.map(|c| -c);
+  }
+  let [dx, dy, dz] = direction.map(|c| c as f32);
+
+  // tilt around x to bring the direction into the image plane, then roll it upright
+  let tilt = (-dz).atan2(dy);
+  let roll = dx.atan2(dy.hypot(dz));
+
+  let limit = MAX_TILT.to_radians();
+  (tilt.clamp(-limit, limit), roll.clamp(-limit, limit))
+}
+
+/**
+ * Jacobi eigenvalue iteration of a symmetric 3x3 matrix
+ */
+fn smallest_eigenvector(matrix: [[f64; 3]; 3]) -> [f64; 3] {
+  let mut a = matrix;
+  let mut v = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+  for _ in 0..32 {
+    let (mut p, mut q) = (0, 1);
+    for (i, j) in [(0, 2), (1, 2)] {
+      if a[i][j].abs() > a[p][q].abs() {
+        (p, q) = (i, j);
+      }
+    }
+    if a[p][q].abs() < 1e-12 {
+      break;
+    }
+
+    let theta = (a[q][q] - a[p][p]) / (2.0 * a[p][q]);
+    let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+    let c = 1.0 / (t * t + 1.0).sqrt();
+    let s = t * c;
+
+    for row in a.iter_mut() {
+      let (akp, akq) = (row[p], row[q]);
+      row[p] = c * akp - s * akq;
+      row[q] = s * akp + c * akq;
+    }
+    let (row_p, row_q) = (a[p], a[q]);
+    a[p] = [0, 1, 2].map(|k| c * row_p[k] - s * row_q[k]);
+    a[q] = [0, 1, 2].map(|k| s * row_p[k] + c * row_q[k]);
+    for row in v.iter_mut() {
+      let (vp, vq) = (row[p], row[q]);
+      row[p] = c * vp - s * vq;
+      row[q] = s * vp + c * vq;
+    }
+  }
+
+  let smallest = (0..3)
+    .min_by(|&i, &j| a[i][i].total_cmp(&a[j][j]))
+    .unwrap_or(2);
+  [v[0][smallest], v[1][smallest], v[2][smallest]]
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const WIDTH: u32 = 384;
+  const HEIGHT: u32 = 256;
+
+  /**
+   * Vertical bars shot with the camera tilted up by `tilt` degrees, they converge towards a
+   * vanishing point above the image
+   */
+  fn converging_bars(tilt: f32) -> impl Fn(f32, f32) -> f32 {
+    let focal = WIDTH as f32;
+    let vanishing = HEIGHT as f32 / 2.0 - focal / tilt.to_radians().tan();
+    move |x, y| {
+      let spread = (y - vanishing) / (HEIGHT as f32 - vanishing);
+      let u = (x - WIDTH as f32 / 2.0) / spread;
+      // smooth bars, hard ones alias into perfectly vertical steps
+      0.45 + 0.35 * (u / 48.0 * std::f32::consts::TAU).sin()
+    }
+  }
+
+  fn render(shade: impl Fn(f32, f32) -> f32) -> ImageBuffer<Rgb<f32>, Vec<f32>> {
+    ImageBuffer::from_fn(WIDTH, HEIGHT, |x, y| {
+      Rgb([shade(x as f32 + 0.5, y as f32 + 0.5); 3])
+    })
+  }
+
+  #[test]
+  fn detects_and_levels_converging_verticals() {
+    let bars = converging_bars(12.0);
+    let (tilt, roll) = detect_verticals(&render(&bars));
+    assert!(
+      (tilt.to_degrees() - 12.0).abs() < 1.0,
+      "{}",
+      tilt.to_degrees()
+    );
+    assert!(roll.abs() < 2f32.to_radians(), "{roll}");
+
+    let perspective = Perspective {
+      mode: PerspectiveMode::Auto,
+      ..Perspective::default()
+    };
+    let (width, height) = (WIDTH as f32, HEIGHT as f32);
+    let map = PerspectiveMap::new(&perspective, (tilt, roll), width, height);
+    let corrected = render(|x, y| {
+      let (sx, sy) = map.map(x, y, width, height).unwrap_or((x, y));
+      bars(sx, sy)
+    });
+
+    let (tilt, roll) = detect_verticals(&corrected);
+    assert!(tilt.abs() < 3f32.to_radians(), "{}", tilt.to_degrees());
+    assert!(roll.abs() < 2f32.to_radians(), "{}", roll.to_degrees());
+  }
+}
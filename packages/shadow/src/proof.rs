@@ -0,0 +1,482 @@
+use crate::color::oklab_from_linear_srgb;
+use crate::color_space::{OutputSpace, D50};
+use crate::icc;
+use crate::tiles;
+use crate::white_balance::{adaptation, xyz_from_xy, D65, SRGB_TO_XYZ};
+use crate::{conversion, mat_inverse, mat_mul, mat_product, WORKING_COLORSPACE};
+use anyhow::anyhow;
+use image::{ImageBuffer, Rgb};
+use lcms2::{
+  DisallowCache, Flags, GlobalContext, Intent, PixelFormat, Profile, TagSignature, Transform,
+};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/**
+ * Linear sRGB color out of gamut pixels are marked with
+ */
+const GAMUT_WARNING: [f32; 3] = [0.0, 0.35, 1.0];
+
+/**
+ * OKLab distance a round trip through a lookup profile may move a color it reproduces
+ */
+const GAMUT_TOLERANCE: f32 = 0.02;
+
+/**
+ * How colors the destination can't reproduce are brought into its gamut
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RenderingIntent {
+  /**
+   * Lowers the saturation of out of gamut colors, keeping their luminance and hue
+   */
+  Perceptual,
+  /**
+   * Clips out of gamut colors, white maps to the paper white
+   */
+  #[default]
+  RelativeColorimetric,
+  /**
+   * Clips out of gamut colors and keeps white as it is, colors brighter than the paper clip
+   */
+  AbsoluteColorimetric,
+}
+
+/**
+ * Destination of a soft-proof, the gamut and paper of an ICC profile
+ */
+#[derive(Debug, Clone)]
+pub struct ProofProfile(Destination);
+
+#[derive(Debug, Clone)]
+enum Destination {
+  /**
+   * RGB matrix profile, linear RGB of the destination to D50 XYZ and the XYZ of the paper,
+   * D50 for displays
+   */
+  Matrix {
+    to_pcs: [[f32; 3]; 3],
+    media_white: [f32; 3],
+  },
+  /**
+   * ICC data of a profile converting through lookup tables, like the CMYK profiles of printers,
+   * the conversions are left to lcms
+   */
+  Lookup(Arc<[u8]>),
+}
+
+type PcsTransform = Transform<[f32; 3], [f32; 3], GlobalContext, DisallowCache>;
+
+impl ProofProfile {
+  pub fn from_icc(data: &[u8]) -> anyhow::Result<ProofProfile> {
+    let profile = Profile::new_icc(data).map_err(|_| anyhow!("Not an ICC profile"))?;
+    // the lookup tables take precedence over the colorants when a profile has both
+    if !profile.has_tag(TagSignature::AToB0Tag) && !profile.has_tag(TagSignature::BToA0Tag) {
+      if let Ok((to_pcs, media_white)) = icc::read_matrix(data) {
+        if mat_inverse(&to_pcs).is_none() || media_white[1] <= 0.0 {
+          return Err(anyhow!("Profile has no usable colorants"));
+        }
+        return Ok(ProofProfile(Destination::Matrix {
+          to_pcs,
+          media_white,
+        }));
+      }
+    }
+    proofing_transform(
+      &profile,
+      Intent::RelativeColorimetric,
+      Intent::RelativeColorimetric,
+    )
+    .map_err(|_| anyhow!("Profile can't convert colors into its device and back"))?;
+    Ok(ProofProfile(Destination::Lookup(data.into())))
+  }
+}
+
+impl From<OutputSpace> for ProofProfile {
+  fn from(space: OutputSpace) -> ProofProfile {
+    ProofProfile(Destination::Matrix {
+      to_pcs: mat_product(&adaptation(space.white(), D50), &space.to_xyz()),
+      media_white: xyz_from_xy(D50),
+    })
+  }
+}
+
+/**
+ * D50 XYZ into the device and back out of it, as lcms simulates a proof
+ */
+fn proofing_transform(
+  device: &Profile,
+  intent: Intent,
+  proofing_intent: Intent,
+) -> lcms2::LCMSResult<PcsTransform> {
+  let pcs = Profile::new_xyz();
+  Transform::new_proofing_context(
+    GlobalContext::new(),
+    &pcs,
+    PixelFormat::XYZ_FLT,
+    &pcs,
+    PixelFormat::XYZ_FLT,
+    device,
+    intent,
+    proofing_intent,
+    Flags::NO_CACHE | Flags::SOFT_PROOFING,
+  )
+}
+
+/**
+ * Simulates on screen how the image comes out of a destination profile
+ */
+#[derive(Debug, Clone)]
+pub struct SoftProof {
+  pub profile: ProofProfile,
+  pub intent: RenderingIntent,
+  /**
+   * Shows white as the paper instead of the white of the screen
+   */
+  pub paper_white: bool,
+  /**
+   * Marks the pixels outside of the destination gamut
+   */
+  pub gamut_warning: bool,
+}
+
+/**
+ * Maps the working space image into the destination and back, as it will look once reproduced
+ */
+pub fn soft_proof(image: &mut ImageBuffer<Rgb<f32>, Vec<f32>>, proof: &SoftProof) {
+  match &proof.profile.0 {
+    Destination::Matrix {
+      to_pcs,
+      media_white,
+    } => soft_proof_matrix(image, proof, to_pcs, *media_white),
+    Destination::Lookup(data) => {
+      if let Err(err) = soft_proof_lookup(image, proof, data) {
+        error!("Soft-proof failed: {}", err);
+      }
+    }
+  }
+}
+
+/**
+ * Working space to D50 XYZ
+ */
+fn working_to_pcs() -> [[f32; 3]; 3] {
+  mat_product(
+    &adaptation(D65, D50),
+    &mat_product(
+      &SRGB_TO_XYZ,
+      &conversion(WORKING_COLORSPACE, kolor::spaces::LINEAR_SRGB),
+    ),
+  )
+}
+
+fn gamut_warning() -> [f32; 3] {
+  mat_mul(
+    &conversion(kolor::spaces::LINEAR_SRGB, WORKING_COLORSPACE),
+    &GAMUT_WARNING,
+  )
+}
+
+fn soft_proof_matrix(
+  image: &mut ImageBuffer<Rgb<f32>, Vec<f32>>,
+  proof: &SoftProof,
+  destination_to_pcs: &[[f32; 3]; 3],
+  media: [f32; 3],
+) {
+  let to_pcs = working_to_pcs();
+  let Some(from_pcs) = mat_inverse(&to_pcs) else {
+    return;
+  };
+  let Some(pcs_to_destination) = mat_inverse(destination_to_pcs) else {
+    return;
+  };
+
+  // the paper white relative to the D50 white of the connection space
+  let media_xy = [
+    media[0] / (media[0] + media[1] + media[2]),
+    media[1] / (media[0] + media[1] + media[2]),
+  ];
+  let to_paper = adaptation(D50, media_xy).map(|row| row.map(|v| v * media[1]));
+  let from_paper = mat_inverse(&to_paper).unwrap_or(adaptation(D50, D50));
+
+  let into = match proof.intent {
+    RenderingIntent::AbsoluteColorimetric => mat_product(&from_paper, &to_pcs),
+    _ => to_pcs,
+  };
+  let into = mat_product(&pcs_to_destination, &into);
+  let back = match proof.paper_white {
+    true => mat_product(&to_paper, destination_to_pcs),
+    false => *destination_to_pcs,
+  };
+  let back = mat_product(&from_pcs, &back);
+
+  let luminance = destination_to_pcs[1];
+  let warning = gamut_warning();
+
+  tiles::map_pixels(image, |rgb| {
+    let destination = mat_mul(&into, &rgb);
+    if proof.gamut_warning && !in_gamut(&destination) {
+      return warning;
+    }
+    let mapped = match proof.intent {
+      RenderingIntent::Perceptual => desaturate_into_gamut(destination, &luminance),
+      _ => destination,
+    };
+    mat_mul(&back, &mapped.map(|c| c.clamp(0.0, 1.0)))
+  });
+}
+
+/**
+ * Proofs through lcms, marking the colors a colorimetric round trip through the device moves
+ */
+fn soft_proof_lookup(
+  image: &mut ImageBuffer<Rgb<f32>, Vec<f32>>,
+  proof: &SoftProof,
+  data: &[u8],
+) -> anyhow::Result<()> {
+  let to_pcs = working_to_pcs();
+  let from_pcs = mat_inverse(&to_pcs).ok_or_else(|| anyhow!("Working space has no inverse"))?;
+  let warning = mat_mul(&to_pcs, &gamut_warning());
+  let device = Profile::new_icc(data)?;
+  let intent = match proof.intent {
+    RenderingIntent::Perceptual => Intent::Perceptual,
+    RenderingIntent::RelativeColorimetric => Intent::RelativeColorimetric,
+    RenderingIntent::AbsoluteColorimetric => Intent::AbsoluteColorimetric,
+  };
+  let proofing_intent = match proof.paper_white {
+    true => Intent::AbsoluteColorimetric,
+    false => Intent::RelativeColorimetric,
+  };
+  let proofed = proofing_transform(&device, intent, proofing_intent)?;
+  let round_trip = match proof.gamut_warning {
+    true => Some(proofing_transform(
+      &device,
+      Intent::RelativeColorimetric,
+      Intent::RelativeColorimetric,
+    )?),
+    false => None,
+  };
+  let pcs_to_srgb = mat_product(
+    &conversion(WORKING_COLORSPACE, kolor::spaces::LINEAR_SRGB),
+    &from_pcs,
+  );
+
+  tiles::for_each_tile(image, |tile, _| {
+    let pcs: Vec<[f32; 3]> = tile
+      .chunks_exact(3)
+      .map(|pixel| mat_mul(&to_pcs, &[pixel[0], pixel[1], pixel[2]]))
+      .collect();
+    let mut out = vec![[0.0; 3]; pcs.len()];
+    proofed.transform_pixels(&pcs, &mut out);
+    if let Some(round_trip) = &round_trip {
+      let mut reproduced = vec![[0.0; 3]; pcs.len()];
+      round_trip.transform_pixels(&pcs, &mut reproduced);
+      for ((xyz, reproduced), out) in pcs.iter().zip(&reproduced).zip(&mut out) {
+        let lab = oklab_from_linear_srgb(mat_mul(&pcs_to_srgb, xyz));
+        let reproduced = oklab_from_linear_srgb(mat_mul(&pcs_to_srgb, reproduced));
+        let distance = lab
+          .iter()
+          .zip(&reproduced)
+          .map(|(a, b)| (a - b) * (a - b))
+          .sum::<f32>()
+          .sqrt();
+        if distance > GAMUT_TOLERANCE {
+          *out = warning;
+        }
+      }
+    }
+    for (pixel, xyz) in tile.chunks_exact_mut(3).zip(&out) {
+      pixel.copy_from_slice(&mat_mul(&from_pcs, xyz));
+    }
+  });
+  Ok(())
+}
+
+fn in_gamut(rgb: &[f32; 3]) -> bool {
+  // rounding of the matrices shouldn't mark neutral colors
+  const TOLERANCE: f32 = 1e-3;
+  rgb
+    .iter()
+    .all(|c| (-TOLERANCE..=1.0 + TOLERANCE).contains(c))
+}
+
+/**
+ * Moves the color towards the gray of its luminance until every channel fits
+ */
+fn desaturate_into_gamut(rgb: [f32; 3], luminance: &[f32; 3]) -> [f32; 3] {
+  let gray =
+    (luminance[0] * rgb[0] + luminance[1] * rgb[1] + luminance[2] * rgb[2]).clamp(0.0, 1.0);
+  let min = rgb[0].min(rgb[1]).min(rgb[2]);
+  let max = rgb[0].max(rgb[1]).max(rgb[2]);
+  let mut amount: f32 = 1.0;
+  if min < 0.0 {
+    amount = amount.min(gray / (gray - min));
+  }
+  if max > 1.0 {
+    amount = amount.min((1.0 - gray) / (max - gray));
+  }
+  rgb.map(|c| gray + (c - gray) * amount)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /**
+   * lut16Type tag with identity matrix and curves around the table
+   */
+  fn lut16(inputs: u8, outputs: u8, grid: u8, table: &[u16]) -> Vec<u8> {
+    let mut tag = b"mft2\0\0\0\0".to_vec();
+    tag.extend_from_slice(&[inputs, outputs, grid, 0]);
+    for v in IDENTITY.concat() {
+      tag.extend_from_slice(&icc::s15_fixed16(v));
+    }
+    tag.extend_from_slice(&2u16.to_be_bytes());
+    tag.extend_from_slice(&2u16.to_be_bytes());
+    let curves = |count: u8| (0..count).flat_map(|_| [0u16, 65535]).collect::<Vec<_>>();
+    for v in curves(inputs).iter().chain(table).chain(&curves(outputs)) {
+      tag.extend_from_slice(&v.to_be_bytes());
+    }
+    tag
+  }
+
+  const IDENTITY: [[f32; 3]; 3] = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+  /**
+   * Linear sRGB to D50 XYZ, the gamut of the test printer
+   */
+  fn srgb_to_pcs() -> [[f32; 3]; 3] {
+    mat_product(&adaptation(D65, D50), &SRGB_TO_XYZ)
+  }
+
+  /**
+   * CMYK printer profile with the gamut of sRGB, the naive `(1 - c) * (1 - k)` ink model
+   * and its inverse as lookup tables. Without the inverse it only describes the printer.
+   */
+  fn printer(with_inverse: bool) -> Vec<u8> {
+    let to_pcs = srgb_to_pcs();
+    // XYZ in lut16 tables goes up to 1 + 32767 / 32768
+    let encode = |v: f32| (v * 32768.0).round().clamp(0.0, 65535.0) as u16;
+
+    const GRID: u16 = 33;
+    const INK_GRID: u16 = 9;
+    let ink = |node: u16| node as f32 / (INK_GRID - 1) as f32;
+    let mut a2b0 = Vec::new();
+    for c in 0..INK_GRID {
+      for m in 0..INK_GRID {
+        for y in 0..INK_GRID {
+          for k in 0..INK_GRID {
+            let rgb = [c, m, y].map(|node| (1.0 - ink(node)) * (1.0 - ink(k)));
+            a2b0.extend(mat_mul(&to_pcs, &rgb).map(encode));
+          }
+        }
+      }
+    }
+
+    let from_pcs = mat_inverse(&to_pcs).unwrap();
+    let mut b2a0 = Vec::new();
+    for x in 0..GRID {
+      for y in 0..GRID {
+        for z in 0..GRID {
+          let xyz = [x, y, z].map(|v| v as f32 / (GRID - 1) as f32 * 65535.0 / 32768.0);
+          let rgb = mat_mul(&from_pcs, &xyz).map(|c| c.clamp(0.0, 1.0));
+          let k = 1.0 - rgb[0].max(rgb[1]).max(rgb[2]);
+          let inks = rgb.map(|c| match k < 1.0 {
+            true => (1.0 - c - k) / (1.0 - k),
+            false => 0.0,
+          });
+          b2a0.extend([inks[0], inks[1], inks[2], k].map(|v| (v * 65535.0).round() as u16));
+        }
+      }
+    }
+
+    let mut tags = vec![
+      (b"wtpt", icc::xyz(xyz_from_xy(D50))),
+      (b"A2B0", lut16(4, 3, INK_GRID as u8, &a2b0)),
+    ];
+    if with_inverse {
+      tags.push((b"B2A0", lut16(3, 4, GRID as u8, &b2a0)));
+    }
+    icc::assemble([2, 0x10, 0, 0], b"prtrCMYKXYZ ", &tags)
+  }
+
+  fn proofed(profile: ProofProfile, gamut_warning: bool, colors: &[[f32; 3]]) -> Vec<[f32; 3]> {
+    let to_working = conversion(kolor::spaces::LINEAR_SRGB, WORKING_COLORSPACE);
+    let mut image = ImageBuffer::from_fn(colors.len() as u32, 1, |x, _| {
+      Rgb(mat_mul(&to_working, &colors[x as usize]))
+    });
+    let proof = SoftProof {
+      profile,
+      intent: RenderingIntent::RelativeColorimetric,
+      paper_white: false,
+      gamut_warning,
+    };
+    soft_proof(&mut image, &proof);
+    let to_srgb = conversion(WORKING_COLORSPACE, kolor::spaces::LINEAR_SRGB);
+    image
+      .pixels()
+      .map(|pixel| mat_mul(&to_srgb, &pixel.0))
+      .collect()
+  }
+
+  fn assert_close(a: [f32; 3], b: [f32; 3], tolerance: f32) {
+    for c in 0..3 {
+      assert!((a[c] - b[c]).abs() < tolerance, "{a:?} {b:?}");
+    }
+  }
+
+  #[test]
+  fn reads_matrix_profiles() {
+    let profile = ProofProfile::from_icc(&icc::profile(OutputSpace::AdobeRgb)).unwrap();
+    assert!(matches!(profile.0, Destination::Matrix { .. }));
+    // pure sRGB colors are inside of Adobe RGB
+    let colors = [[0.5, 0.5, 0.5], [1.0, 0.0, 0.0], [0.1, 0.6, 0.2]];
+    for (proofed, color) in proofed(profile, true, &colors).into_iter().zip(colors) {
+      assert_close(proofed, color, 1e-3);
+    }
+  }
+
+  #[test]
+  fn reads_lookup_profiles() {
+    let profile = ProofProfile::from_icc(&printer(true)).unwrap();
+    assert!(matches!(profile.0, Destination::Lookup(_)));
+  }
+
+  #[test]
+  fn lookup_profiles_keep_reproducible_colors() {
+    // away from the edges of the gamut, where the coarse table interpolates clipped colors
+    let colors = [
+      [0.8, 0.8, 0.8],
+      [0.2, 0.2, 0.2],
+      [0.5, 0.3, 0.2],
+      [0.1, 0.3, 0.6],
+    ];
+    let profile = ProofProfile::from_icc(&printer(true)).unwrap();
+    for (proofed, color) in proofed(profile, true, &colors).into_iter().zip(colors) {
+      assert_close(proofed, color, 0.02);
+    }
+  }
+
+  #[test]
+  fn lookup_profiles_clip_to_their_gamut() {
+    // a green outside of sRGB, as linear sRGB with a negative red
+    let colors = [[-0.2, 0.8, 0.1]];
+    let profile = ProofProfile::from_icc(&printer(true)).unwrap();
+    let proofed = proofed(profile.clone(), false, &colors)[0];
+    assert!(proofed[0] > -0.02, "{proofed:?}");
+    assert!(proofed[1] > 0.6, "{proofed:?}");
+
+    let warned = self::proofed(profile, true, &colors)[0];
+    assert_close(warned, GAMUT_WARNING, 1e-3);
+  }
+
+  #[test]
+  fn rejects_profiles_it_cant_proof() {
+    assert!(ProofProfile::from_icc(b"not a profile").is_err());
+    // a printer profile without the conversion into the printer
+    let error = ProofProfile::from_icc(&printer(false)).unwrap_err();
+    assert!(error.to_string().contains("into its device"), "{error}");
+  }
+}
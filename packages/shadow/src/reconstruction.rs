@@ -0,0 +1,239 @@
+use crate::filter::gaussian_blur;
+use image::{ImageBuffer, Rgb};
+use serde::{Deserialize, Serialize};
+
+/**
+ * What happens where a channel of the sensor clipped
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HighlightReconstruction {
+  /**
+   * All channels are clipped where the first one clips, highlights turn flat white
+   */
+  Clip,
+  /**
+   * Keeps the brightness of the channels that did not clip with the color of the clipped highlight
+   */
+  #[default]
+  Blend,
+  /**
+   * Fills the clipped channels with the color of the surrounding pixels
+   */
+  Propagate,
+}
+
+/**
+ * Pixels count as clipped a bit before the clip level, demosaicing smears it
+ */
+const CLIP_MARGIN: f32 = 0.99;
+
+/**
+ * Reconstructs the highlights of white balanced camera RGB, `clip` being the level each channel
+ * clipped at after white balance
+ */
+pub fn reconstruct(
+  image: &mut ImageBuffer<Rgb<f32>, Vec<f32>>,
+  mode: HighlightReconstruction,
+  clip: [f32; 3],
+) {
+  let clip = clip.map(|c| c * CLIP_MARGIN);
+  match mode {
+    HighlightReconstruction::Clip => {
+      let lowest = clip[0].min(clip[1]).min(clip[2]);
+      for pixel in image.pixels_mut() {
+        pixel.0 = pixel.0.map(|c| c.min(lowest));
+      }
+    }
+    HighlightReconstruction::Blend => {
+      for pixel in image.pixels_mut() {
+        pixel.0 = blend(pixel.0, clip);
+      }
+    }
+    HighlightReconstruction::Propagate => propagate(image, clip),
+  }
+}
+
+fn is_clipped(rgb: [f32; 3], clip: [f32; 3]) -> bool {
+  (0..3).any(|c| rgb[c] >= clip[c])
+}
+
+/**
+ * Brightness from the unclipped values, the chroma scaled down to that of the fully clipped color
+ */
+fn blend(rgb: [f32; 3], clip: [f32; 3]) -> [f32; 3] {
+  if !is_clipped(rgb, clip) {
+    return rgb;
+  }
+
+  let lowest = clip[0].min(clip[1]).min(clip[2]);
+  let clipped = rgb.map(|c| c.min(lowest));
+
+  let mean = (rgb[0] + rgb[1] + rgb[2]) / 3.0;
+  let clipped_mean = (clipped[0] + clipped[1] + clipped[2]) / 3.0;
+  let chroma = rgb.map(|c| c - mean);
+  let clipped_chroma = clipped.map(|c| c - clipped_mean);
+
+  let length = chroma.iter().map(|c| c * c).sum::<f32>().sqrt();
+  let clipped_length = clipped_chroma.iter().map(|c| c * c).sum::<f32>().sqrt();
+  let scale = if length > 0.0 {
+    clipped_length / length
+  } else {
+    0.0
+  };
+
+  chroma.map(|c| mean + c * scale)
+}
+
+/**
+ * Estimates the color of the clipped areas from the pixels around them: the chromaticity of the
+ * unclipped pixels is spread out on a coarse grid at growing radii, the smallest radius that
+ * reaches a clipped pixel gives its color, the channels that did not clip give its brightness
+ */
+fn propagate(image: &mut ImageBuffer<Rgb<f32>, Vec<f32>>, clip: [f32; 3]) {
+  let (width, height) = (image.width() as usize, image.height() as usize);
+  if !image.pixels().any(|pixel| is_clipped(pixel.0, clip)) {
+    return;
+  }
+
+  // the color is smooth enough to be estimated at about 256 px
+  let block = (width.max(height) / 256).max(1);
+  let grid_width = width.div_ceil(block);
+  let grid_height = height.div_ceil(block);
+
+  let mut ratios = vec![vec![0.0; grid_width * grid_height]; 3];
+  let mut weights = vec![0.0; grid_width * grid_height];
+  for (x, y, pixel) in image.enumerate_pixels() {
+    let rgb = pixel.0;
+    let sum = rgb[0] + rgb[1] + rgb[2];
+    if is_clipped(rgb, clip) || sum <= 0.0 {
+      continue;
+    }
+    // bright pixels are closer to the clipped ones in brightness and likely in color
+    let weight = sum;
+    let cell = (y as usize / block) * grid_width + x as usize / block;
+    for c in 0..3 {
+      ratios[c][cell] += rgb[c] / sum * weight;
+    }
+    weights[cell] += weight;
+  }
+
+  let mut color = vec![[1.0 / 3.0; 3]; grid_width * grid_height];
+  let mut found = vec![false; grid_width * grid_height];
+  for sigma in [1.0, 4.0, 16.0, 64.0] {
+    let weight = gaussian_blur(&weights, grid_width, grid_height, sigma);
+    let spread: Vec<Vec<f32>> = ratios
+      .iter()
+      .map(|plane| gaussian_blur(plane, grid_width, grid_height, sigma))
+      .collect();
+    let threshold = weights.iter().fold(0.0_f32, |a, &b| a.max(b)) * 1e-3;
+
+    for i in 0..color.len() {
+      if !found[i] && weight[i] > threshold {
+        color[i] = [0, 1, 2].map(|c| spread[c][i] / weight[i]);
+        found[i] = true;
+      }
+    }
+  }
+
+  let at =
+    |gx: usize, gy: usize| color[gy.min(grid_height - 1) * grid_width + gx.min(grid_width - 1)];
+
+  for (x, y, pixel) in image.enumerate_pixels_mut() {
+    let rgb = pixel.0;
+    if !is_clipped(rgb, clip) {
+      continue;
+    }
+
+    // bilinear between the cell centers
+    let fx = ((x as f32 + 0.5) / block as f32 - 0.5).max(0.0);
+    let fy = ((y as f32 + 0.5) / block as f32 - 0.5).max(0.0);
+    let (gx, gy) = (fx as usize, fy as usize);
+    let (tx, ty) = (fx - gx as f32, fy - gy as f32);
+    let top = [at(gx, gy), at(gx + 1, gy)];
+    let bottom = [at(gx, gy + 1), at(gx + 1, gy + 1)];
+    let ratio: [f32; 3] = [0, 1, 2].map(|c| {
+      let t = top[0][c] + (top[1][c] - top[0][c]) * tx;
+      let b = bottom[0][c] + (bottom[1][c] - bottom[0][c]) * tx;
+      (t + (b - t) * ty).max(1e-4)
+    });
+
+    let unclipped: Vec<usize> = (0..3).filter(|&c| rgb[c] < clip[c]).collect();
+    let scale = if unclipped.is_empty() {
+      (0..3).map(|c| rgb[c] / ratio[c]).fold(0.0, f32::max)
+    } else {
+      unclipped.iter().map(|&c| rgb[c]).sum::<f32>()
+        / unclipped.iter().map(|&c| ratio[c]).sum::<f32>()
+    };
+
+    // clipped channels were at least as bright as they read
+    for c in 0..3 {
+      if rgb[c] >= clip[c] {
+        pixel.0[c] = rgb[c].max(scale * ratio[c]);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const CLIP: [f32; 3] = [1.5, 1.0, 1.8];
+
+  /**
+   * A green leaning scene, twice as bright as green clips in a disc in the middle,
+   * with the sensor reading each channel up to its clip level
+   */
+  fn clipped_scene() -> ImageBuffer<Rgb<f32>, Vec<f32>> {
+    ImageBuffer::from_fn(64, 64, |x, y| {
+      let (dx, dy) = (x as f32 - 32.0, y as f32 - 32.0);
+      let brightness = if dx * dx + dy * dy < 100.0 { 2.0 } else { 0.5 };
+      let scene = [0.5, 1.0, 0.6].map(|c: f32| c * brightness);
+      Rgb([0, 1, 2].map(|c| scene[c].min(CLIP[c])))
+    })
+  }
+
+  #[test]
+  fn clip_caps_at_the_lowest_channel() {
+    let original = clipped_scene();
+    let mut image = original.clone();
+    reconstruct(&mut image, HighlightReconstruction::Clip, CLIP);
+    let lowest = CLIP[1] * CLIP_MARGIN;
+    assert!(image
+      .pixels()
+      .all(|pixel| pixel.0.iter().all(|c| *c <= lowest)));
+    assert_eq!(image.get_pixel(0, 0), original.get_pixel(0, 0));
+  }
+
+  #[test]
+  fn blend_keeps_the_mean() {
+    let original = clipped_scene();
+    let mut image = original.clone();
+    reconstruct(&mut image, HighlightReconstruction::Blend, CLIP);
+    for (before, after) in original.pixels().zip(image.pixels()) {
+      let mean = |rgb: [f32; 3]| (rgb[0] + rgb[1] + rgb[2]) / 3.0;
+      assert!(
+        (mean(before.0) - mean(after.0)).abs() < 1e-5,
+        "{before:?} {after:?}"
+      );
+    }
+    assert_eq!(image.get_pixel(0, 0), original.get_pixel(0, 0));
+    // the clipped highlight loses its color instead of turning magenta
+    let [r, g, b] = image.get_pixel(32, 32).0;
+    assert!((r - g).abs() < 0.1 && (g - b).abs() < 0.1, "{r} {g} {b}");
+  }
+
+  #[test]
+  fn propagate_fills_the_blob_from_its_border() {
+    let original = clipped_scene();
+    let mut image = original.clone();
+    reconstruct(&mut image, HighlightReconstruction::Propagate, CLIP);
+    assert_eq!(image.get_pixel(0, 0), original.get_pixel(0, 0));
+    for (x, y) in [(32, 32), (27, 30), (36, 35)] {
+      let [r, g, b] = image.get_pixel(x, y).0;
+      assert!((g - 2.0).abs() < 0.1, "{x},{y}: {r} {g} {b}");
+      assert_eq!([r, b], [1.0, 1.2]);
+    }
+  }
+}
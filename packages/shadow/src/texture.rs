@@ -0,0 +1,44 @@
+use crate::filter::gaussian_blur;
+use crate::geometry::View;
+use crate::luminance;
+use crate::tiles;
+use image::{ImageBuffer, Rgb};
+
+/**
+ * Local contrast of fine and medium detail.
+ * Splits the log luminance into a blurred base and the detail on top of it,
+ * scales the detail and puts the difference back onto the color, so hues are kept.
+ * The blur radius is relative to the frame, so previews, regions and full resolution renders match.
+ */
+pub fn texture(image: &mut ImageBuffer<Rgb<f32>, Vec<f32>>, view: &View, amount: f32) {
+  if amount == 0.0 {
+    return;
+  }
+
+  let width = image.width() as usize;
+  let height = image.height() as usize;
+  let sigma = sigma(view);
+
+  let log_luminance: Vec<f32> = image
+    .pixels()
+    .map(|pixel| luminance(pixel.0).max(1e-6).log2())
+    .collect();
+
+  let base = gaussian_blur(&log_luminance, width, height, sigma);
+
+  tiles::map_pixels_at(image, |x, y, rgb| {
+    let i = y as usize * width + x as usize;
+    let detail = log_luminance[i] - base[i];
+    // large differences are edges, not texture, dampen them to avoid halos
+    let damped = detail / (1.0 + (detail / 0.5) * (detail / 0.5));
+    let factor = (damped * amount).exp2();
+    rgb.map(|c| c * factor)
+  });
+}
+
+/**
+ * Blur radius in buffer pixels
+ */
+pub fn sigma(view: &View) -> f32 {
+  (view.frame_long_side() * 0.002 * view.scale).max(1.0)
+}
@@ -0,0 +1,112 @@
+use image::{ImageBuffer, Rgb};
+use rayon::prelude::*;
+
+/**
+ * Rows per tile, a tile of a 45 MP frame is about 3 MB and stays in cache while it is worked on
+ */
+const TILE_ROWS: usize = 32;
+
+/**
+ * Pixels per block of the matrix kernel, planar in the block so the loops vectorize
+ */
+const LANES: usize = 64;
+
+/**
+ * Runs `f` on tiles of whole rows across all cores, with the row each tile starts at
+ */
+pub fn for_each_tile(
+  image: &mut ImageBuffer<Rgb<f32>, Vec<f32>>,
+  f: impl Fn(&mut [f32], u32) + Sync,
+) {
+  let row = (image.width() as usize * 3).max(3);
+  let data: &mut [f32] = image;
+  data
+    .par_chunks_mut(row * TILE_ROWS)
+    .enumerate()
+    .for_each(|(i, tile)| f(tile, (i * TILE_ROWS) as u32));
+}
+
+/**
+ * Maps every pixel in parallel
+ */
+pub fn map_pixels(
+  image: &mut ImageBuffer<Rgb<f32>, Vec<f32>>,
+  f: impl Fn([f32; 3]) -> [f32; 3] + Sync,
+) {
+  for_each_tile(image, |tile, _| {
+    for pixel in tile.chunks_exact_mut(3) {
+      pixel.copy_from_slice(&f([pixel[0], pixel[1], pixel[2]]));
+    }
+  });
+}
+
+/**
+ * Maps every pixel in parallel, with its position
+ */
+pub fn map_pixels_at(
+  image: &mut ImageBuffer<Rgb<f32>, Vec<f32>>,
+  f: impl Fn(u32, u32, [f32; 3]) -> [f32; 3] + Sync,
+) {
+  let width = image.width().max(1);
+  for_each_tile(image, |tile, top| {
+    for (i, pixel) in tile.chunks_exact_mut(3).enumerate() {
+      let (x, y) = (i as u32 % width, top + i as u32 / width);
+      pixel.copy_from_slice(&f(x, y, [pixel[0], pixel[1], pixel[2]]));
+    }
+  });
+}
+
+/**
+ * A single channel plane with a value computed from every pixel in parallel
+ */
+pub fn plane(
+  image: &ImageBuffer<Rgb<f32>, Vec<f32>>,
+  f: impl Fn([f32; 3]) -> f32 + Sync,
+) -> Vec<f32> {
+  image
+    .as_raw()
+    .par_chunks_exact(3)
+    .map(|pixel| f([pixel[0], pixel[1], pixel[2]]))
+    .collect()
+}
+
+/**
+ * Runs `f` on tiles of whole rows of a single channel plane across all cores,
+ * with the row each tile starts at
+ */
+pub fn for_each_plane_tile(plane: &mut [f32], width: usize, f: impl Fn(&mut [f32], usize) + Sync) {
+  plane
+    .par_chunks_mut(width.max(1) * TILE_ROWS)
+    .enumerate()
+    .for_each(|(i, tile)| f(tile, i * TILE_ROWS));
+}
+
+/**
+ * Multiplies every pixel with a matrix, the conversions between linear color spaces
+ */
+pub fn apply_matrix(image: &mut ImageBuffer<Rgb<f32>, Vec<f32>>, matrix: &[[f32; 3]; 3]) {
+  for_each_tile(image, |tile, _| {
+    for block in tile.chunks_mut(LANES * 3) {
+      let count = block.len() / 3;
+      let mut planes = [[0.0; LANES]; 3];
+      for i in 0..count {
+        for c in 0..3 {
+          planes[c][i] = block[i * 3 + c];
+        }
+      }
+
+      let mut out = [[0.0; LANES]; 3];
+      for (row, plane) in matrix.iter().zip(out.iter_mut()) {
+        for i in 0..LANES {
+          plane[i] = row[0] * planes[0][i] + row[1] * planes[1][i] + row[2] * planes[2][i];
+        }
+      }
+
+      for i in 0..count {
+        for c in 0..3 {
+          block[i * 3 + c] = out[c][i];
+        }
+      }
+    }
+  });
+}
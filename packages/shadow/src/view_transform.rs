@@ -0,0 +1,165 @@
+use crate::tiles;
+use crate::{conversion, mat_inverse, mat_mul, mat_product, WORKING_COLORSPACE};
+use image::{ImageBuffer, Rgb};
+use serde::{Deserialize, Serialize};
+
+/**
+ * Maps the scene-referred working space to the display at the end of the pipeline
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ViewTransform {
+  /**
+   * Colors are shown as they are, everything above white clips
+   */
+  #[default]
+  None,
+  /**
+   * Filmic curve that rolls highlights off towards white, Hable's
+   */
+  Filmic,
+  /**
+   * ACES reference rendering and sRGB output transform, Hill's fit of them
+   */
+  Aces,
+}
+
+/**
+ * Saturation adjustments of the ACES RRT and ODT, on AP1 primaries
+ */
+const RRT_SAT: [[f32; 3]; 3] = [
+  [0.970889, 0.026963, 0.002148],
+  [0.010889, 0.986963, 0.002148],
+  [0.010889, 0.026963, 0.962148],
+];
+const ODT_SAT: [[f32; 3]; 3] = [
+  [0.949056, 0.047190, 0.003754],
+  [0.019056, 0.977190, 0.003754],
+  [0.019056, 0.047190, 0.933754],
+];
+
+/**
+ * Scene exposure the filmic curve is applied at and the scene value that becomes white
+ */
+const FILMIC_EXPOSURE: f32 = 2.0;
+const FILMIC_WHITE: f32 = 11.2;
+
+pub fn apply(image: &mut ImageBuffer<Rgb<f32>, Vec<f32>>, transform: ViewTransform) {
+  if transform == ViewTransform::None {
+    return;
+  }
+
+  // both curves work per channel on the AP1 primaries, AP0 lies too far outside of the visible
+  let to_ap1 = conversion(WORKING_COLORSPACE, kolor::spaces::ACES_CG);
+  let Some(from_ap1) = mat_inverse(&to_ap1) else {
+    return;
+  };
+
+  match transform {
+    ViewTransform::None => {}
+    ViewTransform::Filmic => {
+      let white = hable(FILMIC_WHITE);
+      tiles::map_pixels(image, |rgb| {
+        let ap1 = mat_mul(&to_ap1, &rgb).map(|c| hable(c.max(0.0) * FILMIC_EXPOSURE) / white);
+        mat_mul(&from_ap1, &ap1)
+      });
+    }
+    ViewTransform::Aces => {
+      let input = mat_product(&RRT_SAT, &to_ap1);
+      let output = mat_product(&from_ap1, &ODT_SAT);
+      tiles::map_pixels(image, |rgb| {
+        let ap1 = mat_mul(&input, &rgb).map(|c| rrt_and_odt(c.max(0.0)).clamp(0.0, 1.0));
+        mat_mul(&output, &ap1)
+      });
+    }
+  }
+}
+
+/**
+ * Uncharted 2 filmic curve
+ */
+fn hable(x: f32) -> f32 {
+  const A: f32 = 0.15;
+  const B: f32 = 0.50;
+  const C: f32 = 0.10;
+  const D: f32 = 0.20;
+  const E: f32 = 0.02;
+  const F: f32 = 0.30;
+  ((x * (A * x + C * B) + D * E) / (x * (A * x + B) + D * F)) - E / F
+}
+
+/**
+ * Rational fit of the RRT followed by the sRGB ODT, from scene to display linear
+ */
+fn rrt_and_odt(v: f32) -> f32 {
+  let a = v * (v + 0.0245786) - 0.000090537;
+  let b = v * (0.983729 * v + 0.432951) + 0.238081;
+  a / b
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /**
+   * Renders a neutral grey, equal on the AP1 primaries the curves work on, returned on them
+   */
+  fn render_grey(value: f32, transform: ViewTransform) -> [f32; 3] {
+    let from_ap1 = conversion(kolor::spaces::ACES_CG, WORKING_COLORSPACE);
+    let to_ap1 = conversion(WORKING_COLORSPACE, kolor::spaces::ACES_CG);
+    let mut image = ImageBuffer::from_pixel(1, 1, Rgb(mat_mul(&from_ap1, &[value; 3])));
+    apply(&mut image, transform);
+    mat_mul(&to_ap1, &image.get_pixel(0, 0).0)
+  }
+
+  #[test]
+  fn none_leaves_pixels_unchanged() {
+    let original = ImageBuffer::from_fn(8, 8, |x, y| Rgb([x as f32 * 0.5, y as f32, -0.1]));
+    let mut image = original.clone();
+    apply(&mut image, ViewTransform::None);
+    assert_eq!(image, original);
+  }
+
+  #[test]
+  fn filmic_is_monotonic_up_to_white() {
+    let white = hable(FILMIC_WHITE);
+    assert!((hable(FILMIC_WHITE) / white - 1.0).abs() < 1e-6);
+    assert!(hable(0.0).abs() < 1e-6);
+
+    let curve: Vec<f32> = (0..=200).map(|i| hable(i as f32 * 0.1) / white).collect();
+    assert!(curve.windows(2).all(|pair| pair[1] > pair[0]), "{curve:?}");
+
+    // the scene value that becomes white lands on white through the whole transform
+    let rendered = render_grey(FILMIC_WHITE / FILMIC_EXPOSURE, ViewTransform::Filmic);
+    assert!(
+      rendered.iter().all(|c| (c - 1.0).abs() < 1e-3),
+      "{rendered:?}"
+    );
+  }
+
+  #[test]
+  fn aces_rolls_off_towards_white() {
+    assert!(render_grey(0.0, ViewTransform::Aces)
+      .iter()
+      .all(|c| c.abs() < 1e-6));
+
+    let bright = rrt_and_odt(10.0);
+    assert!(bright > 0.95 && bright < 1.0, "{bright}");
+    let clipped = render_grey(1e4, ViewTransform::Aces);
+    assert!(
+      clipped.iter().all(|c| (c - 1.0).abs() < 1e-3),
+      "{clipped:?}"
+    );
+  }
+
+  #[test]
+  fn aces_keeps_greys_neutral() {
+    for value in [0.01, 0.18, 0.5, 2.0] {
+      let [r, g, b] = render_grey(value, ViewTransform::Aces);
+      assert!(
+        (r - g).abs() < 1e-4 && (g - b).abs() < 1e-4,
+        "{value}: {r} {g} {b}"
+      );
+    }
+  }
+}
@@ -0,0 +1,363 @@
+use crate::geometry::{sample_lanczos, Geometry};
+use crate::lens::LensMap;
+use crate::perspective::{self, PerspectiveMap};
+use crate::tiles;
+use image::imageops;
+use image::{ImageBuffer, Rgb};
+
+/**
+ * Lens correction and geometry as one mapping from the edited frame back into the source.
+ * Any part of the frame is rendered straight from the source with a single resample,
+ * so a region only reads the source pixels that end up in it.
+ */
+#[derive(Debug, Clone)]
+pub struct Warp {
+  width: u32,
+  height: u32,
+  lens: Option<LensMap>,
+  flip_horizontal: bool,
+  flip_vertical: bool,
+  quarter_turns: u32,
+  perspective: Option<PerspectiveMap>,
+  straighten: Option<Straighten>,
+  crop: (u32, u32, u32, u32),
+}
+
+/**
+ * Rotation around the center, scaled to cover the whole frame
+ */
+#[derive(Debug, Clone, Copy)]
+struct Straighten {
+  sin: f32,
+  cos: f32,
+  scale: f32,
+}
+
+impl Straighten {
+  fn new(angle: f32, width: f32, height: f32) -> Straighten {
+    let (sin, cos) = angle.to_radians().sin_cos();
+    let (sin_abs, cos_abs) = (sin.abs(), cos.abs());
+    let scale = ((width * cos_abs + height * sin_abs) / width)
+      .max((width * sin_abs + height * cos_abs) / height);
+    Straighten { sin, cos, scale }
+  }
+
+  fn map(&self, x: f32, y: f32, width: f32, height: f32) -> (f32, f32) {
+    let dx = (x - width / 2.0) / self.scale;
+    let dy = (y - height / 2.0) / self.scale;
+    (
+      dx * self.cos + dy * self.sin + width / 2.0,
+      -dx * self.sin + dy * self.cos + height / 2.0,
+    )
+  }
+}
+
+impl Warp {
+  /**
+   * Warp of a source of the given size, applied as lens correction, flip, rotate, perspective,
+   * straighten and then crop. The thumbnail is a downscaled source, the automatic perspective
+   * looks for its lines in it.
+   */
+  pub fn new(
+    width: u32,
+    height: u32,
+    lens: Option<LensMap>,
+    geometry: &Geometry,
+    thumbnail: &ImageBuffer<Rgb<f32>, Vec<f32>>,
+  ) -> Warp {
+    let mut warp = Warp {
+      width,
+      height,
+      lens,
+      flip_horizontal: geometry.flip_horizontal,
+      flip_vertical: geometry.flip_vertical,
+      quarter_turns: geometry.quarter_turns(),
+      perspective: None,
+      straighten: None,
+      crop: (0, 0, 0, 0),
+    };
+    let (turned_width, turned_height) = warp.turned_size();
+    warp.crop = (0, 0, turned_width, turned_height);
+
+    if !geometry.perspective.is_identity() {
+      // the lines are found in the image as it comes into the perspective correction
+      let detected = match geometry.perspective.mode {
+        perspective::PerspectiveMode::Auto => {
+          let (thumb_width, thumb_height) = thumbnail.dimensions();
+          let mut before = Warp {
+            width: thumb_width,
+            height: thumb_height,
+            ..warp.clone()
+          };
+          let (turned_width, turned_height) = before.turned_size();
+          before.crop = (0, 0, turned_width, turned_height);
+          perspective::detect_verticals(&before.render_frame(thumbnail))
+        }
+        perspective::PerspectiveMode::Manual => (0.0, 0.0),
+      };
+      warp.perspective = Some(PerspectiveMap::new(
+        &geometry.perspective,
+        detected,
+        turned_width as f32,
+        turned_height as f32,
+      ));
+    }
+    if geometry.angle != 0.0 {
+      warp.straighten = Some(Straighten::new(
+        geometry.angle,
+        turned_width as f32,
+        turned_height as f32,
+      ));
+    }
+    warp.crop = geometry.crop_rect(turned_width, turned_height);
+
+    warp
+  }
+
+  /**
+   * Width and height of the edited frame
+   */
+  pub fn size(&self) -> (u32, u32) {
+    (self.crop.2, self.crop.3)
+  }
+
+  /**
+   * Size of the source after the quarter turns, what perspective, straighten and crop work on
+   */
+  fn turned_size(&self) -> (u32, u32) {
+    match self.quarter_turns % 2 {
+      0 => (self.width, self.height),
+      _ => (self.height, self.width),
+    }
+  }
+
+  fn is_identity(&self) -> bool {
+    self.lens.is_none()
+      && !self.flip_horizontal
+      && !self.flip_vertical
+      && self.quarter_turns == 0
+      && self.perspective.is_none()
+      && self.straighten.is_none()
+  }
+
+  /**
+   * Color of the source at a position of the frame, in pixels from its top left corner
+   */
+  fn sample(&self, source: &ImageBuffer<Rgb<f32>, Vec<f32>>, x: f32, y: f32) -> [f32; 3] {
+    let (turned_width, turned_height) = self.turned_size();
+    let (turned_width, turned_height) = (turned_width as f32, turned_height as f32);
+    let (mut x, mut y) = (x + self.crop.0 as f32, y + self.crop.1 as f32);
+
+    if let Some(straighten) = &self.straighten {
+      (x, y) = straighten.map(x, y, turned_width, turned_height);
+    }
+    if let Some(perspective) = &self.perspective {
+      match perspective.map(x, y, turned_width, turned_height) {
+        Some(position) => (x, y) = position,
+        None => return [0.0; 3],
+      }
+    }
+
+    // turned back counterclockwise
+    let (width, height) = (self.width as f32, self.height as f32);
+    (x, y) = match self.quarter_turns {
+      1 => (y, height - x),
+      2 => (width - x, height - y),
+      3 => (width - y, x),
+      _ => (x, y),
+    };
+    if self.flip_vertical {
+      y = height - y;
+    }
+    if self.flip_horizontal {
+      x = width - x;
+    }
+
+    match &self.lens {
+      Some(lens) => lens.sample(source, x, y),
+      None => sample_lanczos(source, x - 0.5, y - 0.5),
+    }
+  }
+
+  /**
+   * Renders a rectangle of the frame, in pixels of the frame
+   */
+  pub fn render(
+    &self,
+    source: &ImageBuffer<Rgb<f32>, Vec<f32>>,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+  ) -> ImageBuffer<Rgb<f32>, Vec<f32>> {
+    if self.is_identity() {
+      return imageops::crop_imm(source, self.crop.0 + x, self.crop.1 + y, width, height)
+        .to_image();
+    }
+
+    let mut image = ImageBuffer::new(width, height);
+    tiles::map_pixels_at(&mut image, |px, py, _| {
+      self.sample(source, (x + px) as f32 + 0.5, (y + py) as f32 + 0.5)
+    });
+    image
+  }
+
+  /**
+   * Renders the whole frame
+   */
+  pub fn render_frame(
+    &self,
+    source: &ImageBuffer<Rgb<f32>, Vec<f32>>,
+  ) -> ImageBuffer<Rgb<f32>, Vec<f32>> {
+    let (width, height) = self.size();
+    self.render(source, 0, 0, width, height)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::geometry::Crop;
+  use crate::lens::LensCorrection;
+
+  fn gradient(width: u32, height: u32) -> ImageBuffer<Rgb<f32>, Vec<f32>> {
+    ImageBuffer::from_fn(width, height, |x, y| {
+      Rgb([x as f32 / width as f32, y as f32 / height as f32, 0.5])
+    })
+  }
+
+  fn warp(
+    geometry: &Geometry,
+    lens: Option<LensMap>,
+    image: &ImageBuffer<Rgb<f32>, Vec<f32>>,
+  ) -> Warp {
+    Warp::new(image.width(), image.height(), lens, geometry, image)
+  }
+
+  #[test]
+  fn crops_without_resampling() {
+    let image = gradient(40, 30);
+    let geometry = Geometry {
+      crop: Some(Crop {
+        x: 0.25,
+        y: 0.5,
+        width: 0.5,
+        height: 0.5,
+      }),
+      ..Geometry::default()
+    };
+    let warp = warp(&geometry, None, &image);
+    assert_eq!(warp.size(), (20, 15));
+    let frame = warp.render_frame(&image);
+    assert_eq!(frame.get_pixel(0, 0), image.get_pixel(10, 15));
+  }
+
+  #[test]
+  fn flips() {
+    let image = gradient(40, 30);
+    let geometry = Geometry {
+      flip_horizontal: true,
+      ..Geometry::default()
+    };
+    let frame = warp(&geometry, None, &image).render_frame(&image);
+    for c in 0..3 {
+      assert!((frame.get_pixel(0, 7).0[c] - image.get_pixel(39, 7).0[c]).abs() < 1e-4);
+    }
+  }
+
+  #[test]
+  fn turns_clockwise() {
+    let image = gradient(40, 30);
+    for (rotate, size, corner) in [
+      (90, (30, 40), (0, 29)),
+      (180, (40, 30), (39, 29)),
+      (270, (30, 40), (39, 0)),
+    ] {
+      let geometry = Geometry {
+        rotate,
+        ..Geometry::default()
+      };
+      let warp = warp(&geometry, None, &image);
+      assert_eq!(warp.size(), size);
+      // the top left corner of the turned frame
+      let frame = warp.render_frame(&image);
+      let expected = image.get_pixel(corner.0, corner.1).0;
+      for (value, expected) in frame.get_pixel(0, 0).0.iter().zip(expected) {
+        assert!((value - expected).abs() < 1e-4, "{rotate}");
+      }
+    }
+  }
+
+  #[test]
+  fn turns_before_the_crop() {
+    let image = gradient(40, 30);
+    let geometry = Geometry {
+      rotate: 90,
+      crop: Some(Crop {
+        x: 0.0,
+        y: 0.0,
+        width: 1.0,
+        height: 0.5,
+      }),
+      ..Geometry::default()
+    };
+    assert_eq!(warp(&geometry, None, &image).size(), (30, 20));
+  }
+
+  #[test]
+  fn regions_match_the_frame() {
+    let image = gradient(64, 48);
+    let geometry = Geometry {
+      angle: 5.0,
+      crop: Some(Crop {
+        x: 0.1,
+        y: 0.1,
+        width: 0.8,
+        height: 0.8,
+      }),
+      ..Geometry::default()
+    };
+    let lens = LensCorrection {
+      distortion: 0.5,
+      vignetting: 0.3,
+      ..LensCorrection::default()
+    };
+    let warp = warp(&geometry, Some(LensMap::new(None, &lens)), &image);
+    let frame = warp.render_frame(&image);
+    let region = warp.render(&image, 10, 12, 20, 16);
+    for (x, y, pixel) in region.enumerate_pixels() {
+      assert_eq!(pixel, frame.get_pixel(x + 10, y + 12));
+    }
+  }
+
+  #[test]
+  fn perspective_leaves_outside_black() {
+    let image = gradient(64, 48);
+    let mut geometry = Geometry::default();
+    geometry.perspective.vertical = 0.5;
+    geometry.perspective.constrain = false;
+    let frame = warp(&geometry, None, &image).render_frame(&image);
+    // tilting up pulls the top apart, so the bottom corners fall outside of the source
+    assert_eq!(frame.get_pixel(0, 47).0, [0.0; 3]);
+    assert_ne!(frame.get_pixel(32, 24).0, [0.0; 3]);
+  }
+
+  #[test]
+  fn constrained_perspective_has_no_empty_corners() {
+    let image = gradient(64, 48);
+    for (vertical, horizontal, rotate) in [(0.5, 0.0, 0.0), (-0.3, 0.4, 5.0), (0.0, -0.6, -10.0)] {
+      let mut geometry = Geometry::default();
+      geometry.perspective.vertical = vertical;
+      geometry.perspective.horizontal = horizontal;
+      geometry.perspective.rotate = rotate;
+      let frame = warp(&geometry, None, &image).render_frame(&image);
+      for (x, y) in [(0, 0), (63, 0), (0, 47), (63, 47)] {
+        assert_ne!(
+          frame.get_pixel(x, y).0,
+          [0.0; 3],
+          "{vertical} {horizontal} {rotate}"
+        );
+      }
+    }
+  }
+}
@@ -0,0 +1,324 @@
+use crate::geometry::Crop;
+use crate::tiles;
+use crate::{conversion, mat_inverse, mat_mul, mat_product};
+use image::{ImageBuffer, Rgb};
+use serde::{Deserialize, Serialize};
+
+/**
+ * Tint units per Duv, the distance from the planckian locus in CIE 1960 uv
+ */
+const TINT_SCALE: f32 = 3000.0;
+const MIN_TEMPERATURE: f32 = 1000.0;
+const MAX_TEMPERATURE: f32 = 15000.0;
+
+pub const D65: [f32; 2] = [0.31271, 0.32902];
+
+pub const SRGB_TO_XYZ: [[f32; 3]; 3] = [
+  [0.4124564, 0.3575761, 0.1804375],
+  [0.2126729, 0.7151522, 0.0721750],
+  [0.0193339, 0.119192, 0.9503041],
+];
+pub const XYZ_TO_SRGB: [[f32; 3]; 3] = [
+  [3.2404542, -1.5371385, -0.4985314],
+  [-0.969266, 1.8760108, 0.041556],
+  [0.0556434, -0.2040259, 1.0572252],
+];
+const BRADFORD: [[f32; 3]; 3] = [
+  [0.8951, 0.2664, -0.1614],
+  [-0.7502, 1.7135, 0.0367],
+  [0.0389, -0.0685, 1.0296],
+];
+
+/**
+ * Absolute white balance, the color temperature of the light in Kelvin (1000 to 15000)
+ * and its tint, positive towards magenta and negative towards green, about -150..150
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct WhiteBalance {
+  pub temperature: f32,
+  pub tint: f32,
+}
+
+/**
+ * Daylight, what rawler renders white as when the camera recorded nothing else
+ */
+impl Default for WhiteBalance {
+  fn default() -> Self {
+    WhiteBalance::from_xy(D65)
+  }
+}
+
+impl WhiteBalance {
+  /**
+   * The light the camera balanced for, from its white balance multipliers
+   * and the matrix from XYZ to camera RGB
+   */
+  pub fn from_camera(multipliers: [f32; 3], xyz_to_camera: &[[f32; 3]; 3]) -> Option<WhiteBalance> {
+    if multipliers.iter().any(|m| !m.is_finite() || *m <= 0.0) {
+      return None;
+    }
+
+    // a neutral surface under that light gives the inverse of the multipliers in camera RGB
+    let neutral = multipliers.map(|m| multipliers[1] / m);
+    let xyz = mat_mul(&mat_inverse(xyz_to_camera)?, &neutral);
+    let sum = xyz[0] + xyz[1] + xyz[2];
+    if sum <= 0.0 {
+      return None;
+    }
+
+    Some(WhiteBalance::from_xy([xyz[0] / sum, xyz[1] / sum]))
+  }
+
+  pub fn from_xy(xy: [f32; 2]) -> WhiteBalance {
+    let (u, v) = uv_from_xy(xy);
+
+    // closest point of the locus, searched in mired where the locus is evenly spaced
+    let distance = |mired: f32| {
+      let (lu, lv) = planckian_uv(1e6 / mired);
+      (u - lu).hypot(v - lv)
+    };
+    let (mut low, mut high) = (1e6 / MAX_TEMPERATURE, 1e6 / MIN_TEMPERATURE);
+    let ratio = (5.0_f32.sqrt() - 1.0) / 2.0;
+    for _ in 0..64 {
+      let a = high - (high - low) * ratio;
+      let b = low + (high - low) * ratio;
+      if distance(a) < distance(b) {
+        high = b;
+      } else {
+        low = a;
+      }
+    }
+
+    let temperature = 2e6 / (low + high);
+    let (lu, lv) = planckian_uv(temperature);
+    let (nu, nv) = locus_normal(temperature);
+    let duv = (u - lu) * nu + (v - lv) * nv;
+
+    WhiteBalance {
+      temperature,
+      tint: -duv * TINT_SCALE,
+    }
+  }
+
+  /**
+   * The absolute white balance that gives an image balanced for `as_shot` the same cast as the
+   * relative temperature and tint of edits made before Kelvin and tint existed. Those scaled
+   * the CIE 1960 w and u of every color by 1 + temperature and 1 + tint.
+   */
+  pub fn from_relative(temperature: f32, tint: f32, as_shot: &WhiteBalance) -> WhiteBalance {
+    let [x, y, z] = xyz_from_xy(D65);
+    let (u, v, w) = (x * 2.0 / 3.0, y, (-x + 3.0 * y + z) / 2.0);
+    let (u, w) = (u * (1.0 + tint), w * (1.0 + temperature));
+    let shifted = [u * 1.5, v, u * 1.5 - 3.0 * v + 2.0 * w];
+
+    // `apply` turns a neutral into as shot / target in cone space, relative to the D65 white
+    let cone = |xyz: [f32; 3]| mat_mul(&BRADFORD, &xyz);
+    let (as_shot, white, shifted) = (
+      cone(xyz_from_xy(as_shot.xy())),
+      cone([x, y, z]),
+      cone(shifted),
+    );
+    let target = [0, 1, 2].map(|c| as_shot[c] * white[c] / shifted[c]);
+    let xyz = mat_mul(&mat_inverse(&BRADFORD).unwrap(), &target);
+    let total = xyz[0] + xyz[1] + xyz[2];
+    if total.is_nan() || total <= 0.0 {
+      return WhiteBalance::default();
+    }
+
+    WhiteBalance::from_xy([xyz[0] / total, xyz[1] / total])
+  }
+
+  pub fn xy(&self) -> [f32; 2] {
+    let temperature = self.temperature.clamp(MIN_TEMPERATURE, MAX_TEMPERATURE);
+    let (lu, lv) = planckian_uv(temperature);
+    let (nu, nv) = locus_normal(temperature);
+    let duv = -self.tint / TINT_SCALE;
+    xy_from_uv(lu + nu * duv, lv + nv * duv)
+  }
+}
+
+/**
+ * CIE 1960 uv of a black body, Krystek's approximation
+ */
+fn planckian_uv(kelvin: f32) -> (f32, f32) {
+  let t = kelvin as f64;
+  let u = (0.860117757 + 1.54118254e-4 * t + 1.28641212e-7 * t * t)
+    / (1.0 + 8.42420235e-4 * t + 7.08145163e-7 * t * t);
+  let v = (0.317398726 + 4.22806245e-5 * t + 4.20481691e-8 * t * t)
+    / (1.0 - 2.89741816e-5 * t + 1.61456053e-7 * t * t);
+  (u as f32, v as f32)
+}
+
+/**
+ * Unit normal of the locus pointing towards green
+ */
+fn locus_normal(kelvin: f32) -> (f32, f32) {
+  let (u0, v0) = planckian_uv(kelvin - 1.0);
+  let (u1, v1) = planckian_uv(kelvin + 1.0);
+  let (du, dv) = (u1 - u0, v1 - v0);
+  let length = du.hypot(dv).max(1e-12);
+  let (nu, nv) = (-dv / length, du / length);
+  if nv < 0.0 {
+    (-nu, -nv)
+  } else {
+    (nu, nv)
+  }
+}
+
+fn uv_from_xy(xy: [f32; 2]) -> (f32, f32) {
+  let [x, y] = xy;
+  let d = -2.0 * x + 12.0 * y + 3.0;
+  (4.0 * x / d, 6.0 * y / d)
+}
+
+fn xy_from_uv(u: f32, v: f32) -> [f32; 2] {
+  let d = 2.0 * u - 8.0 * v + 4.0;
+  [3.0 * u / d, 2.0 * v / d]
+}
+
+pub fn xyz_from_xy(xy: [f32; 2]) -> [f32; 3] {
+  let [x, y] = xy;
+  [x / y, 1.0, (1.0 - x - y) / y]
+}
+
+/**
+ * Bradford chromatic adaptation from one white to another, in XYZ
+ */
+pub fn adaptation(from: [f32; 2], to: [f32; 2]) -> [[f32; 3]; 3] {
+  let source = mat_mul(&BRADFORD, &xyz_from_xy(from));
+  let target = mat_mul(&BRADFORD, &xyz_from_xy(to));
+  let scale = [
+    [target[0] / source[0], 0.0, 0.0],
+    [0.0, target[1] / source[1], 0.0],
+    [0.0, 0.0, target[2] / source[2]],
+  ];
+  let inverse = mat_inverse(&BRADFORD).unwrap();
+  mat_product(&inverse, &mat_product(&scale, &BRADFORD))
+}
+
+/**
+ * Rebalances an image developed for the `as_shot` light to `target`.
+ * The image has the as shot light adapted to the D65 white of sRGB, that is undone
+ * and the target light is adapted to D65 instead.
+ */
+pub fn apply(
+  image: &mut ImageBuffer<Rgb<f32>, Vec<f32>>,
+  target: &WhiteBalance,
+  as_shot: &WhiteBalance,
+) {
+  let xyz = mat_product(
+    &adaptation(target.xy(), D65),
+    &adaptation(D65, as_shot.xy()),
+  );
+  let srgb = mat_product(&XYZ_TO_SRGB, &mat_product(&xyz, &SRGB_TO_XYZ));
+
+  let to_srgb = conversion(crate::WORKING_COLORSPACE, kolor::spaces::LINEAR_SRGB);
+  let from_srgb = conversion(kolor::spaces::LINEAR_SRGB, crate::WORKING_COLORSPACE);
+  tiles::apply_matrix(
+    image,
+    &mat_product(&from_srgb, &mat_product(&srgb, &to_srgb)),
+  );
+}
+
+/**
+ * The white balance that makes the average of a region neutral, the region relative to the image.
+ * The image is expected as it comes from `get_source`, balanced for the as shot light.
+ */
+pub fn neutral(
+  image: &ImageBuffer<Rgb<f32>, Vec<f32>>,
+  region: &Crop,
+  as_shot: &WhiteBalance,
+) -> WhiteBalance {
+  let (width, height) = image.dimensions();
+  let x0 = ((region.x.clamp(0.0, 1.0) * width as f32) as u32).min(width - 1);
+  let y0 = ((region.y.clamp(0.0, 1.0) * height as f32) as u32).min(height - 1);
+  let x1 = (((region.x + region.width).clamp(0.0, 1.0) * width as f32).ceil() as u32).max(x0 + 1);
+  let y1 = (((region.y + region.height).clamp(0.0, 1.0) * height as f32).ceil() as u32).max(y0 + 1);
+
+  let mut sum = [0.0; 3];
+  for y in y0..y1.min(height) {
+    for x in x0..x1.min(width) {
+      let pixel = image.get_pixel(x, y).0;
+      for c in 0..3 {
+        sum[c] += pixel[c];
+      }
+    }
+  }
+
+  let to_srgb = conversion(crate::WORKING_COLORSPACE, kolor::spaces::LINEAR_SRGB);
+  let rgb = mat_mul(&to_srgb, &sum);
+
+  // the sampled color as it was under the as shot light is the light that makes it neutral
+  let xyz = mat_mul(&adaptation(D65, as_shot.xy()), &mat_mul(&SRGB_TO_XYZ, &rgb));
+  let total = xyz[0] + xyz[1] + xyz[2];
+  if total.is_nan() || total <= 0.0 {
+    return *as_shot;
+  }
+
+  WhiteBalance::from_xy([xyz[0] / total, xyz[1] / total])
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn close(a: &WhiteBalance, b: &WhiteBalance) -> bool {
+    (a.temperature - b.temperature).abs() < 5.0 && (a.tint - b.tint).abs() < 0.5
+  }
+
+  #[test]
+  fn round_trips_through_xy() {
+    for (temperature, tint) in [
+      (2850.0, 0.0),
+      (5000.0, 20.0),
+      (6500.0, -30.0),
+      (9000.0, 5.0),
+    ] {
+      let white_balance = WhiteBalance { temperature, tint };
+      let back = WhiteBalance::from_xy(white_balance.xy());
+      assert!(
+        close(&white_balance, &back),
+        "{:?} {:?}",
+        white_balance,
+        back
+      );
+    }
+  }
+
+  #[test]
+  fn relative_shifts_start_from_as_shot() {
+    let as_shot = WhiteBalance {
+      temperature: 4500.0,
+      tint: 10.0,
+    };
+    assert!(close(
+      &WhiteBalance::from_relative(0.0, 0.0, &as_shot),
+      &as_shot
+    ));
+
+    // more w makes the image bluer, as if balanced for a warmer light
+    let shifted = WhiteBalance::from_relative(0.15, 0.0, &as_shot);
+    assert!(shifted.temperature < as_shot.temperature);
+  }
+
+  #[test]
+  fn samples_the_neutral_light() {
+    let as_shot = WhiteBalance::default();
+    let region = Crop {
+      x: 0.0,
+      y: 0.0,
+      width: 1.0,
+      height: 1.0,
+    };
+    // a grey as the source has it, developed to sRGB
+    let from_srgb = conversion(kolor::spaces::LINEAR_SRGB, crate::WORKING_COLORSPACE);
+    let grey = ImageBuffer::from_pixel(4, 4, Rgb(mat_mul(&from_srgb, &[0.18; 3])));
+    let sampled = neutral(&grey, &region, &as_shot);
+    assert!(close(&sampled, &as_shot), "{:?} {:?}", sampled, as_shot);
+
+    let black = ImageBuffer::from_pixel(4, 4, Rgb([0.0; 3]));
+    assert_eq!(neutral(&black, &region, &as_shot), as_shot);
+    let broken = ImageBuffer::from_pixel(4, 4, Rgb([f32::NAN; 3]));
+    assert_eq!(neutral(&broken, &region, &as_shot), as_shot);
+  }
+}